@@ -0,0 +1,346 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+};
+
+use humansize::{SizeFormatter, DECIMAL};
+use serde::Serialize;
+
+use crate::{
+    pe::StubInfo,
+    setup::is::{IsFileAttributes, IsHeader},
+    util::read_up_to,
+    SetupOpt,
+};
+
+pub const MAP_FILE: &str = "map.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegionKind {
+    /// PE image up to the end of its last section
+    Stub,
+    /// `PADDINGXXPADDING` in front of the container
+    Padding,
+    ContainerHeader,
+    /// Attributes in front of the data of an entry
+    EntryHeader,
+    Entry,
+    /// Authenticode certificate table
+    Certificate,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub range: Range<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// File the region was dumped to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dump: Option<String>,
+}
+
+impl Region {
+    fn new(kind: RegionKind, range: Range<u64>, name: Option<String>) -> Self {
+        Self {
+            kind,
+            range,
+            name,
+            dump: None,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+}
+
+/// Every byte range of a setup file, ranges not explained by the format are unknown
+#[derive(Debug, Clone, Serialize)]
+pub struct SetupMap {
+    pub setup: String,
+    pub format: &'static str,
+    pub size: u64,
+    /// Sorted ranges, which cover the whole file without overlaps
+    pub regions: Vec<Region>,
+}
+
+impl SetupMap {
+    pub fn unknown(&self) -> impl Iterator<Item = &Region> {
+        self.regions
+            .iter()
+            .filter(|r| r.kind == RegionKind::Unknown)
+    }
+
+    pub fn unknown_size(&self) -> u64 {
+        self.unknown().map(Region::len).sum()
+    }
+
+    pub fn log(&self) {
+        log::info!("Analyzing {} setup: {}", self.format, self.setup);
+        for region in self.regions.iter() {
+            log::info!(
+                "{:#010x}..{:#010x} {:?}{} - {}",
+                region.range.start,
+                region.range.end,
+                region.kind,
+                region
+                    .name
+                    .as_ref()
+                    .map(|n| format!(" {n}"))
+                    .unwrap_or_default(),
+                SizeFormatter::new(region.len(), DECIMAL)
+            );
+        }
+        let unknown = self.unknown_size();
+        let perc = (unknown as f64 / self.size as f64) * 100.0;
+        log::info!(
+            "Unknown: {}/{} ({perc:.2}%)",
+            SizeFormatter::new(unknown, DECIMAL),
+            SizeFormatter::new(self.size, DECIMAL)
+        );
+        for region in self.unknown() {
+            if let Some(dump) = &region.dump {
+                log::warn!(
+                    "\tUnknown {:#x}..{:#x}: {dump}",
+                    region.range.start,
+                    region.range.end
+                );
+            }
+        }
+    }
+
+    /// Writes each unknown region into its own file and the map as JSON into `out_dir`
+    pub fn dump(&mut self, out_dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(out_dir)?;
+        let mut rdr = BufReader::new(File::open(&self.setup)?);
+        for region in self.regions.iter_mut() {
+            if region.kind != RegionKind::Unknown {
+                continue;
+            }
+            let name = format!("unknown_{:08x}.bin", region.range.start);
+            rdr.seek(SeekFrom::Start(region.range.start))?;
+            let mut out = BufWriter::new(File::create(out_dir.join(&name))?);
+            io::copy(&mut rdr.by_ref().take(region.len()), &mut out)?;
+            region.dump = Some(name);
+        }
+        let file = BufWriter::new(File::create(out_dir.join(MAP_FILE))?);
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+/// Length of the `PADDINGXXPADDING` pattern at the reader's position, at most `limit`
+fn padding_len<R: Read>(rdr: R, limit: u64) -> io::Result<u64> {
+    const PAT: &[u8] = b"PADDINGXXPADDING";
+    let mut rdr = rdr.take(limit);
+    let mut buf = [0u8; 4096];
+    let mut len = 0;
+    loop {
+        let n = read_up_to(&mut rdr, &mut buf)?;
+        let same = buf[..n]
+            .iter()
+            .enumerate()
+            .take_while(|(i, &b)| b == PAT[(len as usize + i) % PAT.len()])
+            .count();
+        len += same as u64;
+        if same < buf.len() {
+            return Ok(len);
+        }
+    }
+}
+
+/// Maps the known parts of the setup, everything in between is unknown
+pub fn map_setup(setup: &mut SetupOpt) -> anyhow::Result<SetupMap> {
+    let path = setup.path().to_path_buf();
+    let size = path.metadata()?.len();
+    let container = setup.container_offset();
+    let mut known = Vec::new();
+
+    let stub = StubInfo::read(BufReader::new(File::open(&path)?)).ok();
+    let overlay = stub.as_ref().map_or(0, |s| s.overlay_offset);
+    if let Some(stub) = &stub {
+        known.push(Region::new(RegionKind::Stub, 0..stub.overlay_offset, None));
+        if let Some(cert) = &stub.certificate_table {
+            known.push(Region::new(RegionKind::Certificate, cert.clone(), None));
+        }
+    }
+    if container > overlay {
+        let mut rdr = BufReader::new(File::open(&path)?);
+        rdr.seek(SeekFrom::Start(overlay))?;
+        let len = padding_len(rdr, container - overlay)?;
+        known.push(Region::new(
+            RegionKind::Padding,
+            overlay..overlay + len,
+            None,
+        ));
+    }
+
+    let (header_len, entry_header_len) = match setup {
+        SetupOpt::Nfo300(..) => (None, 0),
+        SetupOpt::Is(..) => (
+            Some(std::mem::size_of::<IsHeader>() as u64),
+            std::mem::size_of::<IsFileAttributes>() as u64,
+        ),
+    };
+    let mut spans = setup.entry_spans()?;
+    spans.sort_by_key(|(_, span)| span.offset);
+    // The NFO300 header ends with the entry lines, right before the first entry
+    let header_end = header_len
+        .map(|len| container + len)
+        .or_else(|| spans.first().map(|(_, span)| span.offset))
+        .unwrap_or(container);
+    known.push(Region::new(
+        RegionKind::ContainerHeader,
+        container..header_end,
+        None,
+    ));
+    for (name, span) in spans {
+        if entry_header_len > 0 {
+            let start = span.offset.saturating_sub(entry_header_len);
+            let range = start..span.offset;
+            known.push(Region::new(
+                RegionKind::EntryHeader,
+                range,
+                Some(name.clone()),
+            ));
+        }
+        let range = span.offset..span.offset + span.len;
+        known.push(Region::new(RegionKind::Entry, range, Some(name)));
+    }
+
+    Ok(SetupMap {
+        setup: path.display().to_string(),
+        format: setup.format_name(),
+        size,
+        regions: fill_unknown(known, size),
+    })
+}
+
+/// Sorts the regions and fills the gaps with unknown ones, overlaps are cut off
+fn fill_unknown(mut known: Vec<Region>, size: u64) -> Vec<Region> {
+    known.sort_by_key(|r| (r.range.start, r.range.end));
+    let mut regions = Vec::with_capacity(known.len() * 2);
+    let mut pos = 0;
+    for mut region in known {
+        region.range.end = region.range.end.min(size);
+        if region.range.start < pos {
+            if region.range.end > pos {
+                log::warn!(
+                    "{:?} overlaps at {:#x}..{:#x}",
+                    region.kind,
+                    region.range.start,
+                    pos
+                );
+            }
+            region.range.start = pos.min(region.range.end);
+        }
+        if region.is_empty() {
+            continue;
+        }
+        if region.range.start > pos {
+            regions.push(Region::new(
+                RegionKind::Unknown,
+                pos..region.range.start,
+                None,
+            ));
+        }
+        pos = region.range.end;
+        regions.push(region);
+    }
+    if pos < size {
+        regions.push(Region::new(RegionKind::Unknown, pos..size, None));
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        pack::tests::{nfo300_setup, split_setups},
+        util::unique_temp_dir,
+    };
+
+    use super::*;
+
+    #[test]
+    fn unknown_regions() {
+        let dir = unique_temp_dir("mssetup_analyze_test");
+        let setup_path = nfo300_setup(&dir, &[("Sound.wz", b"sound")]);
+
+        // Padding in front of the container and a payload after it
+        let data = std::fs::read(&setup_path).unwrap();
+        let mut padded = data[..0x400].to_vec();
+        padded.extend(b"PADDINGXXPADDINGPADDINGXX");
+        padded.extend(&data[0x400..]);
+        padded.extend(b"hidden");
+        std::fs::write(&setup_path, &padded).unwrap();
+
+        let mut setup = SetupOpt::open(&setup_path).unwrap();
+        let mut map = map_setup(&mut setup).unwrap();
+        let kinds = map.regions.iter().map(|r| r.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                RegionKind::Stub,
+                RegionKind::Padding,
+                RegionKind::ContainerHeader,
+                RegionKind::Entry,
+                RegionKind::Unknown
+            ]
+        );
+        assert_eq!(map.regions[1].range, 0x400..0x419);
+        assert_eq!(map.regions[3].name.as_deref(), Some("Client.zip"));
+        assert_eq!(map.unknown_size(), 6);
+
+        let out = dir.join("analyze");
+        map.dump(&out).unwrap();
+        let dump = map.regions[4].dump.clone().unwrap();
+        assert_eq!(std::fs::read(out.join(dump)).unwrap(), b"hidden");
+        assert!(out.join(MAP_FILE).is_file());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encoded_split_regions() {
+        let dir = unique_temp_dir("mssetup_analyze_split_test");
+        let setups = split_setups(&dir);
+
+        // The attributes of each volume are followed by its data, nothing is unknown
+        let mut setup = SetupOpt::open(&setups.is).unwrap();
+        let map = map_setup(&mut setup).unwrap();
+        assert_eq!(map.unknown_size(), 0, "{map:?}");
+        let mut expected = vec![
+            (RegionKind::Stub, None),
+            (RegionKind::ContainerHeader, None),
+        ];
+        for volume in setups.volumes.iter() {
+            let name = volume.file_name().unwrap().to_str().unwrap();
+            expected.push((RegionKind::EntryHeader, Some(name)));
+            expected.push((RegionKind::Entry, Some(name)));
+        }
+        let regions = map
+            .regions
+            .iter()
+            .map(|r| (r.kind, r.name.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(regions, expected);
+        let attr_len = std::mem::size_of::<IsFileAttributes>() as u64;
+        assert!(map.regions[2..]
+            .iter()
+            .step_by(2)
+            .all(|r| r.len() == attr_len));
+        assert_eq!(map.regions.last().unwrap().range.end, map.size);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+
+use crate::{
+    cancel::CancelToken,
+    filter::EntryFilter,
+    patch::{WzPatch, WzPatchHandler},
+    progress::Progress,
+    SetupOpt,
+};
+
+/// Runs the blocking closure on the blocking pool of the runtime
+async fn blocking<T, F>(f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("Blocking task failed")?
+}
+
+/// Writes the reader into the file, setups and patches must be seekable to be parsed
+pub async fn spool(mut rdr: impl AsyncRead + Unpin, path: &Path) -> anyhow::Result<u64> {
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("Create spool file: {}", path.display()))?;
+    let len = tokio::io::copy(&mut rdr, &mut file).await?;
+    file.flush().await?;
+    Ok(len)
+}
+
+/// Setup for async callers, the parsing and extraction run on the blocking pool.
+/// Dropping a future doesn't stop its blocking work, use the [`CancelToken`] for that
+#[derive(Clone)]
+pub struct AsyncSetup {
+    setup: Arc<Mutex<SetupOpt>>,
+}
+
+impl AsyncSetup {
+    pub async fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let setup = blocking(move || SetupOpt::open(path)).await?;
+        Ok(Self {
+            setup: Arc::new(Mutex::new(setup)),
+        })
+    }
+
+    /// Opens an uploaded setup, the data is spooled to `path` which must outlive the setup
+    pub async fn from_reader(rdr: impl AsyncRead + Unpin, path: &Path) -> anyhow::Result<Self> {
+        spool(rdr, path).await?;
+        Self::open(path).await
+    }
+
+    async fn with_setup<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut SetupOpt) -> anyhow::Result<T> + Send + 'static,
+    {
+        let setup = self.setup.clone();
+        blocking(move || {
+            let mut setup = setup
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Setup poisoned"))?;
+            f(&mut setup)
+        })
+        .await
+    }
+
+    pub async fn format_name(&self) -> anyhow::Result<&'static str> {
+        self.with_setup(|setup| Ok(setup.format_name())).await
+    }
+
+    /// Name and size of the embedded entries
+    pub async fn entries(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        self.with_setup(|setup| setup.entry_list()).await
+    }
+
+    /// Extracts the client into `out_dir`, the embedded archives are kept in `tmp_dir`
+    /// meanwhile
+    pub async fn extract(
+        &self,
+        tmp_dir: PathBuf,
+        out_dir: PathBuf,
+        filter: EntryFilter,
+        progress: Arc<dyn Progress>,
+        cancel: CancelToken,
+    ) -> anyhow::Result<()> {
+        self.with_setup(move |setup| {
+            setup.extract_setup(&tmp_dir, &out_dir, &filter, &*progress, &cancel)
+        })
+        .await
+    }
+}
+
+/// Applies the patch at `path` on the blocking pool and returns the handler afterwards
+pub async fn process_patch<H>(
+    path: impl AsRef<Path>,
+    mut handler: H,
+    cancel: CancelToken,
+) -> anyhow::Result<H>
+where
+    H: WzPatchHandler + Send + 'static,
+{
+    let path = path.as_ref().to_path_buf();
+    blocking(move || {
+        let mut patch = WzPatch::new(BufReader::new(File::open(&path)?))?;
+        patch.verify_checksum()?;
+        patch.process(&mut handler, &cancel)?;
+        Ok(handler)
+    })
+    .await
+}
+
+/// Applies an uploaded patch, the data is spooled to `path` first
+pub async fn process_patch_reader<H>(
+    rdr: impl AsyncRead + Unpin,
+    path: &Path,
+    handler: H,
+    cancel: CancelToken,
+) -> anyhow::Result<H>
+where
+    H: WzPatchHandler + Send + 'static,
+{
+    spool(rdr, path).await?;
+    process_patch(path, handler, cancel).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cancel::is_cancelled,
+        pack::tests::nfo300_setup,
+        patcher::{tests::sound_patch, WzPatcher},
+        progress::NoProgress,
+        util::unique_temp_dir,
+    };
+
+    use super::*;
+
+    #[test]
+    fn async_patch() {
+        let dir = unique_temp_dir("mssetup_async_test");
+        let (client, out) = (dir.join("client"), dir.join("patched"));
+        let data = sound_patch(&client);
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let patcher = WzPatcher::with_out_dir(&client, &out);
+        rt.block_on(process_patch_reader(
+            &data[..],
+            &dir.join("84.patch"),
+            patcher,
+            CancelToken::new(),
+        ))
+        .unwrap();
+        assert_eq!(std::fs::read(out.join("Sound.wz")).unwrap(), b"sound+++");
+
+        let setup = rt.block_on(AsyncSetup::from_reader(&data[..], &dir.join("setup.exe")));
+        assert!(setup.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn async_extract() {
+        let dir = unique_temp_dir("mssetup_async_extract_test");
+        let setup_path = nfo300_setup(&dir, &[("Sound.wz", b"sound")]);
+        let cancel = CancelToken::new();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let out = dir.join("out");
+        rt.block_on(async {
+            let setup = AsyncSetup::open(&setup_path).await?;
+            assert_eq!(setup.format_name().await?, "NFO300");
+            let tmp_dir = dir.join("tmp");
+            std::fs::create_dir_all(&tmp_dir)?;
+            let filter = EntryFilter::default();
+            setup
+                .extract(tmp_dir, out.clone(), filter, Arc::new(NoProgress), cancel)
+                .await
+        })
+        .unwrap();
+        assert_eq!(std::fs::read(out.join("Sound.wz")).unwrap(), b"sound");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn async_cancel_and_corrupt() {
+        let dir = unique_temp_dir("mssetup_async_cancel_test");
+        let files: [(&str, &[u8]); 2] = [("Data/Skill.wz", b"skill"), ("Sound.wz", b"sound")];
+        let setup_path = nfo300_setup(&dir.join("setup"), &files);
+        let zip_len = std::fs::metadata(dir.join("setup/vol/Client.zip"))
+            .unwrap()
+            .len();
+        let (tmp_dir, out) = (dir.join("tmp"), dir.join("out"));
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let setup = rt.block_on(AsyncSetup::open(&setup_path)).unwrap();
+        assert_eq!(
+            rt.block_on(setup.entries()).unwrap(),
+            [("Client.zip".to_string(), zip_len)]
+        );
+
+        // The token stops the blocking work, dropping the future wouldn't
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let all = EntryFilter::default();
+        let progress = Arc::new(NoProgress);
+        let res = setup.extract(tmp_dir.clone(), out.clone(), all, progress.clone(), cancel);
+        let err = rt.block_on(res).unwrap_err();
+        assert!(is_cancelled(&err), "{err:#}");
+
+        // A clone shares the setup, the filter applies inside the client archive
+        let filter = EntryFilter::new(&["dir:Data".to_string()], &[]).unwrap();
+        let shared = setup.clone();
+        let res = shared.extract(tmp_dir, out.clone(), filter, progress, CancelToken::new());
+        rt.block_on(res).unwrap();
+        assert_eq!(std::fs::read(out.join("Data/Skill.wz")).unwrap(), b"skill");
+        assert!(!out.join("Sound.wz").exists());
+
+        // A corrupted upload is rejected by its checksum before anything is patched
+        let client = dir.join("client");
+        let mut data = sound_patch(&client);
+        *data.last_mut().unwrap() ^= 0xFF;
+        let patched = dir.join("patched");
+        let patcher = WzPatcher::with_out_dir(&client, &patched);
+        let patch_path = dir.join("84.patch");
+        let res = process_patch_reader(&data[..], &patch_path, patcher, CancelToken::new());
+        assert!(rt.block_on(res).is_err());
+        assert!(!patched.join("Sound.wz").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
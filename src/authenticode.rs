@@ -0,0 +1,595 @@
+use std::{
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::digest::DynDigest;
+
+use crate::{pe::PeFile, util::to_hex};
+
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+const OID_SIGNED_DATA: &str = "1.2.840.113549.1.7.2";
+const OID_SPC_INDIRECT_DATA: &str = "1.3.6.1.4.1.311.2.1.4";
+const OID_SIGNING_TIME: &str = "1.2.840.113549.1.9.5";
+const OID_COUNTER_SIGNATURE: &str = "1.2.840.113549.1.9.6";
+const OID_RFC3161_TIMESTAMP: &str = "1.3.6.1.4.1.311.3.3.1";
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_CONTEXT_0: u8 = 0xA0;
+const TAG_CONTEXT_1: u8 = 0xA1;
+
+/// A single DER element
+#[derive(Debug, Clone, Copy)]
+struct Der<'a> {
+    tag: u8,
+    content: &'a [u8],
+    raw: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    /// Reads the next element, returns it and the remaining data
+    fn read(data: &'a [u8]) -> anyhow::Result<(Self, &'a [u8])> {
+        let tag = *data.first().context("Unexpected end of DER data")?;
+        let len_byte = *data.get(1).context("Unexpected end of DER data")?;
+        let (len, hdr_len) = if len_byte < 0x80 {
+            (len_byte as usize, 2)
+        } else {
+            let n = (len_byte & 0x7F) as usize;
+            if n == 0 || n > 4 {
+                anyhow::bail!("Unsupported DER length encoding: {len_byte:#x}");
+            }
+            let len_bytes = data.get(2..2 + n).context("Unexpected end of DER data")?;
+            let len = len_bytes
+                .iter()
+                .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            (len, 2 + n)
+        };
+
+        let end = hdr_len + len;
+        let raw = data.get(..end).context("DER element exceeds data")?;
+        Ok((
+            Self {
+                tag,
+                content: &raw[hdr_len..],
+                raw,
+            },
+            &data[end..],
+        ))
+    }
+
+    fn expect(self, tag: u8) -> anyhow::Result<Self> {
+        if self.tag != tag {
+            anyhow::bail!("Expected DER tag {tag:#x}, got {:#x}", self.tag);
+        }
+        Ok(self)
+    }
+
+    fn children(&self) -> anyhow::Result<Vec<Der<'a>>> {
+        let mut children = Vec::new();
+        let mut data = self.content;
+        while !data.is_empty() {
+            let (child, rest) = Der::read(data)?;
+            children.push(child);
+            data = rest;
+        }
+        Ok(children)
+    }
+
+    fn child(&self, ix: usize) -> anyhow::Result<Der<'a>> {
+        self.children()?
+            .get(ix)
+            .copied()
+            .with_context(|| format!("Missing DER child {ix}"))
+    }
+
+    fn oid(&self) -> anyhow::Result<String> {
+        let Der {
+            tag: TAG_OID,
+            content,
+            ..
+        } = self
+        else {
+            anyhow::bail!("Expected OID, got {:#x}", self.tag);
+        };
+        let first = *content.first().context("Empty OID")?;
+        let mut parts = vec![(first / 40) as u64, (first % 40) as u64];
+        let mut v = 0u64;
+        for &b in &content[1..] {
+            v = (v << 7) | (b & 0x7F) as u64;
+            if b & 0x80 == 0 {
+                parts.push(v);
+                v = 0;
+            }
+        }
+        Ok(parts
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join("."))
+    }
+
+    fn time(&self) -> Option<String> {
+        let s = std::str::from_utf8(self.content).ok()?;
+        let s = s.trim_end_matches('Z');
+        let full = match self.tag {
+            TAG_UTC_TIME => {
+                let yy: u32 = s.get(..2)?.parse().ok()?;
+                let century = if yy < 50 { "20" } else { "19" };
+                format!("{century}{s}")
+            }
+            TAG_GENERALIZED_TIME => s.to_string(),
+            _ => return None,
+        };
+        Some(format!(
+            "{}-{}-{} {}:{}:{} UTC",
+            full.get(0..4)?,
+            full.get(4..6)?,
+            full.get(6..8)?,
+            full.get(8..10)?,
+            full.get(10..12)?,
+            full.get(12..14).unwrap_or("00")
+        ))
+    }
+}
+
+/// Formats a X.501 name like `CN=..., O=...`
+fn format_name(name: &Der) -> anyhow::Result<String> {
+    let mut parts = Vec::new();
+    for rdn in name.children()? {
+        for attr in rdn.children()? {
+            let key = match attr.child(0)?.oid()?.as_str() {
+                "2.5.4.3" => "CN".to_string(),
+                "2.5.4.6" => "C".to_string(),
+                "2.5.4.7" => "L".to_string(),
+                "2.5.4.8" => "ST".to_string(),
+                "2.5.4.10" => "O".to_string(),
+                "2.5.4.11" => "OU".to_string(),
+                oid => oid.to_string(),
+            };
+            let value = String::from_utf8_lossy(attr.child(1)?.content);
+            parts.push(format!("{key}={value}"));
+        }
+    }
+    Ok(parts.join(", "))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    pub not_before: Option<String>,
+    pub not_after: Option<String>,
+}
+
+struct Cert<'a> {
+    info: CertInfo,
+    subject: &'a [u8],
+    issuer: &'a [u8],
+    serial: &'a [u8],
+}
+
+impl<'a> Cert<'a> {
+    fn parse(cert: &Der<'a>) -> anyhow::Result<Self> {
+        let tbs = cert.child(0)?.expect(TAG_SEQUENCE)?.children()?;
+        // Skip the optional explicit version
+        let fields = match tbs.first() {
+            Some(Der {
+                tag: TAG_CONTEXT_0, ..
+            }) => &tbs[1..],
+            _ => &tbs[..],
+        };
+        let [serial, _sig_alg, issuer, validity, subject, ..] = fields else {
+            anyhow::bail!("Invalid certificate");
+        };
+        let validity = validity.children()?;
+
+        Ok(Self {
+            info: CertInfo {
+                subject: format_name(subject)?,
+                issuer: format_name(issuer)?,
+                serial: to_hex(serial.content),
+                not_before: validity.first().and_then(|t| t.time()),
+                not_after: validity.get(1).and_then(|t| t.time()),
+            },
+            subject: subject.raw,
+            issuer: issuer.raw,
+            serial: serial.content,
+        })
+    }
+}
+
+/// Finds the values of the attribute in a signed/unsigned attribute set
+fn find_attr<'a>(attrs: &Der<'a>, oid: &str) -> anyhow::Result<Option<Der<'a>>> {
+    for attr in attrs.children()? {
+        if attr.child(0)?.oid()? == oid {
+            return Ok(Some(attr.child(1)?));
+        }
+    }
+    Ok(None)
+}
+
+/// Signing time from the signed attributes of a signer info
+fn signing_time(signer: &Der) -> anyhow::Result<Option<String>> {
+    let signed_attrs = signer
+        .children()?
+        .into_iter()
+        .find(|c| c.tag == TAG_CONTEXT_0);
+    let Some(signed_attrs) = signed_attrs else {
+        return Ok(None);
+    };
+    Ok(find_attr(&signed_attrs, OID_SIGNING_TIME)?
+        .and_then(|values| values.child(0).ok())
+        .and_then(|t| t.time()))
+}
+
+/// Generation time of a RFC3161 timestamp token
+fn rfc3161_time(token: &Der) -> anyhow::Result<Option<String>> {
+    let signed_data = token.child(1)?.child(0)?;
+    let encap = signed_data.child(2)?;
+    let tst_info = encap.child(1)?.child(0)?.expect(TAG_OCTET_STRING)?;
+    let (tst_info, _) = Der::read(tst_info.content)?;
+    Ok(tst_info
+        .children()?
+        .iter()
+        .find(|c| c.tag == TAG_GENERALIZED_TIME)
+        .and_then(|t| t.time()))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Signature {
+    pub digest_algorithm: String,
+    pub digest: String,
+    /// Digest calculated over the file, `None` for unsupported algorithms
+    pub computed_digest: Option<String>,
+    /// If the digest matches the file, `None` for unsupported algorithms. The signer's
+    /// signature over the digest isn't verified, so a copied signature still matches
+    pub digest_valid: Option<bool>,
+    pub timestamp: Option<String>,
+    /// Certificates from the signer up to the root
+    pub chain: Vec<CertInfo>,
+}
+
+fn digest_algorithm(oid: &str) -> Option<(&'static str, Box<dyn DynDigest>)> {
+    Some(match oid {
+        "1.2.840.113549.2.5" => ("MD5", Box::new(md5::Md5::default())),
+        "1.3.14.3.2.26" => ("SHA1", Box::new(sha1::Sha1::default())),
+        "2.16.840.1.101.3.4.2.1" => ("SHA256", Box::new(sha2::Sha256::default())),
+        _ => return None,
+    })
+}
+
+fn hash_range<R: Read + Seek>(
+    mut rdr: R,
+    digest: &mut dyn DynDigest,
+    range: Range<u64>,
+) -> anyhow::Result<()> {
+    rdr.seek(SeekFrom::Start(range.start))?;
+    let mut rdr = rdr.take(range.end.saturating_sub(range.start));
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = rdr.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+    }
+    Ok(())
+}
+
+/// Authenticode image hash, which covers everything except the checksum,
+/// the security directory entry and the certificate table
+pub fn pe_digest<R: Read + Seek>(
+    mut rdr: R,
+    pe: &PeFile,
+    digest: &mut dyn DynDigest,
+    cert_table: Range<u64>,
+) -> anyhow::Result<()> {
+    let file_size = rdr.seek(SeekFrom::End(0))?;
+    let checksum = pe.optional_header_offset + 64;
+    let sec_dir = pe.data_dirs_offset + 8 * crate::pe::IMAGE_DIRECTORY_ENTRY_SECURITY as u64;
+
+    hash_range(&mut rdr, digest, 0..checksum)?;
+    hash_range(&mut rdr, digest, checksum + 4..sec_dir)?;
+    hash_range(&mut rdr, digest, sec_dir + 8..cert_table.start)?;
+    hash_range(&mut rdr, digest, cert_table.end..file_size)?;
+    Ok(())
+}
+
+impl Signature {
+    /// Parses the PKCS#7 signed data of a certificate table entry
+    fn parse(pkcs7: &[u8]) -> anyhow::Result<(Self, String)> {
+        let (content_info, _) = Der::read(pkcs7)?;
+        if content_info.child(0)?.oid()? != OID_SIGNED_DATA {
+            anyhow::bail!("Certificate is not PKCS#7 signed data");
+        }
+        let signed_data = content_info.child(1)?.child(0)?.expect(TAG_SEQUENCE)?;
+        let fields = signed_data.children()?;
+
+        let encap = fields.get(2).context("Missing content info")?;
+        if encap.child(0)?.oid()? != OID_SPC_INDIRECT_DATA {
+            anyhow::bail!("Content is not SPC indirect data");
+        }
+        let digest_info = encap.child(1)?.child(0)?.child(1)?;
+        let digest_oid = digest_info.child(0)?.child(0)?.oid()?;
+        let digest = digest_info.child(1)?.expect(TAG_OCTET_STRING)?;
+
+        let certs = fields
+            .iter()
+            .find(|f| f.tag == TAG_CONTEXT_0)
+            .map(|c| c.children())
+            .transpose()?
+            .unwrap_or_default()
+            .iter()
+            .map(Cert::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let signer = fields
+            .last()
+            .context("Missing signer infos")?
+            .child(0)
+            .context("No signer")?;
+        let signer_fields = signer.children()?;
+        let sid = signer_fields.get(1).context("Missing signer id")?;
+
+        // Follow the issuers, starting at the signer certificate
+        let mut chain = Vec::new();
+        let mut cur = match sid.tag {
+            TAG_SEQUENCE => {
+                let issuer = sid.child(0)?.raw;
+                let serial = sid.child(1)?.expect(TAG_INTEGER)?.content;
+                certs
+                    .iter()
+                    .find(|c| c.issuer == issuer && c.serial == serial)
+            }
+            _ => None,
+        };
+        while let Some(cert) = cur {
+            chain.push(cert.info.clone());
+            if cert.issuer == cert.subject || chain.len() > certs.len() {
+                break;
+            }
+            cur = certs.iter().find(|c| c.subject == cert.issuer);
+        }
+
+        // Prefer the counter signature/timestamp token over the self reported time
+        let mut timestamp = None;
+        if let Some(unsigned) = signer_fields.iter().find(|c| c.tag == TAG_CONTEXT_1) {
+            if let Some(values) = find_attr(unsigned, OID_COUNTER_SIGNATURE)? {
+                timestamp = signing_time(&values.child(0)?)?;
+            } else if let Some(values) = find_attr(unsigned, OID_RFC3161_TIMESTAMP)? {
+                timestamp = rfc3161_time(&values.child(0)?)?;
+            }
+        }
+        if timestamp.is_none() {
+            timestamp = signing_time(&signer)?;
+        }
+
+        Ok((
+            Self {
+                digest_algorithm: digest_oid.clone(),
+                digest: to_hex(digest.content),
+                computed_digest: None,
+                digest_valid: None,
+                timestamp,
+                chain,
+            },
+            digest_oid,
+        ))
+    }
+
+    /// Reads the signature of a PE file and compares its digest with the file, `None` if
+    /// it's not signed
+    pub fn read<R: Read + Seek>(mut rdr: R) -> anyhow::Result<Option<Self>> {
+        let pe = PeFile::parse(&mut rdr)?;
+        let Some(table) = pe.certificate_table() else {
+            return Ok(None);
+        };
+
+        // The size comes from the header, so it's checked before allocating the table
+        let file_size = rdr.seek(SeekFrom::End(0))?;
+        if table.end > file_size {
+            anyhow::bail!("Certificate table exceeds the file size");
+        }
+        rdr.seek(SeekFrom::Start(table.start))?;
+        let mut data = vec![0u8; (table.end - table.start) as usize];
+        rdr.read_exact(&mut data)?;
+
+        // WIN_CERTIFICATE entries, each aligned to 8 bytes
+        let mut entries = data.as_slice();
+        let pkcs7 = loop {
+            if entries.len() < 8 {
+                anyhow::bail!("No PKCS#7 signed data in the certificate table");
+            }
+            let len = u32::from_le_bytes(entries[..4].try_into()?) as usize;
+            let ty = u16::from_le_bytes(entries[6..8].try_into()?);
+            let entry = entries.get(8..len).context("Invalid certificate entry")?;
+            if ty == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+                break entry;
+            }
+            entries = entries.get((len + 7) & !7..).unwrap_or_default();
+        };
+
+        let (mut sig, digest_oid) = Self::parse(pkcs7)?;
+        if let Some((name, mut digest)) = digest_algorithm(&digest_oid) {
+            pe_digest(&mut rdr, &pe, digest.as_mut(), table)?;
+            let computed = to_hex(&digest.finalize());
+            sig.digest_valid = Some(computed == sig.digest);
+            sig.digest_algorithm = name.to_string();
+            sig.computed_digest = Some(computed);
+        }
+
+        Ok(Some(sig))
+    }
+
+    pub fn log(&self) {
+        log::info!(
+            "Signature: {} digest {} ({})",
+            self.digest_algorithm,
+            self.digest,
+            match self.digest_valid {
+                Some(true) => "digest matches",
+                Some(false) => "digest DIFFERS",
+                None => "unsupported algorithm",
+            }
+        );
+        if let Some(timestamp) = &self.timestamp {
+            log::info!("\tTimestamp: {timestamp}");
+        }
+        for cert in self.chain.iter() {
+            log::info!("\tSigner: {} (issuer: {})", cert.subject, cert.issuer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn der_oid_and_time() {
+        // 1.2.840.113549.1.7.2
+        let oid = [
+            0x06, 0x09, 0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x02,
+        ];
+        let (der, rest) = Der::read(&oid).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(der.oid().unwrap(), OID_SIGNED_DATA);
+
+        let mut time = vec![TAG_UTC_TIME, 13];
+        time.extend(b"080714133742Z");
+        let (der, _) = Der::read(&time).unwrap();
+        assert_eq!(der.time().unwrap(), "2008-07-14 13:37:42 UTC");
+    }
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut data = match content.len() {
+            len @ 0..0x80 => vec![tag, len as u8],
+            len => vec![tag, 0x81, len as u8],
+        };
+        data.extend_from_slice(content);
+        data
+    }
+
+    /// PE without sections, signed with a SHA256 digest over the image
+    fn signed_pe() -> Vec<u8> {
+        let sha256 = [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+        let spc = [0x2B, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x04];
+        let signed_data = [0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x02];
+        let digest_info = der(
+            TAG_SEQUENCE,
+            &[
+                der(TAG_SEQUENCE, &der(TAG_OID, &sha256)),
+                der(TAG_OCTET_STRING, &[0; 32]),
+            ]
+            .concat(),
+        );
+        let spc_data = der(
+            TAG_SEQUENCE,
+            &[der(TAG_SEQUENCE, &[]), digest_info].concat(),
+        );
+        let encap = der(
+            TAG_SEQUENCE,
+            &[der(TAG_OID, &spc), der(TAG_CONTEXT_0, &spc_data)].concat(),
+        );
+        let sid = der(
+            TAG_SEQUENCE,
+            &[der(TAG_SEQUENCE, &[]), der(TAG_INTEGER, &[1])].concat(),
+        );
+        let signer = der(TAG_SEQUENCE, &[der(TAG_INTEGER, &[1]), sid].concat());
+        let signed = der(
+            TAG_SEQUENCE,
+            &[
+                der(TAG_INTEGER, &[1]),
+                der(0x31, &[]),
+                encap,
+                der(0x31, &signer),
+            ]
+            .concat(),
+        );
+        let pkcs7 = der(
+            TAG_SEQUENCE,
+            &[der(TAG_OID, &signed_data), der(TAG_CONTEXT_0, &signed)].concat(),
+        );
+
+        // DOS header, PE signature, COFF header and a 32 bit optional header with
+        // 16 data directories
+        let mut data = vec![0u8; 0x40];
+        data[..2].copy_from_slice(b"MZ");
+        data[0x3C..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        data.extend(b"PE\0\0");
+        let mut coff = [0u8; 20];
+        coff[16..18].copy_from_slice(&224u16.to_le_bytes());
+        data.extend(coff);
+        let mut opt = [0u8; 224];
+        opt[..2].copy_from_slice(&0x10bu16.to_le_bytes());
+        opt[64..68].copy_from_slice(b"CSUM");
+        opt[92..96].copy_from_slice(&16u32.to_le_bytes());
+        data.extend(opt);
+        data.extend(b"image data");
+        data.resize((data.len() + 7) & !7, 0);
+
+        // Certificate table with an unknown entry in front of the signed data
+        let table_start = data.len();
+        data.extend(16u32.to_le_bytes());
+        data.extend([0x00, 0x02, 0x01, 0x00]);
+        data.extend([0; 8]);
+        data.extend((8 + pkcs7.len() as u32).to_le_bytes());
+        data.extend([0x00, 0x02, 0x02, 0x00]);
+        data.extend(&pkcs7);
+        data.resize((data.len() + 7) & !7, 0);
+        let sec_dir = 0x40 + 4 + 20 + 96 + 8 * 4;
+        data[sec_dir..sec_dir + 4].copy_from_slice(&(table_start as u32).to_le_bytes());
+        let table_len = (data.len() - table_start) as u32;
+        data[sec_dir + 4..sec_dir + 8].copy_from_slice(&table_len.to_le_bytes());
+
+        let mut rdr = std::io::Cursor::new(&data);
+        let pe = PeFile::parse(&mut rdr).unwrap();
+        let mut digest = sha2::Sha256::default();
+        let table = table_start as u64..data.len() as u64;
+        pe_digest(&mut rdr, &pe, &mut digest, table).unwrap();
+        let digest = DynDigest::finalize(Box::new(digest));
+        let octets = pkcs7
+            .windows(2)
+            .position(|w| w == [TAG_OCTET_STRING, 32])
+            .unwrap();
+        let pos = table_start + 16 + 8 + octets + 2;
+        data[pos..pos + 32].copy_from_slice(&digest);
+        data
+    }
+
+    #[test]
+    fn signed_pe_digest() {
+        let data = signed_pe();
+        let sig = Signature::read(std::io::Cursor::new(&data))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sig.digest_algorithm, "SHA256");
+        assert_eq!(sig.digest_valid, Some(true));
+
+        // The checksum isn't covered by the digest
+        let mut patched = data.clone();
+        let checksum = patched.windows(4).position(|w| w == b"CSUM").unwrap();
+        patched[checksum] = b'X';
+        let sig = Signature::read(std::io::Cursor::new(&patched))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sig.digest_valid, Some(true));
+
+        let mut patched = data;
+        let image = patched.windows(5).position(|w| w == b"image").unwrap();
+        patched[image] = b'I';
+        let sig = Signature::read(std::io::Cursor::new(&patched))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sig.digest_valid, Some(false));
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use bytemuck::{Pod, Zeroable};
+
+use crate::util::{dos_datetime_to_system_time, DosAttributes};
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct CabFlags: u16 {
+        const PREV_CABINET = 0x1;
+        const NEXT_CABINET = 0x2;
+        const RESERVE_PRESENT = 0x4;
+    }
+}
+
+/// Attribute bit signaling the file name is utf-8 encoded
+const NAME_IS_UTF: u16 = 0x80;
+
+/// Folder indices of files, which span cabinets. Such a file is listed in each cabinet
+/// holding a part of it
+pub const CONTINUED_FROM_PREV: u16 = 0xFFFD;
+pub const CONTINUED_TO_NEXT: u16 = 0xFFFE;
+pub const CONTINUED_PREV_AND_NEXT: u16 = 0xFFFF;
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+pub struct CabHeader {
+    pub signature: [u8; 4],
+    pub reserved1: u32,
+    pub cb_cabinet: u32,
+    pub reserved2: u32,
+    pub coff_files: u32,
+    pub reserved3: u32,
+    pub version_minor: u8,
+    pub version_major: u8,
+    pub num_folders: u16,
+    pub num_files: u16,
+    pub flags: u16,
+    pub set_id: u16,
+    pub cabinet_ix: u16,
+}
+
+impl CabHeader {
+    pub fn flags(&self) -> CabFlags {
+        CabFlags::from_bits_truncate(self.flags)
+    }
+}
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+pub struct CabFolderHeader {
+    pub coff_cab_start: u32,
+    pub num_data: u16,
+    pub compress_type: u16,
+}
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+pub struct CabFileHeader {
+    pub size: u32,
+    pub folder_offset: u32,
+    pub folder_ix: u16,
+    pub date: u16,
+    pub time: u16,
+    pub attribs: u16,
+}
+
+#[derive(Debug)]
+pub struct CabEntry {
+    pub hdr: CabFileHeader,
+    pub name: String,
+}
+
+impl CabEntry {
+    /// Relative path of the file, with `/` as separator
+    pub fn path(&self) -> String {
+        self.name.replace('\\', "/")
+    }
+
+    pub fn size(&self) -> u64 {
+        self.hdr.size as u64
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        dos_datetime_to_system_time(self.hdr.date, self.hdr.time)
+    }
+
+    pub fn attributes(&self) -> DosAttributes {
+        DosAttributes::from_bits_truncate(self.hdr.attribs)
+    }
+
+    /// Whether the file starts in a previous cabinet
+    pub fn is_continued_from_prev(&self) -> bool {
+        matches!(
+            self.hdr.folder_ix,
+            CONTINUED_FROM_PREV | CONTINUED_PREV_AND_NEXT
+        )
+    }
+}
+
+/// Directory of a single cabinet file, the data itself is not decoded
+#[derive(Debug)]
+pub struct CabDirectory {
+    pub hdr: CabHeader,
+    pub folders: Vec<CabFolderHeader>,
+    pub files: Vec<CabEntry>,
+}
+
+fn read_cstr(rdr: &mut impl BufRead, utf8: bool) -> anyhow::Result<String> {
+    let mut buf = Vec::new();
+    rdr.read_until(0, &mut buf)?;
+    if buf.pop() != Some(0) {
+        anyhow::bail!("Unterminated cab string");
+    }
+    Ok(if utf8 {
+        String::from_utf8_lossy(&buf).into_owned()
+    } else {
+        buf.iter().map(|&b| b as char).collect()
+    })
+}
+
+impl CabDirectory {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read<R: BufRead + Seek>(mut rdr: R) -> anyhow::Result<Self> {
+        let mut hdr = CabHeader::zeroed();
+        rdr.read_exact(bytemuck::bytes_of_mut(&mut hdr))?;
+        if &hdr.signature != b"MSCF" {
+            anyhow::bail!("Invalid cab signature: {:?}", hdr.signature);
+        }
+
+        let flags = hdr.flags();
+        let mut folder_reserve = 0;
+        if flags.contains(CabFlags::RESERVE_PRESENT) {
+            let mut reserve = [0u8; 4];
+            rdr.read_exact(&mut reserve)?;
+            let hdr_reserve = u16::from_le_bytes([reserve[0], reserve[1]]);
+            folder_reserve = reserve[2] as i64;
+            rdr.seek(SeekFrom::Current(hdr_reserve as i64))?;
+        }
+        if flags.contains(CabFlags::PREV_CABINET) {
+            read_cstr(&mut rdr, false)?;
+            read_cstr(&mut rdr, false)?;
+        }
+        if flags.contains(CabFlags::NEXT_CABINET) {
+            read_cstr(&mut rdr, false)?;
+            read_cstr(&mut rdr, false)?;
+        }
+
+        let mut folders = Vec::with_capacity(hdr.num_folders as usize);
+        for _ in 0..hdr.num_folders {
+            let mut folder = CabFolderHeader::zeroed();
+            rdr.read_exact(bytemuck::bytes_of_mut(&mut folder))?;
+            rdr.seek(SeekFrom::Current(folder_reserve))?;
+            folders.push(folder);
+        }
+
+        rdr.seek(SeekFrom::Start(hdr.coff_files as u64))?;
+        let mut files = Vec::with_capacity(hdr.num_files as usize);
+        for _ in 0..hdr.num_files {
+            let mut file_hdr = CabFileHeader::zeroed();
+            rdr.read_exact(bytemuck::bytes_of_mut(&mut file_hdr))?;
+            let name = read_cstr(&mut rdr, file_hdr.attribs & NAME_IS_UTF != 0)?;
+            files.push(CabEntry {
+                hdr: file_hdr,
+                name,
+            });
+        }
+
+        Ok(Self {
+            hdr,
+            folders,
+            files,
+        })
+    }
+}
+
+/// File of a cabinet set with the folder of the set, which holds its data
+#[derive(Debug)]
+pub struct CabSetFile {
+    pub entry: CabEntry,
+    pub folder: usize,
+}
+
+/// Files of a cabinet set, each listed once. A folder continued in the next cabinet is a
+/// single folder of the set, since it is decompressed as one stream
+#[derive(Debug)]
+pub struct CabSet {
+    /// Files in the order of the set, which is the order of their data in the folders
+    pub files: Vec<CabSetFile>,
+    pub num_folders: usize,
+}
+
+impl CabSet {
+    pub fn open(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let dirs = paths
+            .iter()
+            .map(|cab| CabDirectory::open(cab).with_context(|| format!("Read cab: {:?}", cab)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::from_dirs(dirs))
+    }
+
+    pub fn from_dirs(dirs: Vec<CabDirectory>) -> Self {
+        let mut files = Vec::new();
+        let mut seen = HashSet::new();
+        let mut next_folder = 0;
+        let mut continues_to_next = false;
+        for dir in dirs {
+            // The first folder continues the last one of the previous cabinet
+            let continued = dir.hdr.flags().contains(CabFlags::PREV_CABINET)
+                && (continues_to_next || dir.files.iter().any(|f| f.is_continued_from_prev()));
+            let first = if continued && next_folder > 0 {
+                next_folder - 1
+            } else {
+                next_folder
+            };
+            let num_folders = dir.folders.len().max(1);
+            let last = first + num_folders - 1;
+            continues_to_next = dir.files.iter().any(|f| {
+                matches!(
+                    f.hdr.folder_ix,
+                    CONTINUED_TO_NEXT | CONTINUED_PREV_AND_NEXT
+                )
+            });
+            next_folder = last + 1;
+
+            for entry in dir.files {
+                let folder = match entry.hdr.folder_ix {
+                    CONTINUED_FROM_PREV => first,
+                    CONTINUED_TO_NEXT | CONTINUED_PREV_AND_NEXT => last,
+                    ix => first + ix as usize,
+                };
+                // The copies in the following cabinets are skipped
+                if seen.insert(entry.path().to_lowercase()) {
+                    files.push(CabSetFile { entry, folder });
+                }
+            }
+        }
+
+        Self {
+            files,
+            num_folders: next_folder,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Cabinet with the files in `(name, size, folder_ix)`, the names of the neighbour
+    /// cabinets are added according to the flags
+    fn build_cab(num_folders: u16, flags: CabFlags, names: &[(&str, u32, u16)]) -> Vec<u8> {
+        let mut links = Vec::<u8>::new();
+        for flag in [CabFlags::PREV_CABINET, CabFlags::NEXT_CABINET] {
+            if flags.contains(flag) {
+                links.extend(b"Data.cab\0Disk\0");
+            }
+        }
+        let files_off = std::mem::size_of::<CabHeader>()
+            + links.len()
+            + std::mem::size_of::<CabFolderHeader>() * num_folders as usize;
+        let hdr = CabHeader {
+            signature: *b"MSCF",
+            reserved1: 0,
+            cb_cabinet: 0,
+            reserved2: 0,
+            coff_files: files_off as u32,
+            reserved3: 0,
+            version_minor: 3,
+            version_major: 1,
+            num_folders,
+            num_files: names.len() as u16,
+            flags: flags.bits(),
+            set_id: 0,
+            cabinet_ix: 0,
+        };
+        let mut data = bytemuck::bytes_of(&hdr).to_vec();
+        data.extend(links);
+        for _ in 0..num_folders {
+            data.extend_from_slice(bytemuck::bytes_of(&CabFolderHeader::zeroed()));
+        }
+        for (name, size, folder_ix) in names {
+            let file = CabFileHeader {
+                size: *size,
+                folder_offset: 0,
+                folder_ix: *folder_ix,
+                date: ((2008 - 1980) << 9) | (7 << 5) | 14,
+                time: 0,
+                attribs: DosAttributes::READ_ONLY.bits(),
+            };
+            data.extend_from_slice(bytemuck::bytes_of(&file));
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+        }
+        data
+    }
+
+    #[test]
+    fn cab_directory() {
+        let data = build_cab(
+            1,
+            CabFlags::empty(),
+            &[("Data.wz", 10, 0), ("HShield\\ahnrpt.exe", 20, 0)],
+        );
+        let dir = CabDirectory::read(Cursor::new(data)).unwrap();
+        assert_eq!(dir.folders.len(), 1);
+        assert_eq!(dir.files.len(), 2);
+        assert_eq!(dir.files[1].path(), "HShield/ahnrpt.exe");
+        assert_eq!(dir.files[1].size(), 20);
+        assert!(dir.files[0].attributes().contains(DosAttributes::READ_ONLY));
+        assert!(dir.files[0].modified().is_some());
+
+        // Base.wz spans both cabinets, its second folder continues in the next one
+        let first = build_cab(
+            2,
+            CabFlags::NEXT_CABINET,
+            &[("Data.wz", 10, 0), ("Skill.wz", 10, 1), ("Base.wz", 30, CONTINUED_TO_NEXT)],
+        );
+        let second = build_cab(
+            2,
+            CabFlags::PREV_CABINET,
+            &[("Base.wz", 30, CONTINUED_FROM_PREV), ("Map.wz", 10, 0), ("Etc.wz", 5, 1)],
+        );
+        let dirs = [first, second].map(|data| CabDirectory::read(Cursor::new(data)).unwrap());
+        let set = CabSet::from_dirs(Vec::from(dirs));
+        let files = set
+            .files
+            .iter()
+            .map(|f| (f.entry.name.as_str(), f.folder))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [("Data.wz", 0), ("Skill.wz", 1), ("Base.wz", 1), ("Map.wz", 1), ("Etc.wz", 2)]
+        );
+        assert_eq!(set.num_folders, 3);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Read},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+
+/// Error of an operation, which was stopped by its [`CancelToken`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl From<Cancelled> for io::Error {
+    fn from(value: Cancelled) -> Self {
+        io::Error::other(value)
+    }
+}
+
+/// Whether the error or one of its causes is [`Cancelled`], also inside of io errors
+pub fn is_cancelled(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<Cancelled>()
+            || cause
+                .downcast_ref::<io::Error>()
+                .and_then(|err| err.get_ref())
+                .is_some_and(|inner| inner.is::<Cancelled>())
+    })
+}
+
+/// Shared flag to stop an extraction or a patch, clones refer to the same flag.
+/// Long operations check it between chunks and return [`Cancelled`]
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Token of the process, which is cancelled by Ctrl-C. The handler is installed once
+    /// by the first call, also for concurrent ones. A second Ctrl-C exits right away
+    pub fn ctrl_c() -> anyhow::Result<Self> {
+        static TOKEN: OnceLock<Result<CancelToken, String>> = OnceLock::new();
+        let token = TOKEN.get_or_init(|| {
+            let token = Self::new();
+            let handler = token.clone();
+            ctrlc::set_handler(move || {
+                if handler.is_cancelled() {
+                    std::process::exit(130);
+                }
+                log::warn!("Cancelling, press Ctrl-C again to exit");
+                handler.cancel();
+            })
+            .map(|()| token)
+            .map_err(|err| err.to_string())
+        });
+        token
+            .clone()
+            .map_err(|err| anyhow::anyhow!("Set Ctrl-C handler: {err}"))
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs the command to completion, the process is killed once cancelled.
+    /// A non-zero exit status is an error
+    pub fn run(&self, cmd: &mut Command) -> anyhow::Result<()> {
+        self.run_with_output(cmd, |_| {})
+    }
+
+    /// Same as [`CancelToken::run`], the output of the command is passed to `on_output`
+    /// as it arrives
+    pub fn run_with_output(
+        &self,
+        cmd: &mut Command,
+        mut on_output: impl FnMut(&[u8]),
+    ) -> anyhow::Result<()> {
+        self.check()?;
+        let program = cmd.get_program().to_string_lossy().into_owned();
+        let mut child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Run {program}"))?;
+
+        // The output is read by a thread, so the token is checked while the command is silent
+        let mut stdout = child.stdout.take().context("No output")?;
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok(n @ 1..) = stdout.read(&mut buf) {
+                if tx.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut open = true;
+        loop {
+            if open {
+                match rx.recv_timeout(Duration::from_millis(50)) {
+                    Ok(data) => on_output(&data),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => open = false,
+                }
+            } else if let Some(status) = child.try_wait()? {
+                if status.success() {
+                    return Ok(());
+                }
+                // The command may have been stopped by the same Ctrl-C
+                if self.is_cancelled() {
+                    return Err(Cancelled.into());
+                }
+                anyhow::bail!("{program} failed: {status}");
+            } else {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            if self.is_cancelled() {
+                kill(&mut child);
+                return Err(Cancelled.into());
+            }
+        }
+    }
+}
+
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Fails the reads with [`Cancelled`] once the token is cancelled
+pub struct CancelReader<'a, R> {
+    inner: R,
+    cancel: &'a CancelToken,
+}
+
+impl<'a, R> CancelReader<'a, R> {
+    pub fn new(inner: R, cancel: &'a CancelToken) -> Self {
+        Self { inner, cancel }
+    }
+}
+
+impl<R: Read> Read for CancelReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.cancel.check()?;
+        self.inner.read(buf)
+    }
+}
+
+impl<R: BufRead> BufRead for CancelReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.cancel.check()?;
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_reader() {
+        let cancel = CancelToken::new();
+        let mut rdr = CancelReader::new(&b"client data"[..], &cancel);
+        let mut buf = [0u8; 6];
+        rdr.read_exact(&mut buf).unwrap();
+
+        cancel.clone().cancel();
+        let err = anyhow::Error::from(rdr.read_exact(&mut buf).unwrap_err());
+        assert!(is_cancelled(&err.context("Copy entry")));
+        assert!(!is_cancelled(&anyhow::anyhow!("Broken")));
+        assert!(cancel.run(&mut Command::new("true")).is_err());
+
+        let cancel = CancelToken::new();
+        cancel.run(&mut Command::new("true")).unwrap();
+        let err = cancel.run(&mut Command::new("false")).unwrap_err();
+        assert!(!is_cancelled(&err));
+        let mut output = Vec::<u8>::new();
+        let mut cmd = Command::new("echo");
+        cmd.arg("7z");
+        cancel
+            .run_with_output(&mut cmd, |data| output.extend(data))
+            .unwrap();
+        assert_eq!(output, b"7z\n");
+    }
+
+    #[test]
+    fn ctrl_c_concurrent() {
+        // Only the first of the concurrent calls installs the handler
+        let tokens = (0..8)
+            .map(|_| std::thread::spawn(CancelToken::ctrl_c))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().unwrap().unwrap())
+            .collect::<Vec<_>>();
+        assert!(tokens.iter().all(|t| Arc::ptr_eq(&t.0, &tokens[0].0)));
+    }
+}
@@ -0,0 +1,381 @@
+use std::path::Path;
+
+use humansize::{SizeFormatter, DECIMAL};
+use rusqlite::{params, Connection, OptionalExtension};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS setups (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    size INTEGER NOT NULL,
+    modified TEXT,
+    sha256 TEXT NOT NULL,
+    format TEXT NOT NULL,
+    container_offset INTEGER NOT NULL,
+    stub_version TEXT,
+    signer TEXT,
+    signature_valid INTEGER,
+    region TEXT,
+    version TEXT
+);
+CREATE TABLE IF NOT EXISTS setup_entries (
+    setup_id INTEGER NOT NULL REFERENCES setups(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    size INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS patches (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    size INTEGER NOT NULL,
+    modified TEXT,
+    sha256 TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    from_version INTEGER,
+    to_version INTEGER,
+    added INTEGER NOT NULL,
+    modified_files INTEGER NOT NULL,
+    removed INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS setup_entries_setup ON setup_entries(setup_id);
+CREATE INDEX IF NOT EXISTS setups_sha256 ON setups(sha256);
+CREATE INDEX IF NOT EXISTS patches_sha256 ON patches(sha256);
+";
+
+/// Indexed setup file
+#[derive(Debug, Clone, Default)]
+pub struct SetupRecord {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<String>,
+    pub sha256: String,
+    pub format: String,
+    pub container_offset: u64,
+    pub stub_version: Option<String>,
+    /// Subject of the signing certificate
+    pub signer: Option<String>,
+    pub signature_valid: Option<bool>,
+    pub region: Option<String>,
+    pub version: Option<String>,
+    /// Name and size of the embedded entries
+    pub entries: Vec<(String, u64)>,
+}
+
+/// Indexed patch file
+#[derive(Debug, Clone, Default)]
+pub struct PatchRecord {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<String>,
+    pub sha256: String,
+    /// Version from the patch header, the client version the patch leads to
+    pub version: i32,
+    /// Versions from the file name, the target falls back to the header version
+    pub from_version: Option<u32>,
+    pub to_version: Option<u32>,
+    pub added: usize,
+    pub modified_files: usize,
+    pub removed: usize,
+}
+
+/// SQLite catalog of setups and patches
+pub struct Catalog {
+    conn: Connection,
+}
+
+impl Catalog {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// Checks if the file is indexed with the same size and modification time
+    pub fn is_indexed(
+        &self,
+        path: &str,
+        size: u64,
+        modified: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let found = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM setups WHERE path = ?1 AND size = ?2 AND modified IS ?3
+                 UNION SELECT 1 FROM patches WHERE path = ?1 AND size = ?2 AND modified IS ?3",
+                params![path, size, modified],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    pub fn insert_setup(&mut self, setup: &SetupRecord) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM setups WHERE path = ?1", params![setup.path])?;
+        tx.execute(
+            "INSERT INTO setups (path, size, modified, sha256, format, container_offset,
+                stub_version, signer, signature_valid, region, version)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                setup.path,
+                setup.size,
+                setup.modified,
+                setup.sha256,
+                setup.format,
+                setup.container_offset,
+                setup.stub_version,
+                setup.signer,
+                setup.signature_valid,
+                setup.region,
+                setup.version
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        {
+            let mut stmt =
+                tx.prepare("INSERT INTO setup_entries (setup_id, name, size) VALUES (?1, ?2, ?3)")?;
+            for (name, size) in setup.entries.iter() {
+                stmt.execute(params![id, name, size])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn insert_patch(&mut self, patch: &PatchRecord) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO patches (path, size, modified, sha256, version,
+                from_version, to_version, added, modified_files, removed)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                patch.path,
+                patch.size,
+                patch.modified,
+                patch.sha256,
+                patch.version,
+                patch.from_version,
+                patch.to_version,
+                patch.added,
+                patch.modified_files,
+                patch.removed
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Removes files, which were indexed below `root` but don't exist anymore
+    pub fn prune(&self, root: &str) -> anyhow::Result<usize> {
+        // Compared as a plain prefix, `LIKE` would take `_` and `%` as wildcards
+        let mut prefix = root.to_string();
+        if !prefix.ends_with(['/', std::path::MAIN_SEPARATOR]) {
+            prefix.push(std::path::MAIN_SEPARATOR);
+        }
+        let mut removed = 0;
+        for table in ["setups", "patches"] {
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT path FROM {table} WHERE substr(path, 1, length(?1)) = ?1"
+            ))?;
+            let paths = stmt
+                .query_map(params![prefix], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            for path in paths.iter().filter(|p| !Path::new(p).exists()) {
+                self.conn.execute(
+                    &format!("DELETE FROM {table} WHERE path = ?1"),
+                    params![path],
+                )?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Setups filtered by version, region or a `LIKE` pattern on the path
+    pub fn find_setups(
+        &self,
+        version: Option<&str>,
+        region: Option<&str>,
+        path: Option<&str>,
+    ) -> anyhow::Result<Vec<SetupRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT path, size, modified, sha256, format, container_offset, stub_version,
+                signer, signature_valid, region, version
+             FROM setups
+             WHERE (?1 IS NULL OR version = ?1 OR version LIKE ?1 || '.%')
+                AND (?2 IS NULL OR region = ?2 COLLATE NOCASE)
+                AND (?3 IS NULL OR path LIKE ?3)
+             ORDER BY path",
+        )?;
+        let setups = stmt
+            .query_map(params![version, region, path], |row| {
+                Ok(SetupRecord {
+                    path: row.get(0)?,
+                    size: row.get(1)?,
+                    modified: row.get(2)?,
+                    sha256: row.get(3)?,
+                    format: row.get(4)?,
+                    container_offset: row.get(5)?,
+                    stub_version: row.get(6)?,
+                    signer: row.get(7)?,
+                    signature_valid: row.get(8)?,
+                    region: row.get(9)?,
+                    version: row.get(10)?,
+                    entries: Vec::new(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(setups)
+    }
+
+    /// Setup paths and entries with a name matching the `LIKE` pattern
+    pub fn find_entries(&self, name: &str) -> anyhow::Result<Vec<(String, String, u64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT s.path, e.name, e.size FROM setup_entries e
+             JOIN setups s ON s.id = e.setup_id
+             WHERE e.name LIKE ?1 ORDER BY s.path, e.name",
+        )?;
+        let entries = stmt
+            .query_map(params![name], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    /// Patches from or to the version
+    pub fn find_patches(&self, version: Option<u32>) -> anyhow::Result<Vec<PatchRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT path, size, modified, sha256, version, from_version, to_version,
+                added, modified_files, removed
+             FROM patches
+             WHERE ?1 IS NULL OR from_version = ?1 OR to_version = ?1 OR version = ?1
+             ORDER BY from_version, to_version, path",
+        )?;
+        let patches = stmt
+            .query_map(params![version], |row| {
+                Ok(PatchRecord {
+                    path: row.get(0)?,
+                    size: row.get(1)?,
+                    modified: row.get(2)?,
+                    sha256: row.get(3)?,
+                    version: row.get(4)?,
+                    from_version: row.get(5)?,
+                    to_version: row.get(6)?,
+                    added: row.get(7)?,
+                    modified_files: row.get(8)?,
+                    removed: row.get(9)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(patches)
+    }
+
+    /// Paths of all setups and patches with the hash
+    pub fn find_hash(&self, sha256: &str) -> anyhow::Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT path FROM setups WHERE sha256 = ?1
+             UNION SELECT path FROM patches WHERE sha256 = ?1 ORDER BY path",
+        )?;
+        let paths = stmt
+            .query_map(params![sha256.to_ascii_lowercase()], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(paths)
+    }
+}
+
+impl SetupRecord {
+    pub fn log(&self) {
+        log::info!(
+            "{} - {} {} - {} v{} - {}",
+            self.path,
+            self.format,
+            SizeFormatter::new(self.size, DECIMAL),
+            self.region.as_deref().unwrap_or("?"),
+            self.version.as_deref().unwrap_or("?"),
+            match (&self.signer, self.signature_valid) {
+                (Some(signer), Some(false)) => format!("signed by {signer} (digest DIFFERS)"),
+                (Some(signer), _) => format!("signed by {signer}"),
+                (None, _) => "unsigned".to_string(),
+            }
+        );
+    }
+}
+
+impl PatchRecord {
+    pub fn log(&self) {
+        let v = |v: Option<u32>| v.map_or("?".to_string(), |v| v.to_string());
+        log::info!(
+            "{} - {} -> {} (header: {}) {} - {} added, {} modified, {} removed",
+            self.path,
+            v(self.from_version),
+            v(self.to_version),
+            self.version,
+            SizeFormatter::new(self.size, DECIMAL),
+            self.added,
+            self.modified_files,
+            self.removed
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog_queries() {
+        let mut catalog = Catalog::open(":memory:").unwrap();
+        let mut setup = SetupRecord {
+            path: "setups/GMSSetupv83.exe".to_string(),
+            size: 10,
+            sha256: "aa".to_string(),
+            format: "NFO300".to_string(),
+            region: Some("GMS".to_string()),
+            version: Some("83.1.0".to_string()),
+            entries: vec![("Maple.zip".to_string(), 8), ("Maple.z01".to_string(), 2)],
+            ..Default::default()
+        };
+        catalog.insert_setup(&setup).unwrap();
+        // Reindexing replaces the entries
+        setup.entries.pop();
+        catalog.insert_setup(&setup).unwrap();
+        catalog
+            .insert_patch(&PatchRecord {
+                path: "patches/00083to00084.patch".to_string(),
+                sha256: "bb".to_string(),
+                version: 84,
+                from_version: Some(83),
+                to_version: Some(84),
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert!(catalog
+            .is_indexed("setups/GMSSetupv83.exe", 10, None)
+            .unwrap());
+        assert!(!catalog
+            .is_indexed("setups/GMSSetupv83.exe", 11, None)
+            .unwrap());
+        assert_eq!(
+            catalog
+                .find_setups(Some("83"), Some("gms"), None)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(catalog
+            .find_setups(Some("8"), None, None)
+            .unwrap()
+            .is_empty());
+        assert_eq!(catalog.find_entries("%.z%").unwrap().len(), 1);
+        assert_eq!(catalog.find_patches(Some(83)).unwrap().len(), 1);
+        assert_eq!(
+            catalog.find_hash("BB").unwrap(),
+            ["patches/00083to00084.patch"]
+        );
+
+        // Only missing files below the directory are pruned, `_` is no wildcard
+        assert_eq!(catalog.prune("set_ps").unwrap(), 0);
+        assert_eq!(catalog.prune("setup").unwrap(), 0);
+        assert_eq!(catalog.prune("setups").unwrap(), 1);
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sevenz_rust::{nt_time::FileTime, SevenZArchiveEntry, SevenZWriter};
+
+use crate::{
+    authenticode::Signature,
+    cab::{CabEntry, CabSet},
+    cancel::{CancelReader, CancelToken},
+    extract::{zip_modified_time, Z7FileReader},
+    manifest::{ArchiveManifest, ManifestFile, SetupMeta, MANIFEST_FILE},
+    pe::StubInfo,
+    util::{sha256_file, sha256_reader, unique_temp_dir},
+    ClientArchives, SetupOpt,
+};
+
+/// Format of a converted setup, taken from the extension of the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    TarZst,
+    SevenZ,
+}
+
+impl ArchiveFormat {
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let name = path.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".tar.zst") {
+            Ok(Self::TarZst)
+        } else if name.ends_with(".7z") {
+            Ok(Self::SevenZ)
+        } else {
+            anyhow::bail!(
+                "Unknown archive format, use .tar.zst or .7z: {}",
+                path.display()
+            )
+        }
+    }
+}
+
+/// Yields exactly `left` bytes of the inner reader, a shorter one is an error
+struct SizedReader<R> {
+    inner: R,
+    left: u64,
+}
+
+impl<R: Read> Read for SizedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let max = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} bytes missing", self.left),
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+enum ArchiveWriter {
+    TarZst(tar::Builder<zstd::Encoder<'static, BufWriter<File>>>),
+    SevenZ(SevenZWriter<File>),
+}
+
+impl ArchiveWriter {
+    fn create(path: &Path, format: ArchiveFormat) -> anyhow::Result<Self> {
+        Ok(match format {
+            ArchiveFormat::TarZst => {
+                let file = BufWriter::new(File::create(path)?);
+                Self::TarZst(tar::Builder::new(zstd::Encoder::new(file, 19)?))
+            }
+            ArchiveFormat::SevenZ => Self::SevenZ(SevenZWriter::create(path)?),
+        })
+    }
+
+    /// Adds a file, the reader must yield at least `size` bytes
+    fn add(
+        &mut self,
+        path: &str,
+        size: u64,
+        modified: Option<SystemTime>,
+        rdr: impl Read,
+    ) -> anyhow::Result<()> {
+        let rdr = SizedReader {
+            inner: rdr,
+            left: size,
+        };
+        match self {
+            Self::TarZst(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(size);
+                header.set_mode(0o644);
+                let mtime = modified.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok());
+                header.set_mtime(mtime.map_or(0, |t| t.as_secs()));
+                builder.append_data(&mut header, path, rdr)?;
+            }
+            Self::SevenZ(writer) => {
+                let mut entry = SevenZArchiveEntry::new();
+                entry.name = path.to_string();
+                entry.has_stream = size > 0;
+                if let Some(t) = modified.and_then(|t| FileTime::try_from(t).ok()) {
+                    entry.has_last_modified_date = true;
+                    entry.last_modified_date = t;
+                }
+                writer.push_archive_entry(entry, Some(rdr))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Self::TarZst(builder) => {
+                builder.into_inner()?.finish()?.flush()?;
+            }
+            Self::SevenZ(writer) => {
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format, hashes and signature of the setup file
+fn setup_meta(setup: &SetupOpt) -> anyhow::Result<SetupMeta> {
+    let path = setup.path();
+    let stub_sha256 = match StubInfo::read(BufReader::new(File::open(path)?)) {
+        Ok(stub) => Some(sha256_reader(File::open(path)?.take(stub.overlay_offset))?),
+        Err(err) => {
+            log::warn!("Invalid PE stub: {err}");
+            None
+        }
+    };
+    let signature = Signature::read(BufReader::new(File::open(path)?)).unwrap_or_else(|err| {
+        log::warn!("Invalid signature: {err}");
+        None
+    });
+    Ok(SetupMeta {
+        setup: path.display().to_string(),
+        format: setup.format_name().to_string(),
+        size: path.metadata()?.len(),
+        sha256: sha256_file(path)?,
+        stub_sha256,
+        signature,
+    })
+}
+
+fn manifest_file(path: &str, size: u64, modified: Option<SystemTime>) -> ManifestFile {
+    ManifestFile {
+        path: path.to_string(),
+        size,
+        modified: modified.map(|t| DateTime::<Utc>::from(t).to_rfc3339()),
+        source: None,
+    }
+}
+
+/// Converts the setup into a single archive at `out`. The client files are streamed
+/// from the inner zip or cabinets, the manifest with the metadata of the setup is the
+/// last entry. Cabinets and MSIs are spooled to a temp dir first, since 7z only reads
+/// them from files
+pub fn convert_setup(
+    setup: &mut SetupOpt,
+    out: &Path,
+    cancel: &CancelToken,
+) -> anyhow::Result<ArchiveManifest> {
+    let format = ArchiveFormat::from_path(out)?;
+    let meta = setup_meta(setup)?;
+    let spool_dir = unique_temp_dir("mssetupconvert");
+    let res = setup
+        .client_archives(&spool_dir, cancel)
+        .and_then(|archives| write_archive(archives, out, format, meta, cancel));
+    let _ = std::fs::remove_dir_all(&spool_dir);
+    if res.is_err() {
+        let _ = std::fs::remove_file(out);
+    }
+    res
+}
+
+fn write_archive(
+    archives: ClientArchives,
+    out: &Path,
+    format: ArchiveFormat,
+    meta: SetupMeta,
+    cancel: &CancelToken,
+) -> anyhow::Result<ArchiveManifest> {
+    let mut writer = ArchiveWriter::create(out, format)?;
+    let mut files = Vec::new();
+    match archives {
+        ClientArchives::Zip(mut archive) => {
+            for ix in 0..archive.len() {
+                let file = archive.by_index(ix)?;
+                if file.is_dir() {
+                    continue;
+                }
+                let (name, size) = (file.name().to_string(), file.size());
+                let modified = zip_modified_time(&file);
+                let rdr = CancelReader::new(file, cancel);
+                writer
+                    .add(&name, size, modified, rdr)
+                    .with_context(|| format!("Add {name}"))?;
+                files.push(manifest_file(&name, size, modified));
+            }
+        }
+        ClientArchives::Cabs(cabs) => {
+            // One 7z per folder, which writes its files in the order of their data
+            let mut folders = BTreeMap::<usize, Vec<CabEntry>>::new();
+            for file in CabSet::open(&cabs)?.files {
+                folders.entry(file.folder).or_default().push(file.entry);
+            }
+            for mut entries in folders.into_values() {
+                entries.sort_by_key(|e| e.hdr.folder_offset);
+                let names = entries.iter().map(|e| e.path()).collect::<Vec<_>>();
+                let mut rdr = CancelReader::new(Z7FileReader::open(&cabs[0], &names)?, cancel);
+                for (entry, name) in entries.iter().zip(names) {
+                    let (size, modified) = (entry.size(), entry.modified());
+                    writer
+                        .add(&name, size, modified, &mut rdr)
+                        .with_context(|| format!("Add {name}"))?;
+                    files.push(manifest_file(&name, size, modified));
+                }
+                // Anything left means 7z matched other files than the listed ones
+                if rdr.read(&mut [0u8; 1])? != 0 {
+                    anyhow::bail!("7z wrote more than the files of the folder");
+                }
+            }
+        }
+    }
+
+    let manifest = ArchiveManifest { setup: meta, files };
+    let data = serde_json::to_vec_pretty(&manifest)?;
+    writer.add(
+        MANIFEST_FILE,
+        data.len() as u64,
+        Some(SystemTime::now()),
+        &data[..],
+    )?;
+    writer.finish()?;
+    log::info!(
+        "Converted {} files into {}",
+        manifest.files.len(),
+        out.display()
+    );
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        pack::tests::{nfo300_setup, split_setups},
+        util::unique_temp_dir,
+    };
+
+    use super::*;
+
+    #[test]
+    fn setup_to_archives() {
+        let dir = unique_temp_dir("mssetup_convert_test");
+        let files: [(&str, &[u8]); 2] = [("Data/Skill.wz", b"skill"), ("Sound.wz", b"sound")];
+        let setup_path = nfo300_setup(&dir, &files);
+        let cancel = CancelToken::new();
+
+        let mut setup = SetupOpt::open(&setup_path).unwrap();
+        let tar_path = dir.join("client.tar.zst");
+        let manifest = convert_setup(&mut setup, &tar_path, &cancel).unwrap();
+        assert_eq!(manifest.setup.format, "NFO300");
+        assert!(manifest.setup.stub_sha256.is_some());
+        let names = manifest
+            .files
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Data/Skill.wz", "Sound.wz"]);
+
+        let decoder = zstd::Decoder::new(File::open(&tar_path).unwrap()).unwrap();
+        let mut tar = tar::Archive::new(decoder);
+        let mut entries = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            entries.push((entry.path().unwrap().display().to_string(), data));
+        }
+        assert_eq!(entries[1], ("Sound.wz".to_string(), b"sound".to_vec()));
+        assert_eq!(entries[2].0, MANIFEST_FILE);
+
+        let seven_path = dir.join("client.7z");
+        convert_setup(&mut setup, &seven_path, &cancel).unwrap();
+        sevenz_rust::decompress_file(&seven_path, dir.join("7z")).unwrap();
+        let skill = std::fs::read(dir.join("7z/Data/Skill.wz")).unwrap();
+        assert_eq!(skill, b"skill");
+        assert!(dir.join("7z").join(MANIFEST_FILE).is_file());
+
+        assert!(convert_setup(&mut setup, &dir.join("client.rar"), &cancel).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encoded_split_setup() {
+        let dir = unique_temp_dir("mssetup_convert_split_test");
+        let setups = split_setups(&dir);
+
+        // The client of the joined volumes is written with the manifest last
+        let mut setup = SetupOpt::open(&setups.is).unwrap();
+        let tar_path = dir.join("client.tar.zst");
+        let manifest = convert_setup(&mut setup, &tar_path, &CancelToken::new()).unwrap();
+        assert_eq!(manifest.setup.format, "InstallShield");
+        assert_eq!(manifest.files[0].size, setups.map.len() as u64);
+
+        let decoder = zstd::Decoder::new(File::open(&tar_path).unwrap()).unwrap();
+        let mut tar = tar::Archive::new(decoder);
+        let mut entries = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            entries.push((entry.path().unwrap().display().to_string(), data));
+        }
+        let names = entries.iter().map(|e| e.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["Data/Map.wz", "Data/Skill.wz", "Sound.wz", MANIFEST_FILE]
+        );
+        assert!(entries[0].1 == setups.map);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use humansize::{SizeFormatter, DECIMAL};
+use serde::Serialize;
+
+use crate::{
+    manifest::is_output_file,
+    patcher::WzPatcherInfo,
+    util::{get_all_nested_files, read_up_to},
+};
+
+/// Differences between two extracted clients
+#[derive(Debug, Default, Serialize)]
+pub struct ClientDiff {
+    pub added: Vec<(String, u64)>,
+    pub removed: Vec<(String, u64)>,
+    /// Path, old and new size
+    pub modified: Vec<(String, u64, u64)>,
+}
+
+/// Sizes of all client files by their relative path, skipping the report and manifest
+fn client_files(dir: &Path) -> anyhow::Result<BTreeMap<String, u64>> {
+    let mut files = BTreeMap::new();
+    for path in get_all_nested_files(dir)? {
+        let rel = path.strip_prefix(dir)?.to_string_lossy().replace('\\', "/");
+        if is_output_file(&rel) {
+            continue;
+        }
+        files.insert(rel, path.metadata()?.len());
+    }
+    Ok(files)
+}
+
+fn files_equal(a: &Path, b: &Path) -> anyhow::Result<bool> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+    loop {
+        let n = read_up_to(a.by_ref(), &mut buf_a)?;
+        let m = read_up_to(b.by_ref(), &mut buf_b)?;
+        if buf_a[..n] != buf_b[..m] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+impl ClientDiff {
+    pub fn from_dirs(old: &Path, new: &Path) -> anyhow::Result<Self> {
+        let old_files = client_files(old)?;
+        let new_files = client_files(new)?;
+        let mut diff = Self::default();
+
+        for (path, &old_size) in old_files.iter() {
+            match new_files.get(path) {
+                None => diff.removed.push((path.clone(), old_size)),
+                Some(&new_size) => {
+                    if old_size != new_size || !files_equal(&old.join(path), &new.join(path))? {
+                        diff.modified.push((path.clone(), old_size, new_size));
+                    }
+                }
+            }
+        }
+        for (path, &size) in new_files.iter() {
+            if !old_files.contains_key(path) {
+                diff.added.push((path.clone(), size));
+            }
+        }
+
+        Ok(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+
+    /// Info in the shape a patch from the old to the new client would produce, the patch
+    /// data of a modified file is unknown, so it's counted as fully replaced
+    pub fn to_patcher_info(&self) -> WzPatcherInfo {
+        WzPatcherInfo {
+            added_files: self
+                .added
+                .iter()
+                .map(|(path, size)| (path.clone(), *size as usize))
+                .collect(),
+            removed_files: self.removed.iter().map(|(path, _)| path.clone()).collect(),
+            modified_files: self
+                .modified
+                .iter()
+                .map(|(path, _, new)| (path.clone(), *new as usize, *new as usize))
+                .collect(),
+        }
+    }
+
+    pub fn log(&self) {
+        log::info!("Added");
+        for (path, size) in self.added.iter() {
+            log::info!("\t{path} - {}", SizeFormatter::new(*size, DECIMAL));
+        }
+
+        log::info!("Modified");
+        for (path, old, new) in self.modified.iter() {
+            let delta = *new as i64 - *old as i64;
+            log::info!(
+                "\t{path} - {} ({}{})",
+                SizeFormatter::new(*new, DECIMAL),
+                if delta < 0 { "-" } else { "+" },
+                SizeFormatter::new(delta.unsigned_abs(), DECIMAL)
+            );
+        }
+
+        log::info!("Removed");
+        for (path, size) in self.removed.iter() {
+            log::info!("\t{path} - {}", SizeFormatter::new(*size, DECIMAL));
+        }
+
+        let added: u64 = self.added.iter().map(|e| e.1).sum();
+        let removed: u64 = self.removed.iter().map(|e| e.1).sum();
+        log::info!(
+            "Total: {} added, {} modified, {} removed ({} new, {} removed)",
+            self.added.len(),
+            self.modified.len(),
+            self.removed.len(),
+            SizeFormatter::new(added, DECIMAL),
+            SizeFormatter::new(removed, DECIMAL)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{manifest::MANIFEST_FILE, util::unique_temp_dir};
+
+    use super::*;
+
+    #[test]
+    fn diff_dirs() {
+        let dir = unique_temp_dir("mssetup_diff_test");
+        let (old, new) = (dir.join("old"), dir.join("new"));
+        for d in [&old, &new] {
+            std::fs::create_dir_all(d.join("HShield")).unwrap();
+            std::fs::write(d.join("Sound.wz"), b"sound").unwrap();
+            std::fs::write(d.join(MANIFEST_FILE), d.to_string_lossy().as_bytes()).unwrap();
+        }
+        std::fs::write(old.join("Skill.wz"), b"skill").unwrap();
+        std::fs::write(new.join("Skill.wz"), b"skall").unwrap();
+        std::fs::write(old.join("HShield/v3.dll"), b"v3").unwrap();
+        std::fs::write(new.join("Base.wz"), b"base").unwrap();
+
+        let diff = ClientDiff::from_dirs(&old, &new).unwrap();
+        assert_eq!(diff.added, [("Base.wz".to_string(), 4)]);
+        assert_eq!(diff.removed, [("HShield/v3.dll".to_string(), 2)]);
+        assert_eq!(diff.modified, [("Skill.wz".to_string(), 5, 5)]);
+        assert!(ClientDiff::from_dirs(&old, &old).unwrap().is_empty());
+
+        let info = diff.to_patcher_info();
+        assert_eq!(info.removed_files, ["HShield/v3.dll"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
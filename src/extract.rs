@@ -1,35 +1,646 @@
 use std::{
+    collections::BTreeMap,
     fs::File,
-    io::{self, Seek},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Child, ChildStdout, Command, Stdio},
+    time::SystemTime,
 };
 
 use anyhow::Context;
-use zipunsplitlib::file::{JoinedFile, MemoryCowFile, Opener};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use zip::{extra_fields::ExtraField, read::ZipFile, ZipArchive};
 
-pub struct JoinedOpener(pub Vec<PathBuf>);
+use crate::{
+    cab::CabSet,
+    cancel::{is_cancelled, CancelReader, CancelToken},
+    filter::EntryFilter,
+    progress::{Progress, ProgressEvent, ProgressReader, Stage},
+    pe::StubInfo,
+    util::{
+        dos_datetime_to_system_time, filetime_to_system_time, read_up_to, unique_name,
+        DosAttributes, SetupFormat,
+    },
+};
+
+/// Archive found inside an extracted client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NestedArchive {
+    Zip,
+    Cab,
+    Msi,
+    /// Executable with a NFO300 or InstallShield container in its overlay
+    Setup,
+}
+
+impl NestedArchive {
+    /// Detects the archive by its magic, executables are only taken if they carry a container
+    pub fn detect(path: &Path) -> anyhow::Result<Option<Self>> {
+        const MSI_MAGIC: &[u8] = &[0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 8];
+        let n = read_up_to(&mut file, &mut magic)?;
+        let magic = &magic[..n];
+
+        Ok(if magic.starts_with(b"PK\x03\x04") {
+            Some(Self::Zip)
+        } else if magic.starts_with(b"MSCF") {
+            Some(Self::Cab)
+        } else if magic.starts_with(MSI_MAGIC) {
+            Some(Self::Msi)
+        } else if magic.starts_with(b"MZ") && Self::is_setup(io::BufReader::new(file)) {
+            Some(Self::Setup)
+        } else {
+            None
+        })
+    }
+
+    fn is_setup<R: Read + Seek>(mut rdr: R) -> bool {
+        match StubInfo::read(&mut rdr) {
+            Ok(stub) if stub.overlay_size() > 0 => {
+                SetupFormat::from_overlay(rdr, stub.overlay_offset, stub.overlay_end).is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Split zip set opened from its files
+pub type SplitZip = ZipArchive<PageOverlay<JoinedReader<File>>>;
+
+/// Opens a split zip set as a single archive
+pub fn open_zip_split(paths: Vec<PathBuf>) -> anyhow::Result<SplitZip> {
+    let parts = paths
+        .iter()
+        .map(|path| File::open(path).with_context(|| format!("Open split: {}", path.display())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    open_zip_parts(parts)
+}
+
+/// Readers joined into one, like the parts of a split zip. Unlike the `JoinedFile` of
+/// zipunsplitlib, the parts may be any reader, e.g. a span of a setup
+pub struct JoinedReader<R> {
+    /// Parts with their start offset
+    parts: Vec<(u64, R)>,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> JoinedReader<R> {
+    pub fn new(parts: Vec<R>) -> io::Result<Self> {
+        let mut len = 0;
+        let mut joined = Vec::with_capacity(parts.len());
+        for mut part in parts {
+            let part_len = part.seek(SeekFrom::End(0))?;
+            joined.push((len, part));
+            len += part_len;
+        }
+        Ok(Self {
+            parts: joined,
+            len,
+            pos: 0,
+        })
+    }
+
+    /// Ranges of the parts in the joined reader
+    pub fn splits(&self) -> Vec<Range<u64>> {
+        let ends = self.parts.iter().skip(1).map(|(start, _)| *start);
+        self.parts
+            .iter()
+            .map(|(start, _)| *start)
+            .zip(ends.chain([self.len]))
+            .map(|(start, end)| start..end)
+            .collect()
+    }
+}
+
+impl<R: Read + Seek> Read for JoinedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        // Last part starting at or before the position, empty parts are skipped by that
+        let ix = self.parts.partition_point(|(start, _)| *start <= self.pos) - 1;
+        let end = self.parts.get(ix + 1).map_or(self.len, |(start, _)| *start);
+        let n = buf.len().min((end - self.pos) as usize);
+        let (start, part) = &mut self.parts[ix];
+        part.seek(SeekFrom::Start(self.pos - *start))?;
+        let n = part.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for JoinedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_pos(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+fn seek_pos(cur: u64, len: u64, pos: SeekFrom) -> io::Result<u64> {
+    match pos {
+        SeekFrom::Start(pos) => Some(pos),
+        SeekFrom::End(off) => len.checked_add_signed(off),
+        SeekFrom::Current(off) => cur.checked_add_signed(off),
+    }
+    .ok_or_else(|| io::Error::other("Seek before the start"))
+}
+
+/// Reader, which keeps the written pages in memory and leaves the inner reader untouched.
+/// The offsets of split zips are fixed on top of it. It takes the place of the
+/// `MemoryCowFile` of zipunsplitlib, which only wraps its own `JoinedFile`
+pub struct PageOverlay<R> {
+    inner: R,
+    pages: BTreeMap<u64, Vec<u8>>,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> PageOverlay<R> {
+    const PAGE_SIZE: u64 = 4096;
+
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            inner,
+            pages: BTreeMap::new(),
+            len,
+            pos: 0,
+        })
+    }
+
+    fn page_mut(&mut self, page: u64) -> io::Result<&mut Vec<u8>> {
+        if !self.pages.contains_key(&page) {
+            let start = page * Self::PAGE_SIZE;
+            let mut data = vec![0; Self::PAGE_SIZE.min(self.len.saturating_sub(start)) as usize];
+            self.inner.seek(SeekFrom::Start(start))?;
+            self.inner.read_exact(&mut data)?;
+            self.pages.insert(page, data);
+        }
+        Ok(self.pages.get_mut(&page).unwrap())
+    }
+}
+
+impl<R: Read + Seek> Read for PageOverlay<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (page, in_page) = (self.pos / Self::PAGE_SIZE, self.pos % Self::PAGE_SIZE);
+        let end = self.len.min((page + 1) * Self::PAGE_SIZE);
+        let n = buf.len().min(end.saturating_sub(self.pos) as usize);
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let n = match self.pages.get(&page) {
+            Some(data) => {
+                let in_page = in_page as usize;
+                buf[..n].copy_from_slice(&data[in_page..in_page + n]);
+                n
+            }
+            None => {
+                self.inner.seek(SeekFrom::Start(self.pos))?;
+                self.inner.read(&mut buf[..n])?
+            }
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Write for PageOverlay<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (page, in_page) = (self.pos / Self::PAGE_SIZE, self.pos % Self::PAGE_SIZE);
+        let n = buf.len().min((Self::PAGE_SIZE - in_page) as usize);
+        let data = self.page_mut(page)?;
+        let (start, end) = (in_page as usize, in_page as usize + n);
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(&buf[..n]);
+        self.pos += n as u64;
+        self.len = self.len.max(self.pos);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Seek for PageOverlay<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_pos(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Opens the parts of a split zip set as a single archive without copying them, the
+/// parts may be lazy readers of setup entries
+pub fn open_zip_parts<R: Read + Seek>(
+    parts: Vec<R>,
+) -> anyhow::Result<ZipArchive<PageOverlay<JoinedReader<R>>>> {
+    let joined = JoinedReader::new(parts)?;
+    let split_ranges = joined.splits();
+    let mut overlay = PageOverlay::new(joined)?;
+    zipunsplitlib::split::fix_offsets(&mut overlay, &split_ranges).context("Fix offsets")?;
+    overlay.rewind()?;
+
+    Ok(ZipArchive::new(overlay)?)
+}
+
+pub fn extract_zip_split(
+    paths: Vec<PathBuf>,
+    setup_dir: impl AsRef<Path>,
+    filter: &EntryFilter,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> anyhow::Result<()> {
+    let open = || open_zip_split(paths.clone());
+    extract_zip_parallel(open, setup_dir, filter, progress, cancel)
+}
+
+/// Extracted zip file and the metadata, which is restored once all files are written
+type ZipExtracted = (PathBuf, Option<SystemTime>, u64);
+
+/// Indices and sizes of the selected files, directories are created right away.
+/// Skipped entries are never decompressed
+fn select_zip_entries<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    setup_dir: &Path,
+    filter: &EntryFilter,
+) -> anyhow::Result<Vec<(usize, u64)>> {
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if entry.is_dir() {
+            if filter.is_all() {
+                let path = entry
+                    .enclosed_name()
+                    .with_context(|| format!("Invalid file path: {}", entry.name()))?;
+                std::fs::create_dir_all(setup_dir.join(path))?;
+            }
+            continue;
+        }
+        if filter.is_match(entry.name(), entry.size()) {
+            entries.push((i, entry.size()));
+        }
+    }
+    Ok(entries)
+}
+
+fn start_archives(entries: &[(usize, u64)], progress: &dyn Progress) {
+    progress.on_event(ProgressEvent::Start {
+        stage: Stage::Archives,
+        files: Some(entries.len() as u64),
+        bytes: entries.iter().map(|(_, size)| size).sum(),
+    });
+}
+
+/// Removes the files written by a cancelled extraction
+pub fn remove_partial(paths: impl IntoIterator<Item = impl AsRef<Path>>) {
+    for path in paths {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+fn extract_zip_entries<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    entries: &[usize],
+    setup_dir: &Path,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> anyhow::Result<Vec<ZipExtracted>> {
+    let mut extracted = Vec::with_capacity(entries.len());
+    match extract_zip_files(archive, entries, setup_dir, &mut extracted, progress, cancel) {
+        Err(err) if is_cancelled(&err) => {
+            remove_partial(extracted.iter().map(|e| &e.0));
+            Err(err)
+        }
+        res => res.map(|_| extracted),
+    }
+}
+
+fn extract_zip_files<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    entries: &[usize],
+    setup_dir: &Path,
+    extracted: &mut Vec<ZipExtracted>,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> anyhow::Result<()> {
+    for &i in entries {
+        cancel.check()?;
+        let mut file = archive.by_index(i)?;
+        let path = file
+            .enclosed_name()
+            .with_context(|| format!("Invalid file path: {}", file.name()))?;
+        let out_path = setup_dir.join(path);
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        remove_existing(&out_path)?;
+        let mut out = File::create(&out_path)
+            .with_context(|| format!("Failed to create file: {:?}", out_path))?;
+        let mut rdr = ProgressReader::new(CancelReader::new(&mut file, cancel), progress);
+        if let Err(err) = io::copy(&mut rdr, &mut out) {
+            drop(out);
+            let _ = std::fs::remove_file(&out_path);
+            return Err(err.into());
+        }
+        progress.on_event(ProgressEvent::File {
+            name: file.name().to_string(),
+            size: file.size(),
+        });
+        extracted.push((out_path, zip_modified_time(&file), file.central_header_start()));
+    }
+    Ok(())
+}
+
+fn restore_zip_meta<R: Read + Seek>(
+    archive: ZipArchive<R>,
+    extracted: Vec<ZipExtracted>,
+) -> anyhow::Result<()> {
+    // The external attributes are not exposed by the zip crate, so they are read
+    // from the central directory once all files are written
+    let mut rdr = archive.into_inner();
+    for (path, modified, central_header) in extracted {
+        let attributes = read_zip_dos_attributes(&mut rdr, central_header)?;
+        restore_entry_meta(&path, &EntryMeta { modified, attributes })
+            .with_context(|| format!("Restore metadata: {:?}", path))?;
+    }
+
+    Ok(())
+}
+
+/// Extracts the selected files of the archive and restores their metadata, nested
+/// archives like the HShield bundles are left packed
+pub fn extract_zip<R: Read + Seek>(
+    mut archive: ZipArchive<R>,
+    setup_dir: impl AsRef<Path>,
+    filter: &EntryFilter,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> anyhow::Result<()> {
+    let setup_dir = setup_dir.as_ref();
+    let entries = select_zip_entries(&mut archive, setup_dir, filter)?;
+    start_archives(&entries, progress);
+    let entries = entries.into_iter().map(|(i, _)| i).collect::<Vec<_>>();
+    let extracted = extract_zip_entries(&mut archive, &entries, setup_dir, progress, cancel)?;
+    restore_zip_meta(archive, extracted)?;
+    progress.on_event(ProgressEvent::Finish);
+    Ok(())
+}
+
+/// Same as [`extract_zip`], but the files are decompressed by the workers of the rayon
+/// pool. Each worker opens its own archive and streams one file at a time, so the
+/// memory use is bounded by the number of workers
+pub fn extract_zip_parallel<R: Read + Seek>(
+    open: impl Fn() -> anyhow::Result<ZipArchive<R>> + Sync,
+    setup_dir: impl AsRef<Path>,
+    filter: &EntryFilter,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> anyhow::Result<()> {
+    let setup_dir = setup_dir.as_ref();
+    let mut archive = open()?;
+    let entries = select_zip_entries(&mut archive, setup_dir, filter)?;
+    start_archives(&entries, progress);
+    let groups = balance(entries, rayon::current_num_threads());
+    let results = groups
+        .into_par_iter()
+        .map(|group| extract_zip_entries(&mut open()?, &group, setup_dir, progress, cancel))
+        .collect::<Vec<_>>();
+    let mut extracted = Vec::new();
+    let mut error = None;
+    for res in results {
+        match res {
+            Ok(files) => extracted.extend(files),
+            Err(err) => error = error.or(Some(err)),
+        }
+    }
+    if let Some(err) = error {
+        // The groups finished before the cancellation are removed as well
+        if is_cancelled(&err) {
+            remove_partial(extracted.iter().map(|e| &e.0));
+        }
+        return Err(err);
+    }
+    restore_zip_meta(archive, extracted)?;
+    progress.on_event(ProgressEvent::Finish);
+    Ok(())
+}
+
+/// Splits the items into at most `n` groups of about the same total size
+fn balance<T>(mut items: Vec<(T, u64)>, n: usize) -> Vec<Vec<T>> {
+    let n = n.clamp(1, items.len().max(1));
+    let mut groups = (0..n).map(|_| (0, Vec::new())).collect::<Vec<_>>();
+    // Largest first, each item goes to the smallest group
+    items.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    for (item, size) in items {
+        let group = groups.iter_mut().min_by_key(|(total, _)| *total).unwrap();
+        group.0 += size;
+        group.1.push(item);
+    }
+    groups
+        .into_iter()
+        .map(|(_, items)| items)
+        .filter(|items| !items.is_empty())
+        .collect()
+}
+
+/// Removes a previously extracted file, which may be read-only after its metadata
+/// was restored. Windows denies removing it, so the flag is cleared and the removal
+/// retried. It's left alone otherwise, a hard link shares it with its store object
+pub fn remove_existing(path: &Path) -> io::Result<()> {
+    let res = match std::fs::remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => match clear_readonly(path) {
+            Ok(true) => std::fs::remove_file(path),
+            _ => Err(err),
+        },
+        res => res,
+    };
+    match res {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Removes a previously extracted directory, the read-only flags of its files are
+/// cleared if the removal is denied
+pub fn remove_existing_dir(path: &Path) -> io::Result<()> {
+    match std::fs::remove_dir_all(path) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            clear_readonly_all(path)?;
+            std::fs::remove_dir_all(path)
+        }
+        res => res,
+    }
+}
+
+/// Clears the read-only flag of the file, returns whether it was set
+fn clear_readonly(path: &Path) -> io::Result<bool> {
+    let mut perms = std::fs::symlink_metadata(path)?.permissions();
+    if !perms.readonly() {
+        return Ok(false);
+    }
+    // Making it writable for everyone doesn't matter, it's removed right after
+    #[allow(clippy::permissions_set_readonly_false)]
+    perms.set_readonly(false);
+    std::fs::set_permissions(path, perms)?;
+    Ok(true)
+}
+
+fn clear_readonly_all(dir: &Path) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            clear_readonly_all(&entry.path())?;
+        } else {
+            clear_readonly(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Metadata of an archive entry, which is restored onto the extracted file
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EntryMeta {
+    pub modified: Option<SystemTime>,
+    pub attributes: DosAttributes,
+}
+
+/// Sets the modification time and maps the read-only attribute onto the permissions.
+/// Hidden is only restored on Windows, other systems have no equivalent
+pub fn restore_entry_meta(path: &Path, meta: &EntryMeta) -> io::Result<()> {
+    if let Some(modified) = meta.modified {
+        File::options().write(true).open(path)?.set_modified(modified)?;
+    }
+
+    #[cfg(windows)]
+    if meta.attributes.contains(DosAttributes::HIDDEN) {
+        set_hidden(path)?;
+    }
+
+    if !meta.attributes.contains(DosAttributes::READ_ONLY) {
+        return Ok(());
+    }
+    let mut perms = std::fs::metadata(path)?.permissions();
+    perms.set_readonly(true);
+    std::fs::set_permissions(path, perms)
+}
+
+#[cfg(windows)]
+fn set_hidden(path: &Path) -> io::Result<()> {
+    use std::os::windows::{ffi::OsStrExt, fs::MetadataExt};
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetFileAttributesW(path: *const u16, attributes: u32) -> i32;
+    }
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x02;
+
+    let attributes = std::fs::metadata(path)?.file_attributes() | FILE_ATTRIBUTE_HIDDEN;
+    let path = path.as_os_str().encode_wide().chain([0]).collect::<Vec<_>>();
+    // SAFETY: the path is nul terminated and outlives the call
+    if unsafe { SetFileAttributesW(path.as_ptr(), attributes) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Extended NTFS timestamps extra field
+const ZIP_EXTRA_NTFS: u16 = 0x000A;
 
-impl Opener for JoinedOpener {
-    fn open_split(&mut self, index: usize) -> io::Result<File> {
-        File::open(&self.0[index])
+fn zip_ntfs_modified_time(extra: &[u8]) -> Option<SystemTime> {
+    let mut fields = extra;
+    while fields.len() >= 4 {
+        let tag = u16::from_le_bytes([fields[0], fields[1]]);
+        let len = u16::from_le_bytes([fields[2], fields[3]]) as usize;
+        let data = fields.get(4..4 + len)?;
+        fields = &fields[4 + len..];
+        if tag != ZIP_EXTRA_NTFS {
+            continue;
+        }
+
+        // Skip the reserved field, followed by attribute tag, size, mtime
+        let attr = data.get(4..)?;
+        if attr.len() >= 12 && u16::from_le_bytes([attr[0], attr[1]]) == 1 {
+            let mtime = u64::from_le_bytes(attr[4..12].try_into().unwrap());
+            return filetime_to_system_time(mtime);
+        }
+    }
+    None
+}
+
+/// Modification time of the zip entry, preferring the high precision extra fields
+pub fn zip_modified_time(file: &ZipFile) -> Option<SystemTime> {
+    if let Some(t) = file.extra_data().and_then(zip_ntfs_modified_time) {
+        return Some(t);
     }
 
-    fn num_splits(&self) -> usize {
-        self.0.len()
+    let unix_time = file
+        .extra_data_fields()
+        .find_map(|field| match field {
+            ExtraField::ExtendedTimestamp(ts) => ts.mod_time(),
+            _ => None,
+        });
+    if let Some(t) = unix_time {
+        return Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(t as u64));
     }
+
+    let dt = file.last_modified()?;
+    dos_datetime_to_system_time(dt.datepart(), dt.timepart())
+}
+
+/// Reads the DOS attributes of a central directory header, only valid for DOS/NTFS hosts
+fn read_zip_dos_attributes<R: Read + Seek>(
+    mut rdr: R,
+    central_header: u64,
+) -> io::Result<DosAttributes> {
+    const HOST_DOS: u8 = 0;
+    const HOST_NTFS: u8 = 10;
+
+    let mut version_made_by = [0u8; 2];
+    rdr.seek(SeekFrom::Start(central_header + 4))?;
+    rdr.read_exact(&mut version_made_by)?;
+
+    let mut external_attributes = [0u8; 4];
+    rdr.seek(SeekFrom::Start(central_header + 38))?;
+    rdr.read_exact(&mut external_attributes)?;
+
+    Ok(match version_made_by[1] {
+        HOST_DOS | HOST_NTFS => {
+            DosAttributes::from_bits_truncate(u32::from_le_bytes(external_attributes) as u16)
+        }
+        _ => DosAttributes::empty(),
+    })
 }
 
-pub fn extract_zip_split(paths: Vec<PathBuf>, setup_dir: impl AsRef<Path>) -> anyhow::Result<()> {
-    let joined_file = JoinedFile::new(JoinedOpener(paths))?;
-    let split_ranges = joined_file.splits();
-    let mut cow_file = MemoryCowFile::new(joined_file, 4096)?;
-    zipunsplitlib::split::fix_offsets(&mut cow_file, &split_ranges).context("Fix offsets")?;
-    cow_file.rewind()?;
+/// Restores the timestamps and attributes of files extracted from the given cabinets
+fn restore_cab_meta(
+    paths: &[PathBuf],
+    setup_dir: &Path,
+    filter: &EntryFilter,
+) -> anyhow::Result<()> {
+    let set = CabSet::open(paths)?;
+    for file in set.files.iter().map(|f| &f.entry) {
+        if !filter.is_match(&file.path(), file.size()) {
+            continue;
+        }
+        let out_path = setup_dir.join(file.path());
+        if !out_path.is_file() {
+            log::warn!("Missing extracted cab file: {}", out_path.display());
+            continue;
+        }
 
-    let mut archive = zip::ZipArchive::new(cow_file)?;
-    //TODO create HShield directory
-    archive.extract(setup_dir)?;
+        let meta = EntryMeta {
+            modified: file.modified(),
+            attributes: file.attributes(),
+        };
+        restore_entry_meta(&out_path, &meta)
+            .with_context(|| format!("Restore metadata: {:?}", out_path))?;
+    }
 
     Ok(())
 }
@@ -54,22 +665,417 @@ fn z7() -> Command {
     })
 }
 
-pub fn extract_cab_split(paths: Vec<PathBuf>, setup_dir: impl AsRef<Path>) -> anyhow::Result<()> {
-    z7()
-        .args(["x", "-y"])
-        .arg(format!("-o{}", setup_dir.as_ref().to_str().unwrap()))
-        .arg(paths[0].to_str().unwrap())
-        .output()?;
+/// Percentages of a 7z progress output (`-bsp1`), which are split by backspaces
+#[derive(Default)]
+struct Z7Percent {
+    token: Vec<u8>,
+}
 
-    Ok(())
+impl Z7Percent {
+    fn feed(&mut self, data: &[u8], mut on_percent: impl FnMut(u64)) {
+        for &b in data {
+            if b.is_ascii_whitespace() || b == b'\x08' {
+                if let Some(pct) = self.token.strip_suffix(b"%") {
+                    if let Some(pct) = std::str::from_utf8(pct).ok().and_then(|p| p.parse().ok()) {
+                        on_percent(pct);
+                    }
+                }
+                self.token.clear();
+            } else {
+                self.token.push(b);
+            }
+        }
+    }
 }
 
-pub fn extract_msi(path: impl AsRef<Path>, setup_dir: impl AsRef<Path>) -> anyhow::Result<()> {
-    z7()
-        .args(["x", "-y"])
-        .arg(format!("-o{}", setup_dir.as_ref().to_str().unwrap()))
-        .arg(path.as_ref().to_str().unwrap())
-        .output()?;
+/// Writes the names of the files into a list for `-i@`, the names are matched without
+/// wildcards
+fn z7_list_file(files: &[String]) -> anyhow::Result<PathBuf> {
+    let list_file = std::env::temp_dir().join(unique_name("mssetup_cab_list"));
+    let list = files
+        .iter()
+        .map(|p| p.replace('/', std::path::MAIN_SEPARATOR_STR))
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(&list_file, list)?;
+    Ok(list_file)
+}
+
+/// Runs 7z on the cabinet set, only the listed files are decompressed if given
+fn z7_extract_cab(
+    first: &Path,
+    setup_dir: &Path,
+    files: Option<&[String]>,
+    on_percent: impl FnMut(u64),
+    cancel: &CancelToken,
+) -> anyhow::Result<()> {
+    let mut cmd = z7();
+    cmd.args(["x", "-y", "-bsp1", "-bso0"])
+        .arg(format!("-o{}", setup_dir.to_str().unwrap()))
+        .arg(first.to_str().unwrap());
+
+    let list_file = files.map(z7_list_file).transpose()?;
+    if let Some(list_file) = &list_file {
+        cmd.args(["-spd", "-scsUTF-8"])
+            .arg(format!("-i@{}", list_file.to_str().unwrap()));
+    }
+    let mut on_percent = on_percent;
+    let mut percent = Z7Percent::default();
+    let res = cancel.run_with_output(&mut cmd, |data| percent.feed(data, &mut on_percent));
+    if let Some(list_file) = list_file {
+        let _ = std::fs::remove_file(list_file);
+    }
+    res
+}
+
+/// Files of a cabinet set streamed out of 7z one after another in the order of their
+/// data, so a folder is decompressed once. The exit status is checked once the data
+/// ends, the process is killed if the reader is dropped early
+pub struct Z7FileReader {
+    child: Child,
+    stdout: ChildStdout,
+    list_file: PathBuf,
+}
+
+impl Z7FileReader {
+    pub fn open(first: &Path, names: &[String]) -> anyhow::Result<Self> {
+        let list_file = z7_list_file(names)?;
+        let child = z7()
+            .args(["e", "-so", "-spd", "-scsUTF-8"])
+            .arg(format!("-i@{}", list_file.to_str().unwrap()))
+            .arg(first)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("Run 7z");
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                let _ = std::fs::remove_file(&list_file);
+                return Err(err);
+            }
+        };
+        let stdout = child.stdout.take().context("No 7z output")?;
+        Ok(Self {
+            child,
+            stdout,
+            list_file,
+        })
+    }
+}
+
+impl Read for Z7FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("7z failed: {status}")));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Z7FileReader {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.list_file);
+    }
+}
+
+/// Extracts the selected files of the cabinet set. A folder is compressed as one stream,
+/// so the folders are split across the rayon workers, each running its own 7z
+pub fn extract_cab_split(
+    paths: Vec<PathBuf>,
+    setup_dir: impl AsRef<Path>,
+    filter: &EntryFilter,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> anyhow::Result<()> {
+    let setup_dir = setup_dir.as_ref();
+    // A folder spanning cabinets is one folder of the set, so it's decompressed by a single
+    // 7z and each file is written once
+    let mut folders = BTreeMap::<usize, (Vec<(String, u64)>, u64)>::new();
+    for file in CabSet::open(&paths)?.files {
+        let (path, size) = (file.entry.path(), file.entry.size());
+        if !filter.is_match(&path, size) {
+            continue;
+        }
+        // 7z can't overwrite files, which were restored as read-only by a previous run
+        remove_existing(&setup_dir.join(&path))?;
+        let folder = folders.entry(file.folder).or_default();
+        folder.0.push((path, size));
+        folder.1 += size;
+    }
+    if folders.is_empty() {
+        return Ok(());
+    }
+
+    // The bytes follow the percentage of each 7z, the files are reported once it's done
+    let files = folders.values().flat_map(|f| f.0.iter()).cloned().collect::<Vec<_>>();
+    start_archives(&files.iter().map(|f| (0, f.1)).collect::<Vec<_>>(), progress);
+    let extract = |files: &[(String, u64)], names: Option<&[String]>| {
+        let total = files.iter().map(|f| f.1).sum::<u64>();
+        let mut reported = 0;
+        z7_extract_cab(
+            &paths[0],
+            setup_dir,
+            names,
+            |pct| {
+                let done = total * pct.min(100) / 100;
+                if done > reported {
+                    progress.on_event(ProgressEvent::Bytes(done - reported));
+                    reported = done;
+                }
+            },
+            cancel,
+        )?;
+        progress.on_event(ProgressEvent::Bytes(total - reported));
+        for (name, size) in files {
+            progress.on_event(ProgressEvent::File {
+                name: name.clone(),
+                size: *size,
+            });
+        }
+        anyhow::Ok(())
+    };
+
+    let res = if folders.len() == 1 && filter.is_all() {
+        extract(&files, None)
+    } else {
+        let groups = balance(folders.into_values().collect(), rayon::current_num_threads());
+        groups
+            .into_par_iter()
+            .map(|group| {
+                let group = group.concat();
+                let names = group.iter().map(|f| f.0.clone()).collect::<Vec<_>>();
+                extract(&group, Some(&names))
+            })
+            .collect::<anyhow::Result<()>>()
+    };
+    if let Err(err) = res {
+        // A killed 7z leaves its current file behind, the other groups may be done
+        if is_cancelled(&err) {
+            remove_partial(files.iter().map(|f| setup_dir.join(&f.0)));
+        }
+        return Err(err);
+    }
+
+    restore_cab_meta(&paths, setup_dir, filter)?;
+    progress.on_event(ProgressEvent::Finish);
 
     Ok(())
 }
+
+pub fn extract_msi(
+    path: impl AsRef<Path>,
+    setup_dir: impl AsRef<Path>,
+    cancel: &CancelToken,
+) -> anyhow::Result<()> {
+    cancel.run(
+        z7().args(["x", "-y"])
+            .arg(format!("-o{}", setup_dir.as_ref().to_str().unwrap()))
+            .arg(path.as_ref().to_str().unwrap()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::{
+        progress::NoProgress,
+        setup::{EntrySpan, SpanReader},
+        util::get_all_nested_files,
+    };
+
+    use super::*;
+
+    #[test]
+    fn read_only_reextract() {
+        let dir = crate::util::unique_temp_dir("mssetup_read_only_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("client.zip");
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let modified = zip::DateTime::from_date_and_time(2008, 6, 12, 10, 30, 0).unwrap();
+        let opts = zip::write::SimpleFileOptions::default().last_modified_time(modified);
+        zip.start_file("Base.wz", opts).unwrap();
+        zip.write_all(b"base").unwrap();
+        let mut data = zip.finish().unwrap().into_inner();
+        // Mark the entry as created on DOS with the read-only and hidden attributes
+        let central = memchr::memmem::find(&data, b"PK\x01\x02").unwrap();
+        data[central + 5] = 0;
+        data[central + 38..central + 42].copy_from_slice(&0x03u32.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+
+        // The second extraction replaces the read-only file of the first one
+        let out = dir.join("out");
+        let expected = dos_datetime_to_system_time(modified.datepart(), modified.timepart());
+        for _ in 0..2 {
+            let archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+            let all = EntryFilter::default();
+            extract_zip(archive, &out, &all, &NoProgress, &CancelToken::new()).unwrap();
+            let meta = out.join("Base.wz").metadata().unwrap();
+            assert!(meta.permissions().readonly());
+            assert_eq!(Some(meta.modified().unwrap()), expected);
+            #[cfg(windows)]
+            {
+                use std::os::windows::fs::MetadataExt;
+                assert_ne!(meta.file_attributes() & 0x02, 0);
+            }
+        }
+        assert_eq!(std::fs::read(out.join("Base.wz")).unwrap(), b"base");
+
+        // A previous output with read-only files is removed as a whole
+        remove_existing_dir(&out).unwrap();
+        assert!(!out.exists());
+        let archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let all = EntryFilter::default();
+        extract_zip(archive, &out, &all, &NoProgress, &CancelToken::new()).unwrap();
+
+        let base = out.join("Base.wz");
+        assert!(clear_readonly(&base).unwrap());
+        assert!(!base.metadata().unwrap().permissions().readonly());
+        assert!(!clear_readonly(&base).unwrap());
+        remove_existing(&base).unwrap();
+        remove_existing(&base).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_archive_unpack() {
+        let dir = crate::util::unique_temp_dir("mssetup_nested_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("HShield.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        zip.start_file("HShield/v3.dll", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        io::Write::write_all(&mut zip, b"v3").unwrap();
+        zip.finish().unwrap();
+        std::fs::write(dir.join("Base.wz"), b"base").unwrap();
+
+        assert_eq!(
+            NestedArchive::detect(&path).unwrap(),
+            Some(NestedArchive::Zip)
+        );
+        assert_eq!(NestedArchive::detect(&dir.join("Base.wz")).unwrap(), None);
+
+        let archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let cancel = CancelToken::new();
+        let all = EntryFilter::default();
+        extract_zip(archive, dir.join("out"), &all, &NoProgress, &cancel).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("out/HShield/v3.dll")).unwrap(),
+            b"v3"
+        );
+
+        let archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let filter = EntryFilter::new(&["*.wz".to_string()], &[]).unwrap();
+        extract_zip(archive, dir.join("filtered"), &filter, &NoProgress, &cancel).unwrap();
+        assert!(!dir.join("filtered").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parallel_zip() {
+        let dir = crate::util::unique_temp_dir("mssetup_parallel_zip_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("client.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        let opts = zip::write::SimpleFileOptions::default();
+        zip.add_directory("Data/", opts).unwrap();
+        for i in 0..16 {
+            zip.start_file(format!("Data/{i}.wz"), opts).unwrap();
+            io::Write::write_all(&mut zip, &vec![i as u8; i * 100]).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let open = || Ok(ZipArchive::new(File::open(&path)?)?);
+        let bytes = AtomicU64::new(0);
+        let progress = |ev: ProgressEvent| {
+            if let ProgressEvent::Bytes(n) = ev {
+                bytes.fetch_add(n, Ordering::Relaxed);
+            }
+        };
+        let all = EntryFilter::default();
+        let cancel = CancelToken::new();
+        extract_zip_parallel(open, dir.join("out"), &all, &progress, &cancel).unwrap();
+        assert_eq!(bytes.load(Ordering::Relaxed), (0..16).map(|i| i * 100).sum::<u64>());
+        for i in 0..16 {
+            let data = std::fs::read(dir.join(format!("out/Data/{i}.wz"))).unwrap();
+            assert_eq!(data, vec![i as u8; i * 100]);
+        }
+
+        // An extraction cancelled after a few files leaves no partial file
+        let cancel = CancelToken::new();
+        let files = AtomicU64::new(0);
+        let progress = |ev: ProgressEvent| {
+            if let ProgressEvent::File { .. } = ev {
+                if files.fetch_add(1, Ordering::Relaxed) == 4 {
+                    cancel.cancel();
+                }
+            }
+        };
+        let open = || Ok(ZipArchive::new(File::open(&path)?)?);
+        let err = extract_zip_parallel(open, dir.join("cancel"), &all, &progress, &cancel);
+        assert!(files.load(Ordering::Relaxed) >= 5);
+        assert!(crate::cancel::is_cancelled(&err.unwrap_err()));
+        assert!(get_all_nested_files(dir.join("cancel")).unwrap().is_empty());
+
+        let groups = balance(vec![("a", 10), ("b", 6), ("c", 5), ("d", 1)], 2);
+        assert_eq!(groups, [vec!["a", "d"], vec!["b", "c"]]);
+        assert_eq!(balance::<&str>(vec![], 4).len(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn z7_percent() {
+        let mut percent = Z7Percent::default();
+        let mut pcts = Vec::new();
+        percent.feed(b"  0%\x08\x08\x08\x08 4", |p| pcts.push(p));
+        percent.feed(b"2% 3 - Data\x08\x08\x08100%\r\n", |p| pcts.push(p));
+        assert_eq!(pcts, [0, 42, 100]);
+    }
+
+    #[test]
+    fn zip_parts() {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file("Data/Skill.wz", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"skill").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        // The zip is read in place from the middle of a setup
+        let mut setup = vec![0xAA; 100];
+        setup.extend(&data);
+        setup.extend([0xBB; 50]);
+        let span = EntrySpan {
+            offset: 100,
+            len: data.len() as u64,
+            key: None,
+        };
+        let part = SpanReader::new(io::Cursor::new(setup), span);
+        let mut archive = open_zip_parts(vec![part]).unwrap();
+        let mut content = String::new();
+        archive
+            .by_name("Data/Skill.wz")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "skill");
+
+        let parts = ["abc", "", "def"].map(|p| io::Cursor::new(p.as_bytes().to_vec()));
+        let mut joined = JoinedReader::new(parts.into()).unwrap();
+        assert_eq!(joined.splits(), [0..3, 3..3, 3..6]);
+        let mut overlay = PageOverlay::new(&mut joined).unwrap();
+        overlay.seek(SeekFrom::Start(2)).unwrap();
+        overlay.write_all(b"XY").unwrap();
+        overlay.rewind().unwrap();
+        let mut all = String::new();
+        overlay.read_to_string(&mut all).unwrap();
+        assert_eq!(all, "abXYef");
+    }
+}
@@ -0,0 +1,235 @@
+use std::path::Path;
+
+use anyhow::Context;
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+
+/// Pattern on the relative path of an entry, all patterns are case-insensitive like the
+/// Windows paths they match
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// Glob, a glob without `/` matches the file name in any directory
+    Glob(GlobMatcher),
+    Regex(Regex),
+    /// Everything below the directory
+    Dir(String),
+    /// Prefix of the file name
+    Prefix(String),
+    /// Extension without the dot
+    Ext(String),
+}
+
+impl Pattern {
+    /// Parses `kind:pattern`, where kind is `glob`, `regex`, `dir`, `prefix` or `ext`.
+    /// Patterns without a kind are globs
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        const KINDS: [&str; 5] = ["glob", "regex", "dir", "prefix", "ext"];
+        let (kind, pattern) = match s.split_once(':') {
+            Some((kind, pattern)) if KINDS.contains(&kind) => (kind, pattern),
+            _ => ("glob", s),
+        };
+
+        Ok(match kind {
+            "regex" => Self::Regex(RegexBuilder::new(pattern).case_insensitive(true).build()?),
+            "dir" => {
+                let dir = pattern.replace('\\', "/");
+                Self::Dir(format!("{}/", dir.trim_matches('/').to_lowercase()))
+            }
+            "prefix" => Self::Prefix(pattern.to_lowercase()),
+            "ext" => Self::Ext(pattern.trim_start_matches('.').to_lowercase()),
+            _ => {
+                let glob = pattern.replace('\\', "/");
+                let glob = if glob.contains('/') {
+                    glob
+                } else {
+                    format!("**/{glob}")
+                };
+                let glob = GlobBuilder::new(&glob)
+                    .case_insensitive(true)
+                    .literal_separator(true)
+                    .build()?;
+                Self::Glob(glob.compile_matcher())
+            }
+        })
+    }
+
+    /// Checks a relative path with `/` as separator
+    pub fn is_match(&self, path: &str) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path).to_lowercase();
+        match self {
+            Self::Glob(glob) => glob.is_match(path),
+            Self::Regex(re) => re.is_match(path),
+            Self::Dir(dir) => path.to_lowercase().starts_with(dir.as_str()),
+            Self::Prefix(prefix) => name.starts_with(prefix.as_str()),
+            Self::Ext(ext) => name.rsplit_once('.').is_some_and(|(_, e)| e == ext),
+        }
+    }
+}
+
+/// Parses sizes like `1500`, `64K`, `512MB` or `2G`, the units are decimal like the
+/// sizes in the reports
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let upper = s.trim().to_ascii_uppercase();
+    let num = upper.strip_suffix('B').unwrap_or(&upper);
+    let (num, unit) = match num.chars().last() {
+        Some('K') => (&num[..num.len() - 1], 1_000),
+        Some('M') => (&num[..num.len() - 1], 1_000_000),
+        Some('G') => (&num[..num.len() - 1], 1_000_000_000),
+        _ => (num, 1),
+    };
+    let num: u64 = num
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid size: {s}"))?;
+    num.checked_mul(unit)
+        .with_context(|| format!("Size too large: {s}"))
+}
+
+/// Selects the archive entries to extract by their relative path and size, entries
+/// which are not selected are skipped before any of their data is decompressed
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    /// An entry must match one of them, if there are any
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+impl EntryFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> anyhow::Result<Self> {
+        let mut filter = Self::default();
+        for pattern in include {
+            filter.include(Pattern::parse(pattern)?);
+        }
+        for pattern in exclude {
+            filter.exclude(Pattern::parse(pattern)?);
+        }
+        Ok(filter)
+    }
+
+    pub fn include(&mut self, pattern: Pattern) {
+        self.include.push(pattern);
+    }
+
+    pub fn exclude(&mut self, pattern: Pattern) {
+        self.exclude.push(pattern);
+    }
+
+    pub fn set_min_size(&mut self, size: u64) {
+        self.min_size = Some(size);
+    }
+
+    pub fn set_max_size(&mut self, size: u64) {
+        self.max_size = Some(size);
+    }
+
+    /// Adds rules from text with one rule per line:
+    ///
+    /// ```text
+    /// # Comment
+    /// include *.wz
+    /// exclude dir:HShield
+    /// exclude regex:^Data/.*\.img$
+    /// max-size 512M
+    /// ```
+    pub fn add_rules(&mut self, rules: &str) -> anyhow::Result<()> {
+        for (ix, line) in rules.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (rule, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim();
+            match rule {
+                "include" => self.include(Pattern::parse(value)?),
+                "exclude" => self.exclude(Pattern::parse(value)?),
+                "min-size" => self.set_min_size(parse_size(value)?),
+                "max-size" => self.set_max_size(parse_size(value)?),
+                _ => anyhow::bail!("Invalid rule in line {}: {line}", ix + 1),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_rules_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let rules = std::fs::read_to_string(path)
+            .with_context(|| format!("Read rules: {}", path.display()))?;
+        self.add_rules(&rules)
+            .with_context(|| format!("Rules: {}", path.display()))
+    }
+
+    /// Whether the filter passes every entry
+    pub fn is_all(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+    }
+
+    /// Checks the relative path and uncompressed size of an entry, `\` is accepted
+    /// as separator
+    pub fn is_match(&self, path: &str, size: u64) -> bool {
+        let path = path.replace('\\', "/");
+        let included = self.include.is_empty() || self.include.iter().any(|p| p.is_match(&path));
+        included
+            && !self.exclude.iter().any(|p| p.is_match(&path))
+            && self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn include_exclude() {
+        let all = EntryFilter::default();
+        assert!(all.is_all());
+        assert!(all.is_match("Data/Skill.wz", 0));
+
+        let filter = EntryFilter::new(
+            &["*.wz".to_string(), "MapleStory.exe".to_string()],
+            &["Data/*".to_string()],
+        )
+        .unwrap();
+        assert!(filter.is_match("Skill.wz", 0));
+        assert!(filter.is_match("skill.WZ", 0));
+        assert!(filter.is_match("Sub\\Base.wz", 0));
+        assert!(filter.is_match("maplestory.exe", 0));
+        assert!(!filter.is_match("Data/Skill.wz", 0));
+        assert!(!filter.is_match("HShield/v3.dll", 0));
+
+        let filter = EntryFilter::new(&[], &["HShield/**".to_string()]).unwrap();
+        assert!(filter.is_match("Skill.wz", 0));
+        assert!(!filter.is_match("HShield/v3/v3.dll", 0));
+    }
+
+    #[test]
+    fn rules() {
+        let mut filter = EntryFilter::default();
+        filter
+            .add_rules(
+                "# Shared rules\n\
+                 exclude dir:HShield\n\
+                 exclude regex:^Data/.*\\.img$\n\
+                 exclude prefix:Patcher\n\
+                 exclude ext:.log\n\
+                 max-size 1K\n",
+            )
+            .unwrap();
+        assert!(filter.is_match("Skill.wz", 1000));
+        assert!(!filter.is_match("Skill.wz", 1001));
+        assert!(!filter.is_match("hshield/v3.dll", 0));
+        assert!(filter.is_match("HShieldUpdate.exe", 0));
+        assert!(!filter.is_match("Data/Map/0.img", 0));
+        assert!(!filter.is_match("Patcher.exe", 0));
+        assert!(!filter.is_match("Setup.LOG", 0));
+
+        assert_eq!(parse_size("512MB").unwrap(), 512_000_000);
+        assert!(parse_size("20000000000G").is_err());
+        assert!(filter.add_rules("remove *.wz").is_err());
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    fs::File,
+    io::{Cursor, Read, Seek},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use zip::ZipArchive;
+
+use crate::{
+    pe::{PeFile, VersionInfo},
+    util::get_all_nested_files,
+};
+
+/// Highest client version which is tried when decoding the WZ version hash
+pub const MAX_WZ_VERSION: u16 = 1000;
+/// Bytes read from the start of a WZ file, enough for the header and version hash
+const WZ_HEADER_READ_LEN: u64 = 1024;
+
+/// Access to the files of a client, either on disk or inside an archive
+pub trait ClientFiles {
+    /// Relative paths of all files with `/` as separator
+    fn paths(&mut self) -> anyhow::Result<Vec<String>>;
+    /// Reads up to `limit` bytes from the start of the file
+    fn read(&mut self, path: &str, limit: u64) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Client files in an extracted directory
+pub struct DirFiles(pub PathBuf);
+
+impl ClientFiles for DirFiles {
+    fn paths(&mut self) -> anyhow::Result<Vec<String>> {
+        Ok(get_all_nested_files(&self.0)?
+            .iter()
+            .filter_map(|p| p.strip_prefix(&self.0).ok())
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .collect())
+    }
+
+    fn read(&mut self, path: &str, limit: u64) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        File::open(self.0.join(path))?
+            .take(limit)
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl<R: Read + Seek> ClientFiles for ZipArchive<R> {
+    fn paths(&mut self) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .file_names()
+            .filter(|name| !name.ends_with('/'))
+            .map(|name| name.replace('\\', "/"))
+            .collect())
+    }
+
+    fn read(&mut self, path: &str, limit: u64) -> anyhow::Result<Vec<u8>> {
+        // Entries may be stored with `\` as separator, so the raw name is looked up
+        let name = self
+            .file_names()
+            .find(|name| name.replace('\\', "/") == path)
+            .unwrap_or(path)
+            .to_string();
+        let mut data = Vec::new();
+        self.by_name(&name)?.take(limit).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// Calculates the encrypted version, which is stored in the WZ header
+pub fn wz_version_hash(version: u16) -> u16 {
+    let hash = version
+        .to_string()
+        .bytes()
+        .fold(0u32, |h, c| h.wrapping_mul(32).wrapping_add(c as u32 + 1));
+    let b = hash.to_le_bytes();
+    (0xFF ^ b[0] ^ b[1] ^ b[2] ^ b[3]) as u16
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WzHeader {
+    pub file_size: u64,
+    pub data_start: u32,
+    pub copyright: String,
+    pub encrypted_version: Option<u16>,
+    /// All versions matching the encrypted version
+    pub version_candidates: Vec<u16>,
+}
+
+impl WzHeader {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if !data.starts_with(b"PKG1") {
+            anyhow::bail!("Invalid WZ magic");
+        }
+        let file_size =
+            u64::from_le_bytes(data.get(4..12).context("Truncated WZ header")?.try_into()?);
+        let data_start = u32::from_le_bytes(
+            data.get(12..16)
+                .context("Truncated WZ header")?
+                .try_into()?,
+        );
+
+        let copyright = data
+            .get(16..(data_start as usize).min(data.len()))
+            .unwrap_or_default();
+        let len = copyright
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(copyright.len());
+        let copyright = String::from_utf8_lossy(&copyright[..len]).into_owned();
+
+        let encrypted_version = data
+            .get(data_start as usize..data_start as usize + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let version_candidates = encrypted_version
+            .map(|enc| {
+                (1..=MAX_WZ_VERSION)
+                    .filter(|&v| wz_version_hash(v) == enc)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            file_size,
+            data_start,
+            copyright,
+            encrypted_version,
+            version_candidates,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ClientVersion {
+    pub major: u16,
+    pub minor: Option<u16>,
+    pub build: Option<u16>,
+}
+
+impl std::fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let part = |v: Option<u16>| v.map_or("?".to_string(), |v| v.to_string());
+        write!(f, "{}.{}.{}", self.major, part(self.minor), part(self.build))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Fingerprint {
+    pub executable: Option<String>,
+    pub exe_version: Option<VersionInfo>,
+    pub wz_file: Option<String>,
+    pub wz_header: Option<WzHeader>,
+    pub region: Option<String>,
+    pub version: Option<ClientVersion>,
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn find_main_exe(paths: &[String]) -> Option<&String> {
+    let root_exes = paths
+        .iter()
+        .filter(|p| !p.contains('/') && p.to_ascii_lowercase().ends_with(".exe"));
+    root_exes
+        .clone()
+        .find(|p| p.eq_ignore_ascii_case("maplestory.exe"))
+        .or_else(|| {
+            root_exes
+                .clone()
+                .find(|p| p.to_ascii_lowercase().contains("maple"))
+        })
+}
+
+fn find_wz(paths: &[String]) -> Option<&String> {
+    let wz = || {
+        paths
+            .iter()
+            .filter(|p| p.to_ascii_lowercase().ends_with(".wz"))
+    };
+    let root_wz = || wz().filter(|p| !p.contains('/'));
+    ["base.wz", "data.wz"]
+        .iter()
+        .find_map(|name| root_wz().find(|p| p.eq_ignore_ascii_case(name)))
+        .or_else(|| wz().min_by_key(|p| (p.matches('/').count(), p.to_string())))
+}
+
+/// Guesses the region from the version resource, the language alone can't tell apart
+/// the english speaking regions so the company strings are checked first
+fn detect_region(info: &VersionInfo) -> Option<&'static str> {
+    let strings = info
+        .strings
+        .values()
+        .map(|s| s.to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if strings.contains("asiasoft") {
+        return Some("MSEA");
+    }
+    if strings.contains("nexon europe") {
+        return Some("EMS");
+    }
+
+    match info.language {
+        0x0412 => Some("KMS"),
+        0x0411 => Some("JMS"),
+        0x0804 => Some("CMS"),
+        0x0404 => Some("TMS"),
+        0x041E => Some("ThMS"),
+        0x0409 => Some("GMS"),
+        _ => None,
+    }
+}
+
+fn detect_version(exe: Option<&VersionInfo>, wz: Option<&WzHeader>) -> Option<ClientVersion> {
+    let candidates = wz
+        .map(|wz| wz.version_candidates.as_slice())
+        .unwrap_or_default();
+    if let Some(exe) = exe {
+        let v = exe.file_version;
+        // The major version is the component which matches the WZ hash, the position of
+        // the major version in the file version differs between the regions
+        if let Some(ix) = v.iter().position(|c| *c != 0 && candidates.contains(c)) {
+            return Some(ClientVersion {
+                major: v[ix],
+                minor: v.get(ix + 1).copied(),
+                build: v.get(ix + 2).copied(),
+            });
+        }
+    }
+
+    match candidates {
+        [major] => Some(ClientVersion {
+            major: *major,
+            minor: None,
+            build: None,
+        }),
+        _ => None,
+    }
+}
+
+impl Fingerprint {
+    pub fn from_files(files: &mut impl ClientFiles) -> anyhow::Result<Self> {
+        let paths = files.paths()?;
+        let mut fp = Self::default();
+
+        if let Some(exe) = find_main_exe(&paths) {
+            let data = files.read(exe, u64::MAX)?;
+            let pe = PeFile::parse(Cursor::new(&data)).context("Parse executable")?;
+            fp.exe_version = pe.version_info(Cursor::new(&data))?;
+            fp.executable = Some(file_name(exe).to_string());
+        }
+
+        if let Some(wz) = find_wz(&paths) {
+            let data = files.read(wz, WZ_HEADER_READ_LEN)?;
+            fp.wz_header = Some(WzHeader::parse(&data).with_context(|| format!("Parse {wz}"))?);
+            fp.wz_file = Some(wz.clone());
+        }
+
+        fp.region = fp
+            .exe_version
+            .as_ref()
+            .and_then(detect_region)
+            .map(str::to_string);
+        fp.version = detect_version(fp.exe_version.as_ref(), fp.wz_header.as_ref());
+        Ok(fp)
+    }
+
+    pub fn from_dir(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_files(&mut DirFiles(dir.as_ref().to_path_buf()))
+    }
+
+    pub fn log(&self) {
+        log::info!("Region: {}", self.region.as_deref().unwrap_or("Unknown"));
+        match self.version {
+            Some(v) => log::info!("Version: {v}"),
+            None => log::info!("Version: Unknown"),
+        }
+        if let (Some(exe), Some(info)) = (&self.executable, &self.exe_version) {
+            let v = info.file_version;
+            log::info!(
+                "{exe}: {}.{}.{}.{} (lang: {:#06x})",
+                v[0],
+                v[1],
+                v[2],
+                v[3],
+                info.language
+            );
+            for (key, value) in info.strings.iter() {
+                log::info!("\t{key}: {value}");
+            }
+        }
+        if let (Some(wz), Some(hdr)) = (&self.wz_file, &self.wz_header) {
+            log::info!(
+                "{wz}: {} - candidates: {:?}",
+                hdr.copyright,
+                hdr.version_candidates
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wz_header() {
+        let mut data = b"PKG1".to_vec();
+        data.extend(1234u64.to_le_bytes());
+        data.extend(60u32.to_le_bytes());
+        data.extend(b"Package file v1.0 Copyright 2002 Wizet, ZMS\0");
+        data.resize(60, 0);
+        data.extend(wz_version_hash(83).to_le_bytes());
+
+        let hdr = WzHeader::parse(&data).unwrap();
+        assert_eq!(hdr.copyright, "Package file v1.0 Copyright 2002 Wizet, ZMS");
+        assert!(hdr.version_candidates.contains(&83));
+
+        let version = detect_version(
+            None,
+            Some(&WzHeader {
+                version_candidates: vec![83],
+                ..hdr
+            }),
+        )
+        .unwrap();
+        assert_eq!(version.major, 83);
+
+        // Zip entries stored with `\\` are read by their normalised path
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("Data\\Base.wz", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut zip, &data).unwrap();
+        let mut archive = ZipArchive::new(zip.finish().unwrap()).unwrap();
+        assert_eq!(archive.paths().unwrap(), ["Data/Base.wz"]);
+        assert_eq!(archive.read("Data/Base.wz", 4).unwrap(), b"PKG1");
+    }
+
+    #[test]
+    fn version_and_wz() {
+        let exe = |file_version| VersionInfo {
+            file_version,
+            product_version: file_version,
+            language: 0x0409,
+            strings: Default::default(),
+        };
+        let wz = |version_candidates| WzHeader {
+            file_size: 0,
+            data_start: 0,
+            copyright: String::new(),
+            encrypted_version: None,
+            version_candidates,
+        };
+
+        let version = detect_version(Some(&exe([1, 83, 1, 0])), Some(&wz(vec![83]))).unwrap();
+        assert_eq!((version.major, version.minor), (83, Some(1)));
+        // Without a matching WZ hash the file version doesn't tell the major version
+        assert!(detect_version(Some(&exe([1, 2, 3, 4])), None).is_none());
+        let version = detect_version(Some(&exe([1, 2, 3, 4])), Some(&wz(vec![62]))).unwrap();
+        assert_eq!((version.major, version.minor), (62, None));
+        assert!(detect_version(Some(&exe([1, 2, 3, 4])), Some(&wz(vec![62, 95]))).is_none());
+
+        let paths = |paths: &[&str]| paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let client = paths(&["Data/Base.wz", "Character.wz", "Base.wz"]);
+        assert_eq!(find_wz(&client).unwrap(), "Base.wz");
+        // A nested Base.wz isn't preferred over the root files
+        let client = paths(&["Data/Base.wz", "Character.wz"]);
+        assert_eq!(find_wz(&client).unwrap(), "Character.wz");
+        let client = paths(&["Data/Base/Base_000.wz", "Data/Base.wz"]);
+        assert_eq!(find_wz(&client).unwrap(), "Data/Base.wz");
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::Write,
+};
+
+use serde::Serialize;
+
+use crate::patch::patch_versions_from_name;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphFormat {
+    Json,
+    Dot,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PatchEdge {
+    pub from: u32,
+    pub to: u32,
+    pub path: String,
+    /// Version from the patch header, the client version the patch leads to
+    pub header_version: i32,
+}
+
+impl PatchEdge {
+    /// Whether the header agrees with the target version from the name
+    pub fn header_matches(&self) -> bool {
+        i64::from(self.header_version) == i64::from(self.to)
+    }
+}
+
+/// Versions of the full setups and the patches between them
+#[derive(Debug, Default, Serialize)]
+pub struct VersionGraph {
+    /// Setup paths by their client version
+    pub setups: BTreeMap<u32, Vec<String>>,
+    pub patches: Vec<PatchEdge>,
+    /// Files, which versions couldn't be determined
+    pub unresolved: Vec<String>,
+}
+
+/// Reachability of the versions and the missing patches
+#[derive(Debug, Serialize)]
+pub struct GraphReport {
+    /// Versions, which can be built from the setup of the version
+    pub reachable: BTreeMap<u32, Vec<u32>>,
+    /// Versions only known from patches, which no setup leads to
+    pub unreachable: Vec<u32>,
+    /// Consecutive known versions without a patch chain between them
+    pub gaps: Vec<(u32, u32)>,
+    /// Patches, whose header version differs from the target in their name
+    pub mismatched: Vec<String>,
+}
+
+/// Parses the client version from setup names like `GMSSetupv95.exe`
+pub fn version_from_setup_name(name: &str) -> Option<u32> {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    let lower = stem.to_ascii_lowercase();
+    lower.match_indices('v').rev().find_map(|(ix, _)| {
+        let digits = lower[ix + 1..]
+            .split(|c: char| !c.is_ascii_digit())
+            .next()
+            .unwrap_or_default();
+        digits.parse().ok()
+    })
+}
+
+/// Source and target version of a patch. Names like `00083to00084.patch` carry both, a
+/// name with only the source version like `00083.patch` takes the target from the header
+pub fn patch_versions(name: &str, header_version: i32) -> Option<(u32, u32)> {
+    if let Some(versions) = patch_versions_from_name(name) {
+        return Some(versions);
+    }
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    let from = stem.parse().ok()?;
+    let to = u32::try_from(header_version).ok().filter(|&to| to > from)?;
+    Some((from, to))
+}
+
+impl VersionGraph {
+    pub fn add_setup(&mut self, version: u32, path: String) {
+        self.setups.entry(version).or_default().push(path);
+    }
+
+    pub fn add_patch(&mut self, patch: PatchEdge) {
+        self.patches.push(patch);
+    }
+
+    pub fn versions(&self) -> BTreeSet<u32> {
+        self.setups
+            .keys()
+            .copied()
+            .chain(self.patches.iter().flat_map(|p| [p.from, p.to]))
+            .collect()
+    }
+
+    /// All versions, which can be reached from `start` by applying patches
+    pub fn reachable_from(&self, start: u32) -> BTreeSet<u32> {
+        let mut seen = BTreeSet::from([start]);
+        let mut q = VecDeque::from([start]);
+        while let Some(v) = q.pop_front() {
+            for patch in self.patches.iter().filter(|p| p.from == v) {
+                if seen.insert(patch.to) {
+                    q.push_back(patch.to);
+                }
+            }
+        }
+        seen
+    }
+
+    pub fn report(&self) -> GraphReport {
+        let reachable = self
+            .setups
+            .keys()
+            .map(|&v| (v, self.reachable_from(v).into_iter().collect::<Vec<_>>()))
+            .collect::<BTreeMap<_, _>>();
+        let obtainable = reachable
+            .values()
+            .flatten()
+            .copied()
+            .collect::<BTreeSet<_>>();
+
+        let versions = self.versions().into_iter().collect::<Vec<_>>();
+        let unreachable = versions
+            .iter()
+            .copied()
+            .filter(|v| !obtainable.contains(v))
+            .collect();
+        let gaps = versions
+            .windows(2)
+            .filter(|w| !self.reachable_from(w[0]).contains(&w[1]))
+            .map(|w| (w[0], w[1]))
+            .collect();
+
+        let mismatched = self
+            .patches
+            .iter()
+            .filter(|p| !p.header_matches())
+            .map(|p| p.path.clone())
+            .collect();
+
+        GraphReport {
+            reachable,
+            unreachable,
+            gaps,
+            mismatched,
+        }
+    }
+
+    /// Graphviz graph, setups are boxes and versions without a setup or path from one are red
+    pub fn to_dot(&self) -> String {
+        let report = self.report();
+        let mut dot = String::from("digraph versions {\n    rankdir=LR;\n");
+        for v in self.versions() {
+            let shape = if self.setups.contains_key(&v) {
+                "box"
+            } else {
+                "ellipse"
+            };
+            let color = if report.unreachable.contains(&v) {
+                "red"
+            } else {
+                "black"
+            };
+            let _ = writeln!(
+                dot,
+                "    v{v} [label=\"v{v}\", shape={shape}, color={color}];"
+            );
+        }
+        for patch in self.patches.iter() {
+            if patch.header_matches() {
+                let _ = writeln!(dot, "    v{} -> v{};", patch.from, patch.to);
+            } else {
+                let _ = writeln!(
+                    dot,
+                    "    v{} -> v{} [color=orange, label=\"header v{}\"];",
+                    patch.from, patch.to, patch.header_version
+                );
+            }
+        }
+        for (from, to) in report.gaps.iter() {
+            let _ = writeln!(
+                dot,
+                "    v{from} -> v{to} [style=dashed, color=red, label=\"missing\"];"
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn log_report(&self) {
+        let report = self.report();
+        for (setup, versions) in report.reachable.iter() {
+            log::info!("Setup v{setup} reaches: {versions:?}");
+        }
+        if !report.unreachable.is_empty() {
+            log::warn!("Unreachable versions: {:?}", report.unreachable);
+        }
+        for (from, to) in report.gaps.iter() {
+            log::warn!("Gap: v{from} -> v{to}");
+        }
+        for patch in self.patches.iter().filter(|p| !p.header_matches()) {
+            log::warn!(
+                "Header version {} doesn't match v{}: {}",
+                patch.header_version,
+                patch.to,
+                patch.path
+            );
+        }
+        for path in self.unresolved.iter() {
+            log::warn!("Unknown version: {path}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_and_reachability() {
+        assert_eq!(version_from_setup_name("GMSSetupv95.exe"), Some(95));
+        assert_eq!(version_from_setup_name("MapleStoryV62_setup.exe"), Some(62));
+        assert_eq!(version_from_setup_name("Setup.exe"), None);
+        assert_eq!(patch_versions("00083to00084.patch", 84), Some((83, 84)));
+        assert_eq!(patch_versions("00083.patch", 84), Some((83, 84)));
+        assert_eq!(patch_versions("00083.patch", 2), None);
+
+        let mut graph = VersionGraph::default();
+        graph.add_setup(83, "GMSSetupv83.exe".to_string());
+        for (from, to) in [(83, 84), (84, 85), (86, 87)] {
+            graph.add_patch(PatchEdge {
+                from,
+                to,
+                path: format!("{from:05}to{to:05}.patch"),
+                header_version: if from == 86 { 88 } else { to as i32 },
+            });
+        }
+
+        let report = graph.report();
+        assert_eq!(report.reachable[&83], [83, 84, 85]);
+        assert_eq!(report.unreachable, [86, 87]);
+        assert_eq!(report.gaps, [(85, 86)]);
+        assert_eq!(report.mismatched, ["00086to00087.patch"]);
+        assert!(graph.to_dot().contains("v85 -> v86 [style=dashed"));
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+/// State file of `extract-all`, which is kept in the output directory
+pub const JOB_STATE_FILE: &str = "extract-state.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// The extraction was started, but never finished, the output may be partial
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobEntry {
+    /// Setup file the output was extracted from
+    pub setup: String,
+    pub sha256: String,
+    pub size: u64,
+    /// Options affecting the output, like the entry filters
+    #[serde(default)]
+    pub options: String,
+    pub status: JobStatus,
+    pub error: Option<String>,
+    pub updated: String,
+}
+
+/// Outcome of every setup processed by `extract-all`, keyed by the output directory name.
+/// The file is rewritten after each change, so a crash loses at most the running setups
+#[derive(Debug)]
+pub struct JobState {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, JobEntry>>,
+}
+
+impl JobState {
+    /// Loads the state of `out_dir`, a missing state file is an empty state
+    pub fn load(out_dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = out_dir.as_ref().join(JOB_STATE_FILE);
+        let entries = if path.is_file() {
+            serde_json::from_reader(BufReader::new(File::open(&path)?))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<JobEntry> {
+        self.entries.lock().unwrap().get(name).cloned()
+    }
+
+    /// A setup is complete, if the same file was extracted successfully with the same
+    /// options and its output still holds the `marker` file, which is written last
+    pub fn is_complete(&self, name: &str, sha256: &str, options: &str, marker: &Path) -> bool {
+        self.get(name).is_some_and(|entry| {
+            entry.status == JobStatus::Done
+                && entry.sha256 == sha256
+                && entry.options == options
+                && marker.is_file()
+        })
+    }
+
+    pub fn begin(
+        &self,
+        name: &str,
+        setup: &Path,
+        sha256: &str,
+        size: u64,
+        options: &str,
+    ) -> anyhow::Result<()> {
+        self.update(
+            name,
+            JobEntry {
+                setup: setup.display().to_string(),
+                sha256: sha256.to_string(),
+                size,
+                options: options.to_string(),
+                status: JobStatus::Running,
+                error: None,
+                updated: String::new(),
+            },
+        )
+    }
+
+    pub fn finish(&self, name: &str, result: &anyhow::Result<()>) -> anyhow::Result<()> {
+        let mut entry = self.get(name).ok_or_else(|| anyhow::anyhow!("Unknown job: {name}"))?;
+        match result {
+            Ok(()) => entry.status = JobStatus::Done,
+            Err(err) => {
+                entry.status = JobStatus::Failed;
+                entry.error = Some(format!("{err:#}"));
+            }
+        }
+        self.update(name, entry)
+    }
+
+    fn update(&self, name: &str, mut entry: JobEntry) -> anyhow::Result<()> {
+        entry.updated = Utc::now().to_rfc3339();
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(name.to_string(), entry);
+
+        // Replace the file at once, so a crash never leaves a truncated state behind
+        let tmp = self.path.with_extension("json.tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut file, &*entries)?;
+        file.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{manifest::MANIFEST_FILE, util::unique_temp_dir};
+
+    use super::*;
+
+    #[test]
+    fn resume_state() {
+        let dir = unique_temp_dir("mssetup_job_test");
+        std::fs::create_dir_all(dir.join("v83")).unwrap();
+        let marker = dir.join("v83").join(MANIFEST_FILE);
+
+        let state = JobState::load(&dir).unwrap();
+        state.begin("v83", Path::new("v83.exe"), "aa", 1, "").unwrap();
+        state.finish("v83", &Err(anyhow::anyhow!("broken"))).unwrap();
+        state.begin("v83", Path::new("v83.exe"), "aa", 1, "").unwrap();
+
+        // A crash after the start leaves the setup running
+        let state = JobState::load(&dir).unwrap();
+        assert_eq!(state.get("v83").unwrap().status, JobStatus::Running);
+        assert!(!state.is_complete("v83", "aa", "", &marker));
+
+        state.finish("v83", &Ok(())).unwrap();
+        std::fs::write(&marker, b"{}").unwrap();
+        let state = JobState::load(&dir).unwrap();
+        assert!(state.get("v83").unwrap().error.is_none());
+        assert!(state.is_complete("v83", "aa", "", &marker));
+        // A changed setup or different options extract the setup again
+        assert!(!state.is_complete("v83", "bb", "", &marker));
+        assert!(!state.is_complete("v83", "aa", "--include *.wz", &marker));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,682 @@
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod analyze;
+pub mod authenticode;
+pub mod cab;
+pub mod cancel;
+pub mod catalog;
+pub mod convert;
+pub mod diff;
+pub mod extract;
+pub mod filter;
+pub mod fingerprint;
+pub mod graph;
+pub mod job;
+pub mod manifest;
+#[cfg(feature = "fuse")]
+pub mod mount;
+pub mod pack;
+pub mod pe;
+pub mod serve;
+pub mod setup;
+pub mod store;
+pub mod util;
+pub mod patch;
+pub mod patched;
+pub mod progress;
+pub mod rebuild;
+pub mod patcher;
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use authenticode::Signature;
+use cancel::{is_cancelled, CancelReader, CancelToken, Cancelled};
+use chrono::{DateTime, Utc};
+use extract::{
+    extract_cab_split, extract_zip, extract_zip_split, open_zip_split, remove_existing,
+    remove_existing_dir, NestedArchive,
+};
+use filter::EntryFilter;
+use fingerprint::Fingerprint;
+use humansize::{SizeFormatter, DECIMAL};
+use manifest::{ExtractManifest, REPORT_FILE};
+use pe::StubInfo;
+use progress::{NoProgress, Progress};
+use setup::{is, nfo300, Entry, Setup};
+use util::{get_all_nested_files, move_file, SetupFormat};
+
+fn systemtime_strftime<T>(dt: T) -> String
+where
+    T: Into<DateTime<Utc>>,
+{
+    let datetime: DateTime<Utc> = dt.into();
+    datetime.format("%d/%m/%Y %T").to_string()
+}
+
+/// Inner zip of a setup, which is read in place from the setup file
+pub type SetupZip = zip::ZipArchive<extract::PageOverlay<SetupParts>>;
+type SetupParts = extract::JoinedReader<setup::SpanReader<BufReader<File>>>;
+
+/// Archives with the client files of a setup
+pub enum ClientArchives {
+    Zip(SetupZip),
+    /// The cabinet set, the files of a MSI are in its `Data1.cab`
+    Cabs(Vec<PathBuf>),
+}
+
+pub enum SetupOpt {
+    Nfo300(nfo300::Nfo300Setup<BufReader<File>>, PathBuf),
+    Is(is::IsSetup<BufReader<File>>, PathBuf),
+}
+
+impl SetupOpt {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path.as_ref())?), path)
+    }
+
+    /// Detects the format on an already opened reader of the setup at `path`
+    pub fn from_reader(mut rdr: BufReader<File>, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        rdr.rewind()?;
+        match SetupFormat::from_reader(rdr.by_ref())? {
+            SetupFormat::NFO300(offset) => {
+                let setup = nfo300::Nfo300Setup::new(rdr, offset)?;
+                Ok(Self::Nfo300(setup, path.as_ref().to_path_buf()))
+            }
+            SetupFormat::InstallShield(offset) => {
+                let setup = is::IsSetup::new(rdr, offset)?;
+                Ok(Self::Is(setup, path.as_ref().to_path_buf()))
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Nfo300(_, path) => path,
+            Self::Is(_, path) => path,
+        }
+    }
+
+    /// Offset of the container in the setup file
+    pub fn container_offset(&self) -> u64 {
+        match self {
+            Self::Nfo300(setup, _) => setup.offset(),
+            Self::Is(setup, _) => setup.offset(),
+        }
+    }
+
+    pub fn format_name(&self) -> &'static str {
+        match self {
+            Self::Nfo300(..) => "NFO300",
+            Self::Is(..) => "InstallShield",
+        }
+    }
+
+    /// Name and size of the embedded entries
+    pub fn entry_list(&mut self) -> anyhow::Result<Vec<(String, u64)>> {
+        fn list(mut setup: impl Setup) -> anyhow::Result<Vec<(String, u64)>> {
+            Ok(setup
+                .entries()?
+                .iter()
+                .map(|e| (e.name().to_string(), e.size()))
+                .collect())
+        }
+
+        match self {
+            Self::Nfo300(setup, _) => list(setup),
+            Self::Is(setup, _) => list(setup),
+        }
+    }
+
+    /// Name and location of the embedded entries, to read them lazily
+    pub fn entry_spans(&mut self) -> anyhow::Result<Vec<(String, setup::EntrySpan)>> {
+        fn spans(mut setup: impl Setup) -> anyhow::Result<Vec<(String, setup::EntrySpan)>> {
+            Ok(setup
+                .entries()?
+                .iter()
+                .map(|e| (e.name().to_string(), setup.entry_span(e)))
+                .collect())
+        }
+        match self {
+            Self::Nfo300(setup, _) => spans(setup),
+            Self::Is(setup, _) => spans(setup),
+        }
+    }
+
+    /// Opens the client archives. Split zips are read in place, cabinets and MSIs are
+    /// spooled to `spool_dir` since 7z only reads them from files. They are copied whole
+    /// before this returns, which takes their size on disk
+    pub fn client_archives(
+        &mut self,
+        spool_dir: &Path,
+        cancel: &CancelToken,
+    ) -> anyhow::Result<ClientArchives> {
+        let path = self.path().to_path_buf();
+        let spans = self.entry_spans()?;
+        setup::check_entry_file_names(spans.iter().map(|(name, _)| name.as_str()))?;
+
+        let zip_parts = spans
+            .iter()
+            .filter(|(name, _)| is_zip_volume(Path::new(name)))
+            .map(|(_, span)| {
+                let rdr = BufReader::new(File::open(&path)?);
+                Ok(setup::SpanReader::new(rdr, span.clone()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if !zip_parts.is_empty() {
+            return Ok(ClientArchives::Zip(extract::open_zip_parts(zip_parts)?));
+        }
+
+        // The names come from the setup, so they are sanitised like extracted entries.
+        // Each MSI gets its own dir, as they all contain a Data1.cab
+        let entries_dir = spool_dir.join("entries");
+        std::fs::create_dir_all(&entries_dir)?;
+        let mut cabs = Vec::new();
+        for (name, span) in spans.iter() {
+            let entry = Path::new(name);
+            let (is_cab, is_msi) = (has_ext(entry, "cab"), has_ext(entry, "msi"));
+            if !is_cab && !is_msi {
+                continue;
+            }
+            let file_name = setup::entry_file_name(name);
+            let out = entries_dir.join(&file_name);
+            let rdr = setup::SpanReader::new(BufReader::new(File::open(&path)?), span.clone());
+            std::io::copy(&mut CancelReader::new(rdr, cancel), &mut File::create(&out)?)?;
+            if is_msi {
+                let msi_dir = spool_dir.join("msi").join(&file_name);
+                std::fs::create_dir_all(&msi_dir)?;
+                extract::extract_msi(&out, &msi_dir, cancel)?;
+                cabs.push(msi_dir.join("Data1.cab"));
+            } else {
+                cabs.push(out);
+            }
+        }
+        anyhow::ensure!(!cabs.is_empty(), "No client archive in the setup");
+        Ok(ClientArchives::Cabs(cabs))
+    }
+
+    pub fn extract_entries(
+        &mut self,
+        tmp_dir: &Path,
+        progress: &dyn Progress,
+        cancel: &CancelToken,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        match self {
+            Self::Nfo300(setup, _) => setup.extract_to(tmp_dir, progress, cancel),
+            Self::Is(setup, _) => setup.extract_to(tmp_dir, progress, cancel),
+        }
+        .context("Extracing entries")
+    }
+
+    pub fn extract_setup(
+        &mut self,
+        tmp_dir: &Path,
+        out_dir: &Path,
+        filter: &EntryFilter,
+        progress: &dyn Progress,
+        cancel: &CancelToken,
+    ) -> anyhow::Result<()> {
+        // Extract all entries to a temporary directory
+        let out = self.extract_entries(tmp_dir, progress, cancel)?;
+        extract_archives(out, tmp_dir, out_dir, filter, progress, cancel)
+    }
+
+    /// Fingerprints the client, split zips are read in place, other formats are
+    /// extracted into the tmp dir first
+    pub fn fingerprint(&mut self, tmp_dir: &Path) -> anyhow::Result<Fingerprint> {
+        let cancel = CancelToken::new();
+        let out = self.extract_entries(tmp_dir, &NoProgress, &cancel)?;
+        if out.iter().any(|p| is_zip_volume(p)) {
+            let mut archive = open_zip_split(out)?;
+            return Fingerprint::from_files(&mut archive);
+        }
+
+        let out_dir = tmp_dir.join("client");
+        std::fs::create_dir(&out_dir)?;
+        let all = EntryFilter::default();
+        extract_archives(out, tmp_dir, &out_dir, &all, &NoProgress, &cancel)?;
+        Fingerprint::from_dir(&out_dir)
+    }
+
+    pub fn list_archives(&mut self) -> anyhow::Result<()> {
+        log::info!("Listing archives for: {}", self.path().display());
+        match StubInfo::read(BufReader::new(File::open(self.path())?)) {
+            Ok(stub) => Self::log_stub(&stub),
+            Err(err) => log::warn!("Invalid PE stub: {err}"),
+        }
+        match Signature::read(BufReader::new(File::open(self.path())?)) {
+            Ok(Some(sig)) => sig.log(),
+            Ok(None) => log::info!("Signature: unsigned"),
+            Err(err) => log::warn!("Invalid signature: {err}"),
+        }
+        match self {
+            Self::Nfo300(setup, _) => Self::list_archives_inner(setup),
+            Self::Is(setup, _) => Self::list_archives_inner(setup),
+        }
+    }
+
+    fn log_stub(stub: &StubInfo) {
+        log::info!(
+            "Overlay: {:#x}..{:#x} ({})",
+            stub.overlay_offset,
+            stub.overlay_end,
+            SizeFormatter::new(stub.overlay_size(), DECIMAL)
+        );
+        if let Some(cert) = &stub.certificate_table {
+            log::info!("Certificate table: {:#x}..{:#x}", cert.start, cert.end);
+        }
+        if let Some(version) = &stub.version {
+            let v = version.file_version;
+            log::info!("Stub version: {}.{}.{}.{}", v[0], v[1], v[2], v[3]);
+            for (key, value) in version.strings.iter() {
+                log::info!("\t{key}: {value}");
+            }
+        }
+    }
+
+    fn list_archives_inner(mut setup: impl Setup) -> anyhow::Result<()> {
+        match setup.entries() {
+            Ok(entries) => {
+                for entry in entries.iter() {
+                    log::info!(
+                        "{} - {}",
+                        entry.name(),
+                        SizeFormatter::new(entry.size(), DECIMAL)
+                    );
+                }
+
+                let total: u64 = entries.iter().map(|e| e.size()).sum();
+                let sz = setup.size();
+                let perc = (total as f64 / sz as f64) * 100.0;
+                log::info!(
+                    "Total: {}/{} ({perc:.2}%)",
+                    SizeFormatter::new(total, DECIMAL),
+                    SizeFormatter::new(sz, DECIMAL)
+                );
+            }
+            Err(e) => log::error!("Error: {}", e),
+        }
+
+        Ok(())
+    }
+
+    pub fn extract_and_report(
+        &mut self,
+        id: usize,
+        out_dir: &Path,
+        opts: &ExtractOptions,
+        progress: &dyn Progress,
+    ) -> anyhow::Result<()> {
+        let name = self.path().file_stem().context("Invalid setup path")?;
+        let out_dir = out_dir.join(name);
+
+        let tmp_dir = std::env::temp_dir().join(format!("mssetupx{id}"));
+        // Ensure it's clean
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+        std::fs::create_dir_all(&tmp_dir)?;
+        std::fs::create_dir_all(&out_dir).context("Create out dir")?;
+        let extracted = self
+            .extract_setup(&tmp_dir, &out_dir, &opts.filter, progress, &opts.cancel)
+            .and_then(|_| {
+                if !opts.recursive {
+                    return Ok(BTreeMap::new());
+                }
+                extract_nested(&out_dir, &tmp_dir.join("nested"), &opts.filter, &opts.cancel)
+                    .context("Extract nested")
+            });
+        let sources = match extracted {
+            Ok(sources) => sources,
+            // Partial outputs of a cancelled setup are removed
+            Err(err) if is_cancelled(&err) => {
+                let _ = std::fs::remove_dir_all(&tmp_dir);
+                let _ = remove_existing_dir(&out_dir);
+                return Err(Cancelled.into());
+            }
+            Err(err) => return Err(err),
+        };
+        Self::create_report(&out_dir)?;
+        if opts.manifest {
+            self.write_manifest(&out_dir, &sources)?;
+        }
+        if !opts.keep_tmp {
+            std::fs::remove_dir_all(tmp_dir)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_manifest(
+        &self,
+        out_dir: &Path,
+        sources: &BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let mut manifest = ExtractManifest::from_dir(self.path(), out_dir)?;
+        manifest.set_sources(sources);
+        match Fingerprint::from_dir(out_dir) {
+            Ok(fp) => manifest.fingerprint = Some(fp),
+            Err(err) => log::warn!("Fingerprint failed for {}: {err}", self.path().display()),
+        }
+        match Signature::read(BufReader::new(File::open(self.path())?)) {
+            Ok(sig) => manifest.signature = sig,
+            Err(err) => log::warn!("Invalid signature for {}: {err}", self.path().display()),
+        }
+        manifest.write(out_dir)
+    }
+
+    fn create_report(dir: &Path) -> anyhow::Result<()> {
+        use std::io::Write;
+        let entries = get_all_nested_files(dir)?;
+
+        // Create report
+        let report = dir.join(REPORT_FILE);
+        let mut report = BufWriter::new(File::create(&report)?);
+        for entry in entries.iter() {
+            let meta = entry.metadata()?;
+            let name = entry.file_name().unwrap().to_string_lossy();
+            let cre = systemtime_strftime(meta.created().unwrap());
+            // The modification time is restored from the archive headers
+            let modi = systemtime_strftime(meta.modified().unwrap());
+
+            writeln!(
+                report,
+                "{} - {} - {cre} - {modi}",
+                name,
+                SizeFormatter::new(meta.len(), DECIMAL)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Options shared by `extract` and `extract-all`
+#[derive(Debug, Default)]
+pub struct ExtractOptions {
+    pub keep_tmp: bool,
+    pub recursive: bool,
+    /// Fingerprints the client and writes its `manifest.json`
+    pub manifest: bool,
+    /// Entries of the client archives to extract
+    pub filter: EntryFilter,
+    pub cancel: CancelToken,
+}
+
+/// Whether the path has the extension, ignoring the case like Windows does
+pub fn has_ext(p: &Path, ext: &str) -> bool {
+    p.extension().is_some_and(|e| e.eq_ignore_ascii_case(ext))
+}
+
+/// Volume of a split zip, the `.zip` or one of the `.z01`, `.z02`, ... before it
+pub fn is_zip_volume(p: &Path) -> bool {
+    let split = p
+        .extension()
+        .and_then(|e| e.to_str()?.strip_prefix(['z', 'Z']));
+    has_ext(p, "zip")
+        || split.is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Extracts the archives contained in a setup into `out_dir`
+fn extract_archives(
+    out: Vec<PathBuf>,
+    tmp_dir: &Path,
+    out_dir: &Path,
+    filter: &EntryFilter,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> anyhow::Result<()> {
+    if out.iter().any(|p| has_ext(p, "cab")) {
+        extract_cab_split(out, out_dir, filter, progress, cancel)?;
+    } else if out.iter().any(|p| is_zip_volume(p)) {
+        extract_zip_split(out, out_dir, filter, progress, cancel)?;
+    } else if let Some(msi) = out.iter().find(|p| has_ext(p, "msi")) {
+        extract_msi_client(msi, tmp_dir, out_dir, filter, progress, cancel)?;
+    } else {
+        let exts = out
+            .iter()
+            .filter_map(|p| p.extension())
+            .filter_map(|s| s.to_str())
+            .collect::<HashSet<_>>();
+        anyhow::bail!("Unknown archive format: {:?}", exts);
+    }
+
+    Ok(())
+}
+
+/// The client files of a MSI are in the embedded `Data1.cab`
+fn extract_msi_client(
+    msi: &Path,
+    tmp_dir: &Path,
+    out_dir: &Path,
+    filter: &EntryFilter,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> anyhow::Result<()> {
+    let tmp_msi = tmp_dir.join("msi");
+    std::fs::create_dir(&tmp_msi)?;
+    extract::extract_msi(msi, &tmp_msi, cancel)?;
+
+    let data_cab = tmp_msi.join("Data1.cab");
+    extract_cab_split(vec![data_cab], out_dir, filter, progress, cancel)
+}
+
+fn rel_path(dir: &Path, path: &Path) -> String {
+    path.strip_prefix(dir).unwrap_or(path).to_string_lossy().replace('\\', "/")
+}
+
+/// First existing path, which unpacking the files to `target` would replace. That's a
+/// file or directory at a destination or a file in place of one of its directories,
+/// the archive at `removed` is ignored
+fn first_collision(target: &Path, files: &[PathBuf], removed: Option<&Path>) -> Option<PathBuf> {
+    let exists = |p: &Path| Some(p) != removed && p.symlink_metadata().is_ok();
+    files.iter().find_map(|file| {
+        let dst = target.join(file);
+        if exists(&dst) {
+            return Some(dst);
+        }
+        dst.ancestors()
+            .skip(1)
+            .take_while(|p| p.starts_with(target))
+            .find(|p| exists(p) && !p.is_dir())
+            .map(Path::to_path_buf)
+    })
+}
+
+/// Unpacks the archives and setups inside the extracted client into a directory named
+/// after them, the archive files are removed while nested setups are kept. Returns the
+/// archive each unpacked file came from, nested archives are joined with ` > `.
+/// The filter is matched against the paths inside each nested archive. Files are never
+/// replaced, an archive colliding with existing files is unpacked into `<stem>_files`
+fn extract_nested(
+    out_dir: &Path,
+    tmp_dir: &Path,
+    filter: &EntryFilter,
+    cancel: &CancelToken,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    const MAX_DEPTH: usize = 4;
+    let mut sources = BTreeMap::new();
+    let mut scan = get_all_nested_files(out_dir)?;
+    for depth in 0..=MAX_DEPTH {
+        if depth == MAX_DEPTH {
+            for path in scan {
+                if NestedArchive::detect(&path)?.is_some() {
+                    log::warn!(
+                        "Nesting deeper than {MAX_DEPTH}, left packed: {}",
+                        rel_path(out_dir, &path)
+                    );
+                }
+            }
+            break;
+        }
+
+        let mut unpacked = Vec::new();
+        for path in scan {
+            let Some(kind) = NestedArchive::detect(&path)? else {
+                continue;
+            };
+            let rel = rel_path(out_dir, &path);
+            log::info!("Unpacking nested {kind:?}: {rel}");
+
+            let _ = std::fs::remove_dir_all(tmp_dir);
+            let files_dir = tmp_dir.join("files");
+            std::fs::create_dir_all(&files_dir)?;
+            match kind {
+                NestedArchive::Zip => {
+                    let archive = zip::ZipArchive::new(BufReader::new(File::open(&path)?))?;
+                    extract_zip(archive, &files_dir, filter, &NoProgress, cancel)?
+                }
+                NestedArchive::Cab => {
+                    extract_cab_split(vec![path.clone()], &files_dir, filter, &NoProgress, cancel)?
+                }
+                NestedArchive::Msi => {
+                    extract_msi_client(&path, tmp_dir, &files_dir, filter, &NoProgress, cancel)?
+                }
+                NestedArchive::Setup => {
+                    let mut setup = SetupOpt::open(&path)?;
+                    setup.extract_setup(tmp_dir, &files_dir, filter, &NoProgress, cancel)?
+                }
+            }
+
+            // Archives, which already contain their directory, are unpacked next to them
+            let files = get_all_nested_files(&files_dir)?
+                .into_iter()
+                .map(|f| f.strip_prefix(&files_dir).map(Path::to_path_buf))
+                .collect::<Result<Vec<_>, _>>()?;
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let prefix = format!("{}/", stem.to_ascii_lowercase());
+            let has_dir = files.iter().all(|f| {
+                f.to_string_lossy()
+                    .replace('\\', "/")
+                    .to_ascii_lowercase()
+                    .starts_with(&prefix)
+            });
+            let parent = path.parent().unwrap_or(out_dir);
+            let mut target = if has_dir {
+                parent.to_path_buf()
+            } else {
+                parent.join(stem.as_ref())
+            };
+
+            // The archive is removed before its files are moved, a kept setup is a collision
+            let removed = (kind != NestedArchive::Setup).then_some(path.as_path());
+            if let Some(conflict) = first_collision(&target, &files, removed) {
+                let files_target = parent.join(format!("{stem}_files"));
+                if let Some(conflict) = first_collision(&files_target, &files, removed) {
+                    anyhow::bail!(
+                        "Nested archive {rel} collides with: {}",
+                        rel_path(out_dir, &conflict)
+                    );
+                }
+                log::warn!(
+                    "Nested archive {rel} collides with {}, unpacking into: {}",
+                    rel_path(out_dir, &conflict),
+                    rel_path(out_dir, &files_target)
+                );
+                target = files_target;
+            }
+
+            let source = match sources.get(&rel) {
+                Some(parent) => format!("{parent} > {rel}"),
+                None => rel.clone(),
+            };
+            if kind != NestedArchive::Setup {
+                remove_existing(&path)?;
+                sources.remove(&rel);
+            }
+            for file in files {
+                let dst = target.join(&file);
+                if let Some(parent) = dst.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                move_file(&files_dir.join(&file), &dst)?;
+                sources.insert(rel_path(out_dir, &dst), source.clone());
+                unpacked.push(dst);
+            }
+        }
+
+        if unpacked.is_empty() {
+            break;
+        }
+        scan = unpacked;
+    }
+
+    let _ = std::fs::remove_dir_all(tmp_dir);
+    Ok(sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::util::unique_temp_dir;
+
+    use super::*;
+
+    fn write_zip(path: &Path, files: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn archive_ext() {
+        assert!(has_ext(Path::new("CLIENT.ZIP"), "zip"));
+        assert!(has_ext(Path::new("Data1.Cab"), "cab"));
+        assert!(!has_ext(Path::new("Data1.cab.bak"), "cab"));
+        assert!(is_zip_volume(Path::new("Maple.Z01")));
+        assert!(is_zip_volume(Path::new("Maple.z12")));
+        assert!(is_zip_volume(Path::new("MAPLE.ZIP")));
+        assert!(!is_zip_volume(Path::new("Maple.z")));
+        assert!(!is_zip_volume(Path::new("Maple.zst")));
+    }
+
+    #[test]
+    fn nested_collision() {
+        let dir = unique_temp_dir("mssetup_nested_collision_test");
+        let out = dir.join("out");
+        std::fs::create_dir_all(out.join("HShield")).unwrap();
+        std::fs::write(out.join("HShield/v3.dll"), b"client").unwrap();
+        write_zip(&out.join("HShield.zip"), &[("HShield/v3.dll", b"nested")]);
+        // A file in place of the directory of the archive
+        std::fs::write(out.join("Data"), b"client").unwrap();
+        write_zip(&out.join("Data.zip"), &[("Base.wz", b"base")]);
+
+        let (all, cancel) = (EntryFilter::default(), CancelToken::new());
+        let sources = extract_nested(&out, &dir.join("tmp"), &all, &cancel).unwrap();
+        assert_eq!(
+            std::fs::read(out.join("HShield/v3.dll")).unwrap(),
+            b"client"
+        );
+        assert_eq!(
+            std::fs::read(out.join("HShield_files/HShield/v3.dll")).unwrap(),
+            b"nested"
+        );
+        assert_eq!(std::fs::read(out.join("Data")).unwrap(), b"client");
+        assert_eq!(
+            std::fs::read(out.join("Data_files/Base.wz")).unwrap(),
+            b"base"
+        );
+        assert_eq!(sources["Data_files/Base.wz"], "Data.zip");
+        assert!(!out.join("HShield.zip").exists());
+
+        // Without a free directory the archive is kept and the conflict reported
+        write_zip(&out.join("HShield.zip"), &[("HShield/v3.dll", b"nested")]);
+        let err = extract_nested(&out, &dir.join("tmp"), &all, &cancel).unwrap_err();
+        assert!(err.to_string().contains("HShield_files/HShield/v3.dll"));
+        assert!(out.join("HShield.zip").exists());
+        assert_eq!(
+            std::fs::read(out.join("HShield/v3.dll")).unwrap(),
+            b"client"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
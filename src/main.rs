@@ -1,212 +1,498 @@
-pub mod extract;
-pub mod setup;
-pub mod util;
-pub mod patch;
-pub mod patcher;
-
 use std::{
-    collections::HashSet,
     fs::File,
-    io::{BufReader, BufWriter, Read},
+    io::{BufReader, BufWriter, Seek},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use anyhow::Context;
 use chrono::{DateTime, Utc};
-use clap::Parser;
-use extract::{extract_cab_split, extract_zip_split};
+use clap::{Parser, Subcommand};
 use humansize::{SizeFormatter, DECIMAL};
-use patch::WzPatch;
-use patcher::WzPatcherInfo;
+use indicatif::{MultiProgress, ProgressBar};
+use indicatif_log_bridge::LogWrapper;
+use mssetup::{
+    analyze,
+    authenticode::Signature,
+    cancel::CancelToken,
+    catalog::{Catalog, PatchRecord, SetupRecord},
+    convert,
+    diff::ClientDiff,
+    extract::{self, remove_existing, remove_existing_dir},
+    filter::{self, EntryFilter, Pattern},
+    fingerprint::Fingerprint,
+    graph::{patch_versions, version_from_setup_name, GraphFormat, PatchEdge, VersionGraph},
+    has_ext,
+    job::JobState,
+    manifest::{MANIFEST_FILE, REPORT_FILE},
+    pack,
+    patch::WzPatch,
+    patcher::{WzPatcher, WzPatcherInfo},
+    pe::StubInfo,
+    progress::{BarProgress, NoProgress},
+    rebuild, serve,
+    store::{LinkMode, Store},
+    util::{copy_dir, get_all_nested_files, sha256_file, sha256_reader, unique_temp_dir},
+    ExtractOptions, SetupOpt,
+};
 use rayon::iter::{ParallelBridge, ParallelIterator};
-use setup::{is, nfo300, Entry, Setup};
-use util::{get_all_nested_files, SetupFormat};
-
-fn systemtime_strftime<T>(dt: T) -> String
-where
-    T: Into<DateTime<Utc>>,
-{
-    let datetime: DateTime<Utc> = dt.into();
-    datetime.format("%d/%m/%Y %T").to_string()
+#[cfg(feature = "fuse")]
+use mssetup::mount;
+
+/// Extracts a setup of `extract` into `output`, setups completed by a previous run are
+/// skipped. The `marker` is written last and marks the output as complete
+fn extract_job(
+    state: &JobState,
+    path: &Path,
+    output: &Path,
+    marker: &Path,
+    force: bool,
+    options: &str,
+    extract: impl FnOnce(&mut SetupOpt) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let name = path.file_stem().context("Invalid setup path")?.to_string_lossy();
+    let sha256 = sha256_file(path).context("Hash setup")?;
+    if !force && state.is_complete(&name, &sha256, options, marker) {
+        log::info!("Skipping completed setup: {}", path.display());
+        return Ok(());
+    }
+
+    // Output of an interrupted, failed or changed setup is removed, so no stale files remain
+    if state.get(&name).is_some() {
+        if output.is_dir() {
+            remove_existing_dir(output).context("Remove previous output")?;
+        } else {
+            remove_existing(output).context("Remove previous output")?;
+        }
+    }
+
+    state.begin(&name, path, &sha256, path.metadata()?.len(), options)?;
+    let res = SetupOpt::open(path).and_then(|mut setup| extract(&mut setup));
+    state.finish(&name, &res)?;
+    res
 }
 
-pub enum SetupOpt {
-    Nfo300(nfo300::Nfo300Setup<BufReader<File>>, PathBuf),
-    Is(is::IsSetup<BufReader<File>>, PathBuf),
+/// Setups of a file, glob or directory, a directory is searched for executables
+fn setup_paths(setup: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let path = Path::new(setup);
+    let paths = if path.is_file() {
+        vec![path.to_path_buf()]
+    } else if path.is_dir() {
+        let mut paths = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|p| p.is_file() && has_ext(p, "exe"));
+        paths.sort();
+        paths
+    } else {
+        glob::glob(setup)?.collect::<Result<Vec<_>, _>>()?
+    };
+
+    if paths.is_empty() {
+        anyhow::bail!("No setups found: {setup}");
+    }
+    Ok(paths)
 }
 
-impl SetupOpt {
-    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let mut rdr = BufReader::new(File::open(path.as_ref())?);
-        match SetupFormat::from_reader(rdr.by_ref())? {
-            SetupFormat::NFO300(offset) => {
-                let setup = nfo300::Nfo300Setup::new(rdr, offset)?;
-                Ok(Self::Nfo300(setup, path.as_ref().to_path_buf()))
-            }
-            SetupFormat::InstallShield(offset) => {
-                let setup = is::IsSetup::new(rdr, offset)?;
-                Ok(Self::Is(setup, path.as_ref().to_path_buf()))
-            }
-        }
+fn extract(args: ExtractArgs, multi: &MultiProgress) -> anyhow::Result<()> {
+    let options = args.options_key()?;
+    let opts = ExtractOptions {
+        keep_tmp: args.keep_tmp,
+        recursive: args.recursive,
+        manifest: !args.no_manifest,
+        filter: args.filter.build()?,
+        cancel: CancelToken::ctrl_c()?,
+    };
+    let archive_ext = args.format.archive_ext();
+    if archive_ext.is_some() && (args.store.is_some() || opts.recursive || !opts.filter.is_all()) {
+        anyhow::bail!("Archive formats can't be combined with a store, recursion or filters");
     }
+    let paths = setup_paths(&args.setup)?;
+    let out_dir = Path::new(&args.out_dir);
+    std::fs::create_dir_all(out_dir).context("Create out dir")?;
+    let state = JobState::load(out_dir).context("Load job state")?;
+    let store = args.store.map(Store::open).transpose().context("Open store")?;
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build_global()
+        .unwrap();
+    let failed = AtomicUsize::new(0);
+    paths
+        .iter()
+        .enumerate()
+        .par_bridge()
+        .for_each(|(id, path)| {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            let output = match archive_ext {
+                Some(ext) => out_dir.join(format!("{name}.{ext}")),
+                None => out_dir.join(name.as_ref()),
+            };
+            let marker = match (&store, archive_ext) {
+                (Some(store), _) => store.manifest_path(&name),
+                (None, Some(_)) => output.clone(),
+                (None, None) if opts.manifest => output.join(MANIFEST_FILE),
+                (None, None) => output.join(REPORT_FILE),
+            };
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            let bar = BarProgress::new(multi.add(ProgressBar::new(0)), &file_name);
+            let force = args.force;
+            let res = extract_job(&state, path, &output, &marker, force, &options, |setup| {
+                if archive_ext.is_some() {
+                    return convert::convert_setup(setup, &output, &opts.cancel).map(|_| ());
+                }
+                setup.extract_and_report(id, out_dir, &opts, &bar)?;
+                match &store {
+                    Some(store) => store.ingest(&name, &output).map(|_| ()),
+                    None => Ok(()),
+                }
+            });
+            if let Err(err) = res {
+                failed.fetch_add(1, Ordering::Relaxed);
+                bar.finish("failed");
+                log::error!("Error: {} for: {}", err, path.display());
+            } else {
+                bar.finish("done");
+            }
+        });
 
-    fn path(&self) -> &Path {
-        match self {
-            Self::Nfo300(_, path) => path,
-            Self::Is(_, path) => path,
-        }
+    let failed = failed.into_inner();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} setups failed", paths.len());
     }
+    Ok(())
+}
 
-    fn extract_setup(&mut self, tmp_dir: &Path, out_dir: &Path) -> anyhow::Result<()> {
-        // Extract all entries to a temporary directory
-        let out = match self {
-            Self::Nfo300(setup, _) => setup.extract_to(tmp_dir),
-            Self::Is(setup, _) => setup.extract_to(tmp_dir),
-        }
-        .context("Extracing entries")?;
-
-        let exts = out
-            .iter()
-            .filter_map(|p| p.extension())
-            .filter_map(|s| s.to_str())
-            .collect::<HashSet<_>>();
-        if exts.contains(&"cab") {
-            extract_cab_split(out, out_dir)?;
-        } else if exts.contains(&"zip") || exts.contains(&"z0") {
-            extract_zip_split(out, out_dir)?;
-        } else if exts.contains(&"msi") {
-            let msi = out
-                .iter()
-                .find(|p| p.extension().and_then(|s| s.to_str()) == Some("msi"))
-                .unwrap();
-            let tmp_msi = tmp_dir.join("msi");
-            std::fs::create_dir(&tmp_msi)?;
-            extract::extract_msi(msi, &tmp_msi)?;
-
-            let data_cab = tmp_msi.join("Data1.cab");
-            extract_cab_split(vec![data_cab], out_dir)?;
-        } else {
-            anyhow::bail!("Unknown archive format: {:?}", exts);
-        }
+fn fingerprint(path: &Path) -> anyhow::Result<()> {
+    let fp = if path.is_dir() {
+        Fingerprint::from_dir(path)?
+    } else {
+        let mut setup = SetupOpt::open(path)?;
+        let tmp_dir = unique_temp_dir("mssetupfp");
+        let fp = std::fs::create_dir_all(&tmp_dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| setup.fingerprint(&tmp_dir));
+        let _ = std::fs::remove_dir_all(&tmp_dir);
+        fp?
+    };
+
+    log::info!("Fingerprint: {}", path.display());
+    fp.log();
+    Ok(())
+}
 
-        Ok(())
+/// Directory of the client, a setup is extracted into the tmp dir first
+fn client_dir(path: &Path, tmp_dir: &Path) -> anyhow::Result<PathBuf> {
+    if path.is_dir() {
+        return Ok(path.to_path_buf());
     }
 
-    fn list_archives(&mut self) -> anyhow::Result<()> {
-        log::info!("Listing archives for: {}", self.path().display());
-        match self {
-            Self::Nfo300(setup, _) => Self::list_archives_inner(setup),
-            Self::Is(setup, _) => Self::list_archives_inner(setup),
-        }
+    let out_dir = tmp_dir.join("client");
+    std::fs::create_dir_all(&out_dir)?;
+    let mut setup = SetupOpt::open(path)?;
+    let all = EntryFilter::default();
+    setup.extract_setup(tmp_dir, &out_dir, &all, &NoProgress, &CancelToken::new())?;
+    Ok(out_dir)
+}
+
+fn diff(old: &Path, new: &Path, json: Option<&Path>) -> anyhow::Result<()> {
+    let old_tmp = unique_temp_dir("mssetupdiff");
+    let new_tmp = unique_temp_dir("mssetupdiff");
+    std::fs::create_dir_all(&old_tmp)?;
+    std::fs::create_dir_all(&new_tmp)?;
+    let diff = client_dir(old, &old_tmp)
+        .and_then(|old| Ok((old, client_dir(new, &new_tmp)?)))
+        .and_then(|(old, new)| ClientDiff::from_dirs(&old, &new));
+    std::fs::remove_dir_all(&old_tmp)?;
+    std::fs::remove_dir_all(&new_tmp)?;
+    let diff = diff?;
+
+    log::info!("Diff: {} -> {}", old.display(), new.display());
+    diff.log();
+    if let Some(json) = json {
+        let file = BufWriter::new(File::create(json)?);
+        serde_json::to_writer_pretty(file, &diff.to_patcher_info())?;
     }
+    Ok(())
+}
 
-    fn list_archives_inner(mut setup: impl Setup) -> anyhow::Result<()> {
-        match setup.entries() {
-            Ok(entries) => {
-                for entry in entries.iter() {
-                    log::info!(
-                        "{} - {}",
-                        entry.name(),
-                        SizeFormatter::new(entry.size(), DECIMAL)
-                    );
-                }
+/// Applies the patch onto a copy of the `from` client in the tmp dir
+fn patched_client(patch: &Path, from: &Path, tmp_dir: &Path) -> anyhow::Result<PathBuf> {
+    let client = if from.is_dir() {
+        let client = tmp_dir.join("client");
+        copy_dir(from, &client)?;
+        client
+    } else {
+        client_dir(from, tmp_dir)?
+    };
+
+    let mut patch = WzPatch::open(patch)?;
+    patch.verify_checksum().context("Verify patch")?;
+    log::info!("Applying patch version {}", patch.version());
+    let out_dir = tmp_dir.join("patched");
+    let mut patcher = WzPatcher::with_out_dir(&client, &out_dir);
+    patch.process(&mut patcher, &CancelToken::new())?;
+    for path in patcher.removed() {
+        extract::remove_existing(&client.join(path))?;
+    }
 
-                let total: u64 = entries.iter().map(|e| e.size()).sum();
-                let sz = setup.size();
-                let perc = (total as f64 / sz as f64) * 100.0;
-                log::info!(
-                    "Total: {}/{} ({perc:.2}%)",
-                    SizeFormatter::new(total, DECIMAL),
-                    SizeFormatter::new(sz, DECIMAL)
-                );
+    // Replace the old files with the patched ones
+    if out_dir.is_dir() {
+        for path in get_all_nested_files(&out_dir)? {
+            let dst = client.join(path.strip_prefix(&out_dir)?);
+            if let Some(parent) = dst.parent() {
+                std::fs::create_dir_all(parent)?;
             }
-            Err(e) => log::error!("Error: {}", e),
+            extract::remove_existing(&dst)?;
+            std::fs::rename(&path, &dst)?;
         }
+    }
+    Ok(client)
+}
+
+/// Checks that the patch turns the `from` client into the `to` client
+fn validate_patch(patch: &Path, from: &Path, to: &Path) -> anyhow::Result<()> {
+    let from_tmp = unique_temp_dir("mssetupval");
+    let to_tmp = unique_temp_dir("mssetupval");
+    std::fs::create_dir_all(&from_tmp)?;
+    std::fs::create_dir_all(&to_tmp)?;
+    let diff = patched_client(patch, from, &from_tmp)
+        .and_then(|patched| Ok((patched, client_dir(to, &to_tmp)?)))
+        .and_then(|(patched, to)| ClientDiff::from_dirs(&patched, &to));
+    std::fs::remove_dir_all(&from_tmp)?;
+    std::fs::remove_dir_all(&to_tmp)?;
+    let diff = diff?;
 
-        Ok(())
+    if diff.is_empty() {
+        log::info!("Patch {} matches: {}", patch.display(), to.display());
+        return Ok(());
     }
 
-    fn extract_and_report(
-        &mut self,
-        id: usize,
-        remove_prefix: &[String],
-        remove_exts: &[String],
-        out_dir: &Path,
-        keep_tmp: bool,
-    ) -> anyhow::Result<()> {
-        let name = self.path().file_stem().context("Invalid setup path")?;
-        let out_dir = out_dir.join(name);
-
-        let tmp_dir = std::env::temp_dir().join(format!("mssetupx{id}"));
-        // Ensure it's clean
-        let _ = std::fs::remove_dir_all(&tmp_dir);
+    log::warn!("Patched client differs from: {}", to.display());
+    for (path, size) in diff.added.iter() {
+        log::warn!("\tMissing: {path} - {}", SizeFormatter::new(*size, DECIMAL));
+    }
+    for (path, size) in diff.removed.iter() {
+        log::warn!("\tUnexpected: {path} - {}", SizeFormatter::new(*size, DECIMAL));
+    }
+    for (path, patched, expected) in diff.modified.iter() {
+        log::warn!(
+            "\tDiffers: {path} - {}({})",
+            SizeFormatter::new(*patched, DECIMAL),
+            SizeFormatter::new(*expected, DECIMAL)
+        );
+    }
+    anyhow::bail!(
+        "{} files differ",
+        diff.added.len() + diff.removed.len() + diff.modified.len()
+    )
+}
+
+fn setup_record(path: &Path, fingerprint: bool) -> anyhow::Result<SetupRecord> {
+    // The hash, stub and signature are read through the same reader as the setup
+    let mut rdr = BufReader::new(File::open(path)?);
+    let sha256 = sha256_reader(&mut rdr)?;
+    rdr.rewind()?;
+    let stub = StubInfo::read(&mut rdr).ok();
+    rdr.rewind()?;
+    let signature = Signature::read(&mut rdr).unwrap_or_else(|err| {
+        log::warn!("Invalid signature for {}: {err}", path.display());
+        None
+    });
+    let mut setup = SetupOpt::from_reader(rdr, path)?;
+
+    let mut record = SetupRecord {
+        path: path.display().to_string(),
+        sha256,
+        format: setup.format_name().to_string(),
+        container_offset: setup.container_offset(),
+        stub_version: stub.and_then(|stub| stub.version).map(|version| {
+            let v = version.file_version;
+            format!("{}.{}.{}.{}", v[0], v[1], v[2], v[3])
+        }),
+        signer: signature
+            .as_ref()
+            .and_then(|sig| sig.chain.first())
+            .map(|cert| cert.subject.clone()),
+        signature_valid: signature.as_ref().and_then(|sig| sig.digest_valid),
+        entries: setup.entry_list()?,
+        ..Default::default()
+    };
+
+    if fingerprint {
+        let tmp_dir = unique_temp_dir("mssetupidx");
         std::fs::create_dir_all(&tmp_dir)?;
-        std::fs::create_dir_all(&out_dir).context("Create out dir")?;
-        self.extract_setup(&tmp_dir, &out_dir)?;
-        Self::create_report_and_clean_up(&out_dir, remove_prefix, remove_exts)?;
-        if !keep_tmp {
-            std::fs::remove_dir_all(tmp_dir)?;
+        let fp = setup.fingerprint(&tmp_dir);
+        std::fs::remove_dir_all(&tmp_dir)?;
+        match fp {
+            Ok(fp) => {
+                record.region = fp.region;
+                record.version = fp.version.map(|v| v.to_string());
+            }
+            Err(err) => log::warn!("Fingerprint failed for {}: {err}", path.display()),
         }
+    }
 
-        Ok(())
+    Ok(record)
+}
+
+fn patch_record(path: &Path) -> anyhow::Result<PatchRecord> {
+    let mut patch = WzPatch::open(path)?;
+    let mut info = WzPatcherInfo::default();
+    patch.process(&mut info, &CancelToken::new())?;
+    let versions = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| patch_versions(name, patch.version()));
+
+    Ok(PatchRecord {
+        path: path.display().to_string(),
+        sha256: sha256_file(path)?,
+        version: patch.version(),
+        from_version: versions.map(|v| v.0),
+        to_version: versions.map(|v| v.1),
+        added: info.added_files.len(),
+        modified_files: info.modified_files.len(),
+        removed: info.removed_files.len(),
+        ..Default::default()
+    })
+}
+
+/// Adds all setups and patches below `dir` to the catalog, unchanged files are skipped
+fn index(catalog: &mut Catalog, dir: &Path, fingerprint: bool) -> anyhow::Result<()> {
+    let (mut indexed, mut skipped) = (0, 0);
+    for path in get_all_nested_files(dir)? {
+        if !has_ext(&path, "exe") && !has_ext(&path, "patch") {
+            continue;
+        }
+
+        let meta = path.metadata()?;
+        let modified = meta
+            .modified()
+            .ok()
+            .map(|t| DateTime::<Utc>::from(t).to_rfc3339());
+        if catalog.is_indexed(&path.display().to_string(), meta.len(), modified.as_deref())? {
+            skipped += 1;
+            continue;
+        }
+
+        let res = if has_ext(&path, "patch") {
+            patch_record(&path).and_then(|record| {
+                catalog.insert_patch(&PatchRecord {
+                    size: meta.len(),
+                    modified,
+                    ..record
+                })
+            })
+        } else {
+            setup_record(&path, fingerprint).and_then(|record| {
+                catalog.insert_setup(&SetupRecord {
+                    size: meta.len(),
+                    modified,
+                    ..record
+                })
+            })
+        };
+        match res {
+            Ok(()) => {
+                log::info!("Indexed: {}", path.display());
+                indexed += 1;
+            }
+            Err(err) => log::warn!("Skipping {}: {err}", path.display()),
+        }
     }
 
-    fn create_report_and_clean_up(
-        dir: &Path,
-        remove_prefix: &[String],
-        remove_exts: &[String],
-    ) -> anyhow::Result<()> {
-        use std::io::Write;
-        let entries = get_all_nested_files(dir)?;
-
-        // Create report
-        let report = dir.join("report.txt");
-        let mut report = BufWriter::new(File::create(&report)?);
-        for entry in entries.iter() {
-            let meta = entry.metadata()?;
-            let name = entry.file_name().unwrap().to_string_lossy();
-            let acc = systemtime_strftime(meta.accessed().unwrap());
-            let cre = systemtime_strftime(meta.created().unwrap());
-
-            writeln!(
-                report,
-                "{} - {} - {cre} - {acc}",
-                name,
-                SizeFormatter::new(meta.len(), DECIMAL)
-            )?;
-        }
-
-        for entry in entries.iter() {
-            let name = entry.file_name().unwrap().to_string_lossy();
-            let name = name.to_string();
-
-            #[allow(clippy::search_is_some)]
-            let has_prefix = remove_prefix
-                .iter()
-                .find(|p| name.starts_with(p.as_str()))
-                .is_some();
-
-            #[allow(clippy::search_is_some)]
-            let has_ext = remove_exts
-                .iter()
-                .find(|e| entry.extension().and_then(|s| s.to_str()) == Some(e))
-                .is_some();
-
-            if has_prefix || has_ext {
-                if let Err(err) = std::fs::remove_file(entry) {
-                    log::error!("Error Deleting File({}): {err}", entry.display());
-                }
+    let pruned = catalog.prune(&dir.display().to_string())?;
+    log::info!("Indexed {indexed} files, {skipped} unchanged, {pruned} removed");
+    Ok(())
+}
+
+fn query(catalog: &Catalog, query: CatalogQuery) -> anyhow::Result<()> {
+    match query {
+        CatalogQuery::Setups {
+            version,
+            region,
+            path,
+        } => {
+            let setups =
+                catalog.find_setups(version.as_deref(), region.as_deref(), path.as_deref())?;
+            for setup in setups.iter() {
+                setup.log();
+            }
+            log::info!("Found {} setups", setups.len());
+        }
+        CatalogQuery::Entries { name } => {
+            for (setup, name, size) in catalog.find_entries(&name)? {
+                log::info!("{setup} - {name} - {}", SizeFormatter::new(size, DECIMAL));
             }
         }
+        CatalogQuery::Patches { version } => {
+            let patches = catalog.find_patches(version)?;
+            for patch in patches.iter() {
+                patch.log();
+            }
+            log::info!("Found {} patches", patches.len());
+        }
+        CatalogQuery::Hash { sha256 } => {
+            for path in catalog.find_hash(&sha256)? {
+                log::info!("{path}");
+            }
+        }
+    }
+    Ok(())
+}
 
-        Ok(())
+/// Builds the version graph of the setups and patches below `dir`, setups are versioned
+/// by their name or their fingerprint
+fn version_graph(dir: &Path, fingerprint: bool) -> anyhow::Result<VersionGraph> {
+    let mut graph = VersionGraph::default();
+    for path in get_all_nested_files(dir)? {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if has_ext(&path, "patch") {
+            let patch = match WzPatch::open(&path) {
+                Ok(patch) => patch,
+                Err(err) => {
+                    log::warn!("Skipping {}: {err}", path.display());
+                    continue;
+                }
+            };
+            match patch_versions(&name, patch.version()) {
+                Some((from, to)) => graph.add_patch(PatchEdge {
+                    from,
+                    to,
+                    path: path.display().to_string(),
+                    header_version: patch.version(),
+                }),
+                None => graph.unresolved.push(path.display().to_string()),
+            }
+        } else if has_ext(&path, "exe") {
+            let mut version = version_from_setup_name(&name);
+            if version.is_none() && fingerprint {
+                let tmp_dir = unique_temp_dir("mssetupgraph");
+                std::fs::create_dir_all(&tmp_dir)?;
+                let fp = SetupOpt::open(&path).and_then(|mut setup| setup.fingerprint(&tmp_dir));
+                std::fs::remove_dir_all(&tmp_dir)?;
+                match fp {
+                    Ok(fp) => version = fp.version.map(|v| v.major as u32),
+                    Err(err) => log::warn!("Fingerprint failed for {}: {err}", path.display()),
+                }
+            }
+            match version {
+                Some(version) => graph.add_setup(version, path.display().to_string()),
+                None => graph.unresolved.push(path.display().to_string()),
+            }
+        }
     }
+    Ok(graph)
 }
 
-fn list_patcher(p: impl AsRef<Path>) -> anyhow::Result<()> {
+fn list_patcher(p: impl AsRef<Path>, json: Option<&Path>) -> anyhow::Result<()> {
     let mut patcher = WzPatch::open(&p)?;
     let mut info = WzPatcherInfo::default();
-    patcher.process(&mut info)?;
+    patcher.process(&mut info, &CancelToken::new())?;
+    if let Some(json) = json {
+        let file = BufWriter::new(File::create(json)?);
+        serde_json::to_writer_pretty(file, &info)?;
+    }
 
     log::info!("Patcher: {}", p.as_ref().display());
     log::info!("Version: {}", patcher.version());
@@ -229,47 +515,186 @@ fn list_patcher(p: impl AsRef<Path>) -> anyhow::Result<()> {
     Ok(())
 }
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-enum Args {
-    Extract {
-        /// The setup file to extract
+#[derive(Subcommand, Debug)]
+enum CatalogQuery {
+    /// Setups by client version, region or path
+    Setups {
+        /// Client version, a major version matches all minor versions
         #[arg(short, long)]
-        setup: String,
-
-        /// The setup directory
-        #[arg(short, long, default_value = "setup")]
-        dir: String,
-
-        /// Keep the tmp dir
-        #[arg(short, long, default_value = "false")]
-        keep_tmp: bool,
+        version: Option<String>,
+        #[arg(short, long)]
+        region: Option<String>,
+        /// SQL `LIKE` pattern on the setup path
+        #[arg(short, long)]
+        path: Option<String>,
     },
-    ExtractAll {
+    /// Setups with an embedded entry matching the SQL `LIKE` pattern
+    Entries {
         #[arg(short, long)]
-        setup_glob: String,
-        #[arg(
-            long,
-            default_value = "",
-            use_value_delimiter = true,
-            value_delimiter = ','
-        )]
-        remove_prefix: Vec<String>,
-        #[arg(
-            long,
-            default_value = "",
-            use_value_delimiter = true,
-            value_delimiter = ','
-        )]
-        remove_exts: Vec<String>,
+        name: String,
+    },
+    /// Patches from or to a version
+    Patches {
         #[arg(short, long)]
-        out_dir: String,
-        #[arg(short, long, default_value = "4")]
-        threads: usize,
-        /// Keep the tmp dir
-        #[arg(short, long, default_value = "false")]
-        keep_tmp: bool,
+        version: Option<u32>,
     },
+    /// Setups and patches with the SHA-256
+    Hash {
+        #[arg(short, long)]
+        sha256: String,
+    },
+}
+
+// Rules selecting the entries to extract, a doc comment would replace the command help
+#[derive(clap::Args, Debug)]
+struct FilterArgs {
+    /// Only extract entries matching one of the patterns, globs like `*.wz` or
+    /// `Data/**`, or `regex:`, `dir:`, `prefix:` and `ext:` followed by the pattern
+    #[arg(long, value_delimiter = ',')]
+    include: Vec<String>,
+    /// Skip entries matching one of the patterns
+    #[arg(long, value_delimiter = ',')]
+    exclude: Vec<String>,
+    /// Skip entries with the file name prefix, case-sensitive unlike `--exclude prefix:..`
+    #[arg(long, value_delimiter = ',')]
+    remove_prefix: Vec<String>,
+    /// Skip entries with the extension, case-sensitive unlike `--exclude ext:..`
+    #[arg(long, value_delimiter = ',')]
+    remove_exts: Vec<String>,
+    /// Skip entries smaller than the size, like `64K` or `1MB`
+    #[arg(long, value_parser = filter::parse_size)]
+    min_size: Option<u64>,
+    /// Skip entries larger than the size
+    #[arg(long, value_parser = filter::parse_size)]
+    max_size: Option<u64>,
+    /// File with one `include`, `exclude`, `min-size` or `max-size` rule per line
+    #[arg(long)]
+    rules: Option<PathBuf>,
+}
+
+impl FilterArgs {
+    /// Canonical form of the rules, the rules file is taken by the hash of its content
+    fn key(&self) -> anyhow::Result<serde_json::Value> {
+        let sorted = |list: &[String]| {
+            let mut list = list.to_vec();
+            list.sort();
+            list.dedup();
+            list
+        };
+        let rules = self
+            .rules
+            .as_deref()
+            .map(|rules| sha256_file(rules).with_context(|| format!("Hash {}", rules.display())))
+            .transpose()?;
+        Ok(serde_json::json!({
+            "include": sorted(&self.include),
+            "exclude": sorted(&self.exclude),
+            "remove_prefix": sorted(&self.remove_prefix),
+            "remove_exts": sorted(&self.remove_exts),
+            "min_size": self.min_size,
+            "max_size": self.max_size,
+            "rules_sha256": rules,
+        }))
+    }
+
+    fn build(&self) -> anyhow::Result<EntryFilter> {
+        let mut filter = EntryFilter::new(&self.include, &self.exclude)?;
+        // The file names are compared case-sensitively like the removal after extraction
+        for prefix in self.remove_prefix.iter().filter(|p| !p.is_empty()) {
+            let re = format!("(?:^|/){}[^/]*$", regex::escape(prefix));
+            filter.exclude(Pattern::Regex(regex::Regex::new(&re)?));
+        }
+        for ext in self.remove_exts.iter().filter(|e| !e.is_empty()) {
+            let re = format!(r"\.{}$", regex::escape(ext.trim_start_matches('.')));
+            filter.exclude(Pattern::Regex(regex::Regex::new(&re)?));
+        }
+        if let Some(size) = self.min_size {
+            filter.set_min_size(size);
+        }
+        if let Some(size) = self.max_size {
+            filter.set_max_size(size);
+        }
+        if let Some(rules) = self.rules.as_deref() {
+            filter.add_rules_file(rules)?;
+        }
+        Ok(filter)
+    }
+}
+
+// Options of `extract` and `extract-all`
+#[derive(clap::Args, Debug)]
+struct ExtractArgs {
+    /// The setup file, a glob like `setups/*.exe` or a directory with setups
+    #[arg(short, long, visible_alias = "setup-glob")]
+    setup: String,
+    /// Receives a directory per setup
+    #[arg(short, long, visible_alias = "dir", short_alias = 'd', default_value = "setup")]
+    out_dir: String,
+    #[arg(short, long, default_value = "4")]
+    threads: usize,
+    /// Keep the tmp dir
+    #[arg(short, long, default_value = "false")]
+    keep_tmp: bool,
+    /// Unpack archives and setups inside the extracted clients
+    #[arg(short, long, default_value = "false")]
+    recursive: bool,
+    #[command(flatten)]
+    filter: FilterArgs,
+    /// Extract all setups again, ignoring the state of previous runs
+    #[arg(long, default_value = "false")]
+    force: bool,
+    /// Content addressed store, which receives the extracted files instead of `out_dir`
+    #[arg(long)]
+    store: Option<String>,
+    /// Output of each setup, the archives hold the client files and manifest like `convert`
+    #[arg(long, value_enum, default_value = "dir")]
+    format: OutputFormat,
+    /// Skip the fingerprint and `manifest.json` of the extracted clients
+    #[arg(long, default_value = "false")]
+    no_manifest: bool,
+}
+
+impl ExtractArgs {
+    /// Options affecting the output, outputs of other options are never taken as complete
+    fn options_key(&self) -> anyhow::Result<String> {
+        let key = serde_json::json!({
+            "filter": self.filter.key()?,
+            "recursive": self.recursive,
+            "format": self.format,
+            "manifest": !self.no_manifest,
+        });
+        Ok(key.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum OutputFormat {
+    /// Directory with the client files
+    Dir,
+    TarZst,
+    #[value(name = "7z")]
+    #[serde(rename = "7z")]
+    SevenZ,
+}
+
+impl OutputFormat {
+    fn archive_ext(self) -> Option<&'static str> {
+        match self {
+            Self::Dir => None,
+            Self::TarZst => Some("tar.zst"),
+            Self::SevenZ => Some("7z"),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+enum Args {
+    /// Extracts a setup file, all setups matching a glob or all setups in a directory
+    Extract(ExtractArgs),
+    /// Same as `extract`
+    ExtractAll(ExtractArgs),
     ListArchives {
         /// The setup file to list
         #[arg(short, long)]
@@ -284,35 +709,199 @@ enum Args {
         /// The patcher file to list
         #[arg(short, long)]
         patcher: String,
+        /// Also write the listing as JSON
+        #[arg(long)]
+        json: Option<String>,
     },
     ListAllPatchers {
         /// The patcher file to list
         #[arg(short, long)]
         patcher_glob: String,
     },
+    Fingerprint {
+        /// The setup file or extracted client directory
+        #[arg(short, long)]
+        path: String,
+    },
+    Diff {
+        /// The old setup file or extracted client directory
+        #[arg(long)]
+        old: String,
+        /// The new setup file or extracted client directory
+        #[arg(long)]
+        new: String,
+        /// Also write the differences as JSON in the shape of a patcher listing
+        #[arg(long)]
+        json: Option<String>,
+    },
+    ValidatePatch {
+        /// The patch file
+        #[arg(short, long)]
+        patch: String,
+        /// The setup file or extracted client directory the patch applies to
+        #[arg(long)]
+        from: String,
+        /// The setup file or extracted client directory of the patched version
+        #[arg(long)]
+        to: String,
+    },
+    Index {
+        /// The catalog database
+        #[arg(long)]
+        db: String,
+        /// The directory with setups and patches
+        #[arg(short, long)]
+        dir: String,
+        /// Extract each setup to detect its region and version
+        #[arg(long, default_value = "false")]
+        fingerprint: bool,
+    },
+    Query {
+        /// The catalog database
+        #[arg(long)]
+        db: String,
+        #[command(subcommand)]
+        query: CatalogQuery,
+    },
+    VersionGraph {
+        /// The directory with setups and patches
+        #[arg(short, long)]
+        dir: String,
+        #[arg(short, long, value_enum, default_value = "json")]
+        format: GraphFormat,
+        /// Output file, the graph is printed if not set
+        #[arg(short, long)]
+        out: Option<String>,
+        /// Extract setups, which have no version in their name, to detect it
+        #[arg(long, default_value = "false")]
+        fingerprint: bool,
+    },
+    /// Mounts a setup, or a client directory with a patch applied, as read-only filesystem
+    #[cfg(feature = "fuse")]
+    Mount {
+        /// The setup file, or the client directory with `--patch`
+        source: String,
+        /// The directory to mount at
+        mountpoint: String,
+        /// Patch to apply on top of the client directory
+        #[arg(long)]
+        patch: Option<String>,
+        /// Directory for the files produced on read, a temporary one if not set. The
+        /// archives of cab and MSI setups are copied into it when mounting
+        #[arg(long)]
+        cache_dir: Option<String>,
+    },
+    /// Serves a local HTTP API to list, extract and patch setups
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+        /// Directory for uploads, extracted clients and patch outputs, the paths of
+        /// requests are resolved inside of it
+        #[arg(short, long, default_value = "serve")]
+        dir: String,
+    },
+    Materialize {
+        /// The store directory
+        #[arg(long)]
+        store: String,
+        /// Client names to rebuild, all clients of the store if empty
+        #[arg(short, long, use_value_delimiter = true, value_delimiter = ',')]
+        client: Vec<String>,
+        #[arg(short, long)]
+        out_dir: String,
+        #[arg(short, long, value_enum, default_value = "hardlink")]
+        mode: LinkMode,
+    },
+    /// Converts a setup into a single .tar.zst or .7z archive with a manifest
+    Convert {
+        /// The setup file
+        #[arg(short, long)]
+        setup: String,
+        /// The archive to write, the format is taken from its extension
+        #[arg(short, long)]
+        out: String,
+    },
+    /// Maps every byte range of a setup and dumps the unknown ones
+    Analyze {
+        /// The setup file
+        #[arg(short, long)]
+        setup: String,
+        /// Directory for the unknown regions and the map
+        #[arg(short, long, default_value = "analyze")]
+        out_dir: String,
+    },
+    /// Re-serialises the container of a setup and compares it with the original
+    RebuildCheck {
+        /// The setup file
+        #[arg(short, long)]
+        setup: String,
+        /// Directory with the extracted entries, a temporary one is used if not set
+        #[arg(long)]
+        entries: Option<String>,
+        /// Also write the rebuilt setup
+        #[arg(short, long)]
+        out: Option<String>,
+    },
+    /// Packs an extracted client into a split zip set, optionally wrapped into a setup
+    Pack {
+        /// The extracted client directory
+        #[arg(short, long)]
+        client: String,
+        #[arg(short, long)]
+        out_dir: String,
+        /// Base name of the volumes and the setup
+        #[arg(short, long, default_value = "Client")]
+        name: String,
+        /// Maximum size of a volume, the central directory in the last one included
+        #[arg(long, value_parser = filter::parse_size, default_value = "650MB")]
+        volume_size: u64,
+        /// Wraps the volumes into a NFO300 setup with the PE stub of this executable. The
+        /// setup is read by this tool, the original launcher may reject its entry checksums
+        #[arg(long, value_name = "STUB")]
+        nfo300: Option<String>,
+    },
+}
+
+/// Rebuilds the setup from its extracted entries, which are extracted first if not given
+fn rebuild_check(setup: &Path, entries: Option<&Path>, out: Option<&Path>) -> anyhow::Result<()> {
+    let mut setup_opt = SetupOpt::open(setup)?;
+    let report = match entries {
+        Some(entries) => rebuild::rebuild_check(&mut setup_opt, entries, out),
+        None => {
+            let tmp_dir = unique_temp_dir("mssetuprebuild");
+            std::fs::create_dir_all(&tmp_dir)?;
+            let res = setup_opt
+                .extract_entries(&tmp_dir, &NoProgress, &CancelToken::new())
+                .and_then(|_| rebuild::rebuild_check(&mut setup_opt, &tmp_dir, out));
+            let _ = std::fs::remove_dir_all(&tmp_dir);
+            res
+        }
+    }?;
+
+    report.log();
+    if !report.is_identical() {
+        anyhow::bail!("Rebuilt setup differs");
+    }
+    log::info!("Rebuilt setup is identical: {}", setup.display());
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
-    simplelog::TermLogger::init(
+    // Log lines are printed above the progress bars
+    let multi = MultiProgress::new();
+    let logger = simplelog::TermLogger::new(
         simplelog::LevelFilter::Info,
         simplelog::Config::default(),
         simplelog::TerminalMode::Mixed,
         simplelog::ColorChoice::Auto,
-    )?;
+    );
+    LogWrapper::new(multi.clone(), logger).try_init()?;
+    log::set_max_level(log::LevelFilter::Info);
 
     let args = Args::parse();
 
     match args {
-        Args::Extract {
-            setup,
-            dir,
-            keep_tmp,
-        } => {
-            let mut setup = SetupOpt::open(&setup)?;
-            if let Err(err) = setup.extract_and_report(0, &[], &[], Path::new(&dir), keep_tmp) {
-                log::error!("Error: {err} for: {}", setup.path().display());
-            }
-        }
+        Args::Extract(args) | Args::ExtractAll(args) => extract(args, &multi)?,
         Args::ListArchives { setup } => {
             let mut setup = SetupOpt::open(&setup)?;
             setup.list_archives()?;
@@ -324,51 +913,149 @@ fn main() -> anyhow::Result<()> {
                 setup.list_archives()?;
             }
         }
-        Args::ExtractAll {
-            setup_glob,
-            remove_prefix,
-            remove_exts,
-            out_dir,
-            threads,
-            keep_tmp
-        } => {
-            let _ = std::fs::create_dir_all(&out_dir);
-            let paths = glob::glob(&setup_glob)?.collect::<Result<Vec<_>, _>>()?;
-            rayon::ThreadPoolBuilder::new()
-                .num_threads(threads)
-                .build_global()
-                .unwrap();
-            paths
-                .iter()
-                .enumerate()
-                .par_bridge()
-                .for_each(|(id, path)| {
-                    if let Err(err) = SetupOpt::open(path).and_then(|mut setup| {
-                        setup.extract_and_report(
-                            id,
-                            &remove_prefix,
-                            &remove_exts,
-                            Path::new(&out_dir),
-                            keep_tmp
-                        )
-                    }) {
-                        log::error!("Error: {} for: {}", err, path.display());
-                    }
-                });
-        },
-        Args::ListPatcher { patcher } => {
-            if let Err(err) = list_patcher(&patcher) {
+        Args::ListPatcher { patcher, json } => {
+            if let Err(err) = list_patcher(&patcher, json.as_deref().map(Path::new)) {
                 log::error!("Error: {err} for: {}", patcher);
             }
         },
         Args::ListAllPatchers { patcher_glob } => {
             let paths = glob::glob(&patcher_glob)?.collect::<Result<Vec<_>, _>>()?;
             for path in paths {
-                if let Err(err) = list_patcher(&path) {
+                if let Err(err) = list_patcher(&path, None) {
                     log::error!("Error: {err} for: {}", path.display());
                 }
             }
         }
+        Args::Fingerprint { path } => {
+            fingerprint(Path::new(&path)).with_context(|| format!("Fingerprint: {path}"))?;
+        }
+        Args::Diff { old, new, json } => {
+            let json = json.as_deref().map(Path::new);
+            diff(Path::new(&old), Path::new(&new), json)
+                .with_context(|| format!("Diff: {old} -> {new}"))?;
+        }
+        Args::ValidatePatch { patch, from, to } => {
+            validate_patch(Path::new(&patch), Path::new(&from), Path::new(&to))
+                .with_context(|| format!("Validate patch: {patch}"))?;
+        }
+        Args::Index {
+            db,
+            dir,
+            fingerprint,
+        } => {
+            let mut catalog = Catalog::open(&db)?;
+            index(&mut catalog, Path::new(&dir), fingerprint)?;
+        }
+        Args::Query { db, query: q } => {
+            let catalog = Catalog::open(&db)?;
+            query(&catalog, q)?;
+        }
+        Args::VersionGraph {
+            dir,
+            format,
+            out,
+            fingerprint,
+        } => {
+            let graph = version_graph(Path::new(&dir), fingerprint)?;
+            graph.log_report();
+            let data = match format {
+                GraphFormat::Dot => graph.to_dot(),
+                GraphFormat::Json => serde_json::to_string_pretty(&serde_json::json!({
+                    "graph": graph,
+                    "report": graph.report(),
+                }))?,
+            };
+            match out {
+                Some(out) => std::fs::write(out, data)?,
+                None => println!("{data}"),
+            }
+        }
+        #[cfg(feature = "fuse")]
+        Args::Mount {
+            source,
+            mountpoint,
+            patch,
+            cache_dir,
+        } => {
+            let tmp_cache = cache_dir.is_none();
+            let cache_dir = cache_dir
+                .map(PathBuf::from)
+                .unwrap_or_else(|| unique_temp_dir("mssetupmount"));
+            let source = Path::new(&source);
+            let fs = match patch {
+                Some(patch) => mount::MountFs::from_patch(source, Path::new(&patch), &cache_dir)?,
+                None => mount::MountFs::from_setup(source, &cache_dir)?,
+            };
+            let res = mount::mount(fs, Path::new(&mountpoint));
+            if tmp_cache {
+                let _ = std::fs::remove_dir_all(&cache_dir);
+            }
+            res?;
+        }
+        Args::Serve { addr, dir } => serve::serve(&addr, Path::new(&dir))?,
+        Args::Materialize {
+            store,
+            client,
+            out_dir,
+            mode,
+        } => {
+            let store = Store::open(&store)?;
+            let clients = if client.is_empty() {
+                store.clients()?
+            } else {
+                client
+            };
+            for name in clients {
+                if let Err(err) = store.materialize(&name, &Path::new(&out_dir).join(&name), mode) {
+                    log::error!("Error: {err} for: {name}");
+                }
+            }
+        }
+        Args::Convert { setup, out } => {
+            let mut setup = SetupOpt::open(&setup)?;
+            convert::convert_setup(&mut setup, Path::new(&out), &CancelToken::new())?;
+        }
+        Args::Analyze { setup, out_dir } => {
+            let mut setup = SetupOpt::open(&setup)?;
+            let mut map = analyze::map_setup(&mut setup)?;
+            map.dump(Path::new(&out_dir))?;
+            map.log();
+        }
+        Args::RebuildCheck {
+            setup,
+            entries,
+            out,
+        } => {
+            let (entries, out) = (entries.as_deref().map(Path::new), out.as_deref().map(Path::new));
+            rebuild_check(Path::new(&setup), entries, out)
+                .with_context(|| format!("Rebuild check: {setup}"))?;
+        }
+        Args::Pack {
+            client,
+            out_dir,
+            name,
+            volume_size,
+            nfo300,
+        } => {
+            let cancel = CancelToken::ctrl_c()?;
+            let (client, out_dir) = (Path::new(&client), Path::new(&out_dir));
+            match nfo300 {
+                Some(stub) => {
+                    let setup = pack::pack_nfo300(
+                        client,
+                        out_dir,
+                        &name,
+                        volume_size,
+                        Path::new(&stub),
+                        &cancel,
+                    )?;
+                    log::info!("Setup: {}", setup.display());
+                }
+                None => {
+                    pack::pack_split_zip(client, out_dir, &name, volume_size, &cancel)?;
+                }
+            }
+        }
     }
 
     Ok(())
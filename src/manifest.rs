@@ -0,0 +1,99 @@
+use std::{collections::BTreeMap, fs::File, io::BufWriter, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{authenticode::Signature, fingerprint::Fingerprint, util::get_all_nested_files};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const REPORT_FILE: &str = "report.txt";
+
+/// Whether the relative path is the report or manifest written next to the client files
+pub fn is_output_file(rel: &str) -> bool {
+    rel == MANIFEST_FILE || rel == REPORT_FILE
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestFile {
+    /// Path relative to the client directory with `/` as separator
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<String>,
+    /// Nested archive the file was unpacked from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// Manifest, which is written into the output directory of an extracted setup
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExtractManifest {
+    pub setup: String,
+    pub fingerprint: Option<Fingerprint>,
+    /// Authenticode signature of the setup stub
+    pub signature: Option<Signature>,
+    pub files: Vec<ManifestFile>,
+}
+
+/// The original setup of a converted archive
+#[derive(Debug, Clone, Serialize)]
+pub struct SetupMeta {
+    pub setup: String,
+    pub format: String,
+    pub size: u64,
+    pub sha256: String,
+    /// SHA-256 of the PE stub without the overlay
+    pub stub_sha256: Option<String>,
+    pub signature: Option<Signature>,
+}
+
+/// Manifest, which is stored as the last entry of a converted archive
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveManifest {
+    pub setup: SetupMeta,
+    pub files: Vec<ManifestFile>,
+}
+
+impl ExtractManifest {
+    /// Collects all files of the extracted client, skipping the report and manifest itself
+    pub fn from_dir(setup: &Path, dir: &Path) -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        for path in get_all_nested_files(dir)? {
+            let rel = path.strip_prefix(dir)?.to_string_lossy().replace('\\', "/");
+            if is_output_file(&rel) {
+                continue;
+            }
+
+            let meta = path.metadata()?;
+            files.push(ManifestFile {
+                path: rel,
+                size: meta.len(),
+                modified: meta
+                    .modified()
+                    .ok()
+                    .map(|t| DateTime::<Utc>::from(t).to_rfc3339()),
+                source: None,
+            });
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Self {
+            setup: setup.display().to_string(),
+            fingerprint: None,
+            signature: None,
+            files,
+        })
+    }
+
+    /// Records the nested archives the files were unpacked from
+    pub fn set_sources(&mut self, sources: &BTreeMap<String, String>) {
+        for file in self.files.iter_mut() {
+            file.source = sources.get(&file.path).cloned();
+        }
+    }
+
+    pub fn write(&self, dir: &Path) -> anyhow::Result<()> {
+        let file = BufWriter::new(File::create(dir.join(MANIFEST_FILE))?);
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fs::File,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request, FUSE_ROOT_ID,
+};
+use crate::{
+    cab::CabSet,
+    cancel::CancelToken,
+    extract,
+    filter::{EntryFilter, Pattern},
+    patched::PatchedView,
+    progress::NoProgress,
+    util::get_all_nested_files,
+    ClientArchives, SetupOpt, SetupZip,
+};
+
+const TTL: Duration = Duration::from_secs(60);
+
+/// Where the data of a file comes from
+enum Source {
+    /// Member of the inner zip, decompressed on the first read
+    Zip(usize),
+    /// File of the cabinet set, extracted on the first read
+    Cab(String),
+    /// Unchanged file of the old client
+    Old(PathBuf),
+    /// New or modified file of the patch, read through the patched view
+    Patched(String),
+}
+
+enum Node {
+    Dir(BTreeMap<String, u64>),
+    File { size: u64, source: Source },
+}
+
+/// Read-only tree of a setup or of a patched client. The contents of a file are produced
+/// on its first read and kept in the cache dir, nothing else is written out
+pub struct MountFs {
+    /// Nodes by their inode - 1
+    nodes: Vec<Node>,
+    mtime: SystemTime,
+    cache_dir: PathBuf,
+    zip: Option<SetupZip>,
+    cabs: Vec<PathBuf>,
+    view: Option<PatchedView>,
+    open_files: HashMap<u64, File>,
+}
+
+impl MountFs {
+    fn new(cache_dir: &Path, mtime: SystemTime) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(cache_dir).context("Create cache dir")?;
+        Ok(Self {
+            nodes: vec![Node::Dir(BTreeMap::new())],
+            mtime,
+            cache_dir: cache_dir.to_path_buf(),
+            zip: None,
+            cabs: Vec::new(),
+            view: None,
+            open_files: HashMap::new(),
+        })
+    }
+
+    /// Exposes the client of a setup. The entries of zip based setups are read in place,
+    /// cabinets and MSIs are copied whole into the cache dir up front since 7z needs
+    /// them as files, so mounting those takes as long as copying the setup
+    pub fn from_setup(path: &Path, cache_dir: &Path) -> anyhow::Result<Self> {
+        let mtime = path.metadata()?.modified()?;
+        let mut fs = Self::new(cache_dir, mtime)?;
+        let mut setup = SetupOpt::open(path)?;
+        let spool_dir = cache_dir.join("archives");
+        match setup.client_archives(&spool_dir, &CancelToken::new())? {
+            ClientArchives::Zip(mut archive) => {
+                for ix in 0..archive.len() {
+                    let file = archive.by_index_raw(ix)?;
+                    if !file.is_dir() {
+                        fs.add_file(file.name(), file.size(), Source::Zip(ix));
+                    }
+                }
+                fs.zip = Some(archive);
+            }
+            ClientArchives::Cabs(cabs) => {
+                for file in CabSet::open(&cabs)?.files {
+                    let (path, size) = (file.entry.path(), file.entry.size());
+                    fs.add_file(&path, size, Source::Cab(path.clone()));
+                }
+                fs.cabs = cabs;
+            }
+        }
+        Ok(fs)
+    }
+
+    /// Exposes the client in `client` with the patch applied
+    pub fn from_patch(client: &Path, patch: &Path, cache_dir: &Path) -> anyhow::Result<Self> {
+        let mtime = patch.metadata()?.modified()?;
+        let mut fs = Self::new(cache_dir, mtime)?;
+        for path in get_all_nested_files(client)? {
+            let rel = path
+                .strip_prefix(client)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            fs.add_file(&rel, path.metadata()?.len(), Source::Old(path));
+        }
+
+        let view = PatchedView::open(patch, client, &cache_dir.join("view"))?;
+        for name in view.removed() {
+            fs.remove_file(name);
+        }
+        for (name, file) in view.files() {
+            fs.add_file(name, file.size, Source::Patched(name.clone()));
+        }
+        fs.view = Some(view);
+        Ok(fs)
+    }
+
+    fn add_file(&mut self, path: &str, size: u64, source: Source) {
+        let path = path.replace('\\', "/");
+        let mut parts = path
+            .split('/')
+            .filter(|p| !p.is_empty())
+            .collect::<Vec<_>>();
+        let Some(name) = parts.pop() else {
+            return;
+        };
+        let mut dir = FUSE_ROOT_ID;
+        for part in parts {
+            dir = match self.child(dir, part) {
+                Some(child) if matches!(self.node(child), Some(Node::Dir(_))) => child,
+                Some(_) => {
+                    log::warn!("Not a directory: {path}");
+                    return;
+                }
+                None => self.insert(dir, part, Node::Dir(BTreeMap::new())),
+            };
+        }
+
+        // Files of the old client are replaced by the patched ones
+        match self.child(dir, name) {
+            Some(child) => *self.node_mut(child) = Node::File { size, source },
+            None => {
+                self.insert(dir, name, Node::File { size, source });
+            }
+        }
+    }
+
+    fn insert(&mut self, dir: u64, name: &str, node: Node) -> u64 {
+        let ino = self.nodes.len() as u64 + 1;
+        self.nodes.push(node);
+        if let Node::Dir(children) = self.node_mut(dir) {
+            children.insert(name.to_string(), ino);
+        }
+        ino
+    }
+
+    fn child(&self, dir: u64, name: &str) -> Option<u64> {
+        match self.node(dir)? {
+            Node::Dir(children) => children.get(name).copied(),
+            Node::File { .. } => None,
+        }
+    }
+
+    fn remove_file(&mut self, path: &str) {
+        let path = path.replace('\\', "/");
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+        if let Some(Node::Dir(children)) = self.lookup_path(parent).map(|ino| self.node_mut(ino)) {
+            children.remove(name);
+        }
+    }
+
+    fn lookup_path(&self, path: &str) -> Option<u64> {
+        path.split('/')
+            .filter(|p| !p.is_empty())
+            .try_fold(FUSE_ROOT_ID, |dir, name| self.child(dir, name))
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get((ino as usize).checked_sub(1)?)
+    }
+
+    fn node_mut(&mut self, ino: u64) -> &mut Node {
+        &mut self.nodes[ino as usize - 1]
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, size, perm) = match self.node(ino)? {
+            Node::Dir(_) => (FileType::Directory, 0, 0o555),
+            Node::File { size, .. } => (FileType::RegularFile, *size, 0o444),
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            crtime: self.mtime,
+            kind,
+            perm,
+            nlink: 1,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    /// Reads up to `size` bytes of a file at `offset`
+    fn read_data(&mut self, ino: u64, offset: u64, size: usize) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0; size];
+        if let Some(Node::File {
+            source: Source::Patched(name),
+            ..
+        }) = self.node(ino)
+        {
+            let name = name.clone();
+            let view = self.view.as_mut().context("No patch")?;
+            let n = view.read_at(&name, offset, &mut buf)?;
+            buf.truncate(n);
+            return Ok(buf);
+        }
+
+        let file = self.open_file(ino)?;
+        let mut n = 0;
+        while n < buf.len() {
+            match file.read_at(&mut buf[n..], offset + n as u64)? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    /// Opens the data of a file, which is produced in the cache dir on the first read
+    fn open_file(&mut self, ino: u64) -> anyhow::Result<&File> {
+        if !self.open_files.contains_key(&ino) {
+            let file = self.produce(ino)?;
+            self.open_files.insert(ino, file);
+        }
+        Ok(&self.open_files[&ino])
+    }
+
+    fn produce(&mut self, ino: u64) -> anyhow::Result<File> {
+        let Some(Node::File { source, .. }) = self.node(ino) else {
+            anyhow::bail!("Not a file: {ino}");
+        };
+        match source {
+            Source::Old(path) => Ok(File::open(path)?),
+            Source::Zip(ix) => {
+                let ix = *ix;
+                let archive = self.zip.as_mut().context("No zip")?;
+                let path = self.cache_dir.join("files").join(ino.to_string());
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                std::io::copy(&mut archive.by_index(ix)?, &mut File::create(&path)?)?;
+                Ok(File::open(path)?)
+            }
+            Source::Cab(name) => {
+                let out_dir = self.cache_dir.join("cab");
+                let mut filter = EntryFilter::default();
+                filter.include(Pattern::parse(&format!("regex:^{}$", regex::escape(name)))?);
+                let all = self.cabs.clone();
+                let path = out_dir.join(name);
+                extract::extract_cab_split(
+                    all,
+                    &out_dir,
+                    &filter,
+                    &NoProgress,
+                    &CancelToken::new(),
+                )?;
+                Ok(File::open(path)?)
+            }
+            Source::Patched(_) => anyhow::bail!("Patched files are read through the view"),
+        }
+    }
+}
+
+impl Filesystem for MountFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let child = name.to_str().and_then(|name| self.child(parent, name));
+        match child.and_then(|ino| self.attr(ino)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_data(ino, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(err) => {
+                log::error!("Read failed for inode {ino}: {err:#}");
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(Node::Dir(children)) = self.node(ino) else {
+            reply.error(libc::ENOTDIR);
+            return;
+        };
+        let dots = [
+            (ino, FileType::Directory, "."),
+            (ino, FileType::Directory, ".."),
+        ];
+        let entries = children.iter().map(|(name, &child)| {
+            let kind = match self.node(child) {
+                Some(Node::Dir(_)) => FileType::Directory,
+                _ => FileType::RegularFile,
+            };
+            (child, kind, name.as_str())
+        });
+        for (ix, (ino, kind, name)) in dots.into_iter().chain(entries).enumerate() {
+            if (ix as i64) < offset {
+                continue;
+            }
+            if reply.add(ino, ix as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mounts the tree read-only until it is unmounted
+pub fn mount(fs: MountFs, mountpoint: &Path) -> anyhow::Result<()> {
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("mssetup".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    log::info!("Mounted at {}, unmount to stop", mountpoint.display());
+    fuser::mount2(fs, mountpoint, &options).context("Mount")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        pack::tests::{nfo300_setup, split_setups},
+        patcher::tests::sound_patch,
+        util::unique_temp_dir,
+    };
+
+    use super::*;
+
+    #[test]
+    fn setup_tree() {
+        let dir = unique_temp_dir("mssetup_mount_setup_test");
+        let files: [(&str, &[u8]); 2] = [("Data/Skill.wz", b"skill"), ("Sound.wz", b"sound")];
+        let setup_path = nfo300_setup(&dir, &files);
+
+        let mut fs = MountFs::from_setup(&setup_path, &dir.join("cache")).unwrap();
+        let skill = fs.lookup_path("Data/Skill.wz").unwrap();
+        assert_eq!(fs.attr(skill).unwrap().size, 5);
+        assert_eq!(fs.read_data(skill, 1, 16).unwrap(), b"kill");
+        let sound = fs.lookup_path("Sound.wz").unwrap();
+        assert_eq!(fs.read_data(sound, 0, 16).unwrap(), b"sound");
+        assert!(fs.lookup_path("Data/Map.wz").is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn split_setup_tree() {
+        let dir = unique_temp_dir("mssetup_mount_split_test");
+        let setups = split_setups(&dir);
+
+        // The files of the joined volumes are listed by their path
+        let mut fs = MountFs::from_setup(&setups.nfo300, &dir.join("cache")).unwrap();
+        let names = |fs: &MountFs, path: &str| match fs.node(fs.lookup_path(path).unwrap()) {
+            Some(Node::Dir(children)) => children.keys().cloned().collect::<Vec<_>>(),
+            _ => panic!("Not a dir: {path}"),
+        };
+        assert_eq!(names(&fs, ""), ["Data", "Sound.wz"]);
+        assert_eq!(names(&fs, "Data"), ["Map.wz", "Skill.wz"]);
+        let map = fs.lookup_path("Data/Map.wz").unwrap();
+        assert_eq!(fs.attr(map).unwrap().kind, FileType::RegularFile);
+        assert!(fs.read_data(map, 1000, 100_000).unwrap() == setups.map[1000..101_000]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn patched_tree() {
+        let dir = unique_temp_dir("mssetup_mount_test");
+        let client = dir.join("client");
+        std::fs::write(dir.join("84.patch"), sound_patch(&client)).unwrap();
+        std::fs::create_dir_all(client.join("Data")).unwrap();
+        std::fs::write(client.join("Data/Map.wz"), b"map").unwrap();
+
+        let mut fs =
+            MountFs::from_patch(&client, &dir.join("84.patch"), &dir.join("cache")).unwrap();
+        let sound = fs.lookup_path("Sound.wz").unwrap();
+        assert_eq!(fs.attr(sound).unwrap().size, 8);
+        let skill = fs.lookup_path("Data/Skill.wz").unwrap();
+        assert_eq!(fs.attr(skill).unwrap().kind, FileType::RegularFile);
+        assert!(fs.lookup_path("Data/Map.wz").is_some());
+
+        assert_eq!(fs.read_data(sound, 0, 16).unwrap(), b"sound+++");
+        // The old client is left as it is
+        assert_eq!(std::fs::read(client.join("Sound.wz")).unwrap(), b"sound");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
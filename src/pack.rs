@@ -0,0 +1,634 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    cancel::{CancelReader, CancelToken},
+    pe::{PeFile, IMAGE_DIRECTORY_ENTRY_SECURITY},
+    setup::nfo300::{self, Nfo300Entry},
+    util::{get_all_nested_files, unique_name, unique_temp_dir, SetupFormat},
+};
+
+const CD_SIG: u32 = 0x02014b50;
+const EOCD_SIG: u32 = 0x06054b50;
+const LOCAL_SIG: u32 = 0x04034b50;
+/// Spanning signature at the start of the first volume of a split set
+const SPAN_SIG: &[u8] = b"PK\x07\x08";
+const EOCD_LEN: usize = 22;
+/// Smallest volume, so local headers always fit into one
+pub const MIN_VOLUME_SIZE: u64 = 64 * 1024;
+/// The reader of `Nfo300Setup` only looks at this many bytes for the entry lines
+const NFO300_HEADER_LIMIT: usize = 1000;
+
+/// Entry of the central directory
+#[derive(Debug, Clone, Copy)]
+struct CdEntry {
+    /// Position of the record in the central directory
+    pos: usize,
+    disk: u16,
+    offset: u32,
+}
+
+fn u16_at(data: &[u8], pos: usize) -> anyhow::Result<u16> {
+    let b = data.get(pos..pos + 2).context("Truncated zip record")?;
+    Ok(u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> anyhow::Result<u32> {
+    let b = data.get(pos..pos + 4).context("Truncated zip record")?;
+    Ok(u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn cd_entries(cd: &[u8]) -> anyhow::Result<Vec<CdEntry>> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < cd.len() {
+        if u32_at(cd, pos)? != CD_SIG {
+            anyhow::bail!("Invalid central directory record at {pos:#x}");
+        }
+        let offset = u32_at(cd, pos + 42)?;
+        if offset == u32::MAX {
+            anyhow::bail!("Zip64 archives can't be split");
+        }
+        entries.push(CdEntry {
+            pos,
+            disk: u16_at(cd, pos + 34)?,
+            offset,
+        });
+        let var = u16_at(cd, pos + 28)? as usize
+            + u16_at(cd, pos + 30)? as usize
+            + u16_at(cd, pos + 32)? as usize;
+        pos += 46 + var;
+    }
+    Ok(entries)
+}
+
+fn zip_time(time: SystemTime) -> Option<zip::DateTime> {
+    let dt: DateTime<Utc> = time.into();
+    zip::DateTime::from_date_and_time(
+        dt.year().try_into().ok()?,
+        dt.month() as u8,
+        dt.day() as u8,
+        dt.hour() as u8,
+        dt.minute() as u8,
+        dt.second() as u8,
+    )
+    .ok()
+}
+
+/// Writes the files of the client into a single zip, paths are relative to the client
+fn write_zip(client: &Path, path: &Path, cancel: &CancelToken) -> anyhow::Result<()> {
+    let mut files = get_all_nested_files(client)?;
+    files.sort();
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?));
+    for file in files {
+        cancel.check()?;
+        let name = file
+            .strip_prefix(client)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        let meta = file.metadata()?;
+        let mut options =
+            SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        if let Some(time) = meta.modified().ok().and_then(zip_time) {
+            options = options.last_modified_time(time);
+        }
+        zip.start_file(name.as_str(), options)?;
+        let mut rdr = CancelReader::new(BufReader::new(File::open(&file)?), cancel);
+        io::copy(&mut rdr, &mut zip).with_context(|| format!("Pack {name}"))?;
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// Start of each volume, a volume never splits a local header. The `tail` with the
+/// central directory is kept whole in the last volume and the first one starts with the
+/// spanning signature, both count towards the volume size
+fn volume_starts(
+    rdr: &mut (impl Read + Seek),
+    entries: &[CdEntry],
+    data_end: u64,
+    tail: u64,
+    volume_size: u64,
+) -> anyhow::Result<Vec<u64>> {
+    if tail > volume_size {
+        anyhow::bail!("The central directory doesn't fit into a volume: {tail} bytes");
+    }
+    let mut headers = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let offset = entry.offset as u64;
+        let mut hdr = [0u8; 30];
+        rdr.seek(SeekFrom::Start(offset))?;
+        rdr.read_exact(&mut hdr)?;
+        if u32_at(&hdr, 0)? != LOCAL_SIG {
+            anyhow::bail!("Invalid local header at {offset:#x}");
+        }
+        let len = 30 + u16_at(&hdr, 26)? as u64 + u16_at(&hdr, 28)? as u64;
+        headers.push(offset..offset + len);
+    }
+
+    let mut starts = vec![0];
+    let mut start = 0;
+    let mut capacity = volume_size - SPAN_SIG.len() as u64;
+    while data_end - start + tail > capacity {
+        let mut next = data_end.min(start + capacity);
+        if let Some(hdr) = headers
+            .iter()
+            .find(|h| h.contains(&next) && h.start > start)
+        {
+            next = hdr.start;
+        }
+        if next == start {
+            anyhow::bail!("The volume size is too small");
+        }
+        starts.push(next);
+        start = next;
+        capacity = volume_size;
+    }
+    Ok(starts)
+}
+
+/// Name of a volume, the last one is the `.zip` and the others count up from `.z01`
+fn volume_name(name: &str, ix: usize, count: usize) -> String {
+    if ix + 1 == count {
+        format!("{name}.zip")
+    } else {
+        format!("{name}.z{:02}", ix + 1)
+    }
+}
+
+/// Packs the client into a split zip set in `out_dir` with volumes of at most
+/// `volume_size` bytes. Returns the volumes in the order they are joined
+pub fn pack_split_zip(
+    client: &Path,
+    out_dir: &Path,
+    name: &str,
+    volume_size: u64,
+    cancel: &CancelToken,
+) -> anyhow::Result<Vec<PathBuf>> {
+    if volume_size < MIN_VOLUME_SIZE {
+        anyhow::bail!("The volume size must be at least {MIN_VOLUME_SIZE} bytes");
+    }
+    std::fs::create_dir_all(out_dir)?;
+    let tmp = out_dir.join(unique_name(&format!("{name}.pack")));
+    let res = write_zip(client, &tmp, cancel).and_then(|_| {
+        let mut rdr = BufReader::new(File::open(&tmp)?);
+        split_zip(&mut rdr, out_dir, name, volume_size, cancel)
+    });
+    let _ = std::fs::remove_file(&tmp);
+    res
+}
+
+fn split_zip(
+    rdr: &mut (impl Read + Seek),
+    out_dir: &Path,
+    name: &str,
+    volume_size: u64,
+    cancel: &CancelToken,
+) -> anyhow::Result<Vec<PathBuf>> {
+    // The archive is written without a comment, so the end record is at the very end
+    let len = rdr.seek(SeekFrom::End(0))?;
+    let mut eocd = [0u8; EOCD_LEN];
+    rdr.seek(SeekFrom::End(-(EOCD_LEN as i64)))?;
+    rdr.read_exact(&mut eocd)?;
+    if u32_at(&eocd, 0)? != EOCD_SIG {
+        anyhow::bail!("No end of central directory record");
+    }
+    let cd_size = u32_at(&eocd, 12)? as u64;
+    let cd_offset = u32_at(&eocd, 16)? as u64;
+    if cd_offset == u32::MAX as u64 || cd_offset + cd_size + EOCD_LEN as u64 != len {
+        anyhow::bail!("Zip64 archives can't be split");
+    }
+
+    let mut cd = vec![0u8; cd_size as usize];
+    rdr.seek(SeekFrom::Start(cd_offset))?;
+    rdr.read_exact(&mut cd)?;
+    let entries = cd_entries(&cd)?;
+    let tail = cd_size + EOCD_LEN as u64;
+    let starts = volume_starts(rdr, &entries, cd_offset, tail, volume_size)?;
+    let last = starts.len() - 1;
+    if last > u16::MAX as usize {
+        anyhow::bail!("Too many volumes: {}", starts.len());
+    }
+    // A single volume is a plain zip without the spanning signature
+    let sig_len = if last > 0 { SPAN_SIG.len() as u64 } else { 0 };
+
+    // Offsets of the local headers are relative to their volume, the signature included
+    for entry in &entries {
+        if entry.disk != 0 {
+            anyhow::bail!("The archive is already split");
+        }
+        let offset = entry.offset as u64;
+        let disk = starts.partition_point(|&start| start <= offset) - 1;
+        let shift = if disk == 0 { sig_len } else { 0 };
+        let rel = (offset - starts[disk] + shift) as u32;
+        cd[entry.pos + 34..entry.pos + 36].copy_from_slice(&(disk as u16).to_le_bytes());
+        cd[entry.pos + 42..entry.pos + 46].copy_from_slice(&rel.to_le_bytes());
+    }
+    let disk = (last as u16).to_le_bytes();
+    eocd[4..6].copy_from_slice(&disk);
+    eocd[6..8].copy_from_slice(&disk);
+    eocd[8..10].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    let rel_cd = (cd_offset - starts[last]) as u32;
+    eocd[16..20].copy_from_slice(&rel_cd.to_le_bytes());
+
+    let mut volumes = Vec::with_capacity(starts.len());
+    for (ix, &start) in starts.iter().enumerate() {
+        let end = starts.get(ix + 1).copied().unwrap_or(cd_offset);
+        let path = out_dir.join(volume_name(name, ix, starts.len()));
+        let mut out = BufWriter::new(File::create(&path)?);
+        if ix == 0 && last > 0 {
+            out.write_all(SPAN_SIG)?;
+        }
+        rdr.seek(SeekFrom::Start(start))?;
+        let mut data = CancelReader::new(rdr.by_ref().take(end - start), cancel);
+        io::copy(&mut data, &mut out).with_context(|| format!("Write {}", path.display()))?;
+        if ix == last {
+            out.write_all(&cd)?;
+            out.write_all(&eocd)?;
+        }
+        out.flush()?;
+        log::info!("Volume: {}", path.display());
+        volumes.push(path);
+    }
+    Ok(volumes)
+}
+
+/// Head of the stub, which is kept in front of the container. That's the PE image or,
+/// for a NFO300 setup, everything before its container including the padding
+fn stub_head(stub: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut rdr = BufReader::new(File::open(stub)?);
+    let pe = PeFile::parse(&mut rdr).context("Invalid PE stub")?;
+    let len = match SetupFormat::from_reader(&mut rdr) {
+        Ok(SetupFormat::NFO300(offset)) => offset,
+        _ => pe.sections_end(),
+    };
+    let mut head = vec![0u8; len as usize];
+    rdr.seek(SeekFrom::Start(0))?;
+    rdr.read_exact(&mut head).context("Truncated stub")?;
+
+    // The certificate table isn't copied, so its directory entry is cleared
+    if pe.certificate_table().is_some() {
+        let dir = pe.data_dirs_offset as usize + IMAGE_DIRECTORY_ENTRY_SECURITY * 8;
+        head.get_mut(dir..dir + 8)
+            .context("Security directory outside of the stub")?
+            .fill(0);
+    }
+    Ok(head)
+}
+
+/// Writes a setup with the PE stub of `stub` and a NFO300 container of the volumes,
+/// which `Nfo300Setup` reads back in the same order. `Nfo300Setup` doesn't check the
+/// entry checksums, so it's a plain CRC-32 here and the original launcher may reject it
+pub fn write_nfo300(stub: &Path, volumes: &[PathBuf], out: &Path) -> anyhow::Result<()> {
+    const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    let mut entries = Vec::with_capacity(volumes.len());
+    for volume in volumes {
+        let name = volume
+            .file_name()
+            .and_then(|n| n.to_str())
+            .context("Invalid volume name")?;
+        let size = i32::try_from(volume.metadata()?.len())
+            .with_context(|| format!("Volume too large for NFO300: {name}"))?;
+        let mut digest = CRC.digest();
+        let mut rdr = BufReader::new(File::open(volume)?);
+        loop {
+            let buf = rdr.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            digest.update(buf);
+            let n = buf.len();
+            rdr.consume(n);
+        }
+        entries.push(Nfo300Entry {
+            name: name.to_string(),
+            size,
+            checksum: digest.finalize() as i32,
+            offset: 0,
+        });
+    }
+    let header = nfo300::header_bytes(&entries);
+    if header.len() >= NFO300_HEADER_LIMIT {
+        anyhow::bail!("Too many volumes for the NFO300 header: {}", volumes.len());
+    }
+
+    let mut file = BufWriter::new(File::create(out)?);
+    file.write_all(&stub_head(stub)?)?;
+    file.write_all(&header)?;
+    for volume in volumes {
+        io::copy(&mut File::open(volume)?, &mut file)?;
+    }
+    file.flush()?;
+    log::info!("Setup: {}", out.display());
+    Ok(())
+}
+
+/// Packs the client into a NFO300 setup `<name>.exe` in `out_dir` with the PE stub of
+/// `stub`, the volumes are only kept until the setup is written
+pub fn pack_nfo300(
+    client: &Path,
+    out_dir: &Path,
+    name: &str,
+    volume_size: u64,
+    stub: &Path,
+    cancel: &CancelToken,
+) -> anyhow::Result<PathBuf> {
+    let tmp_dir = unique_temp_dir("mssetuppack");
+    let out = out_dir.join(format!("{name}.exe"));
+    let res = pack_split_zip(client, &tmp_dir, name, volume_size, cancel).and_then(|volumes| {
+        std::fs::create_dir_all(out_dir)?;
+        write_nfo300(stub, &volumes, &out)
+    });
+    let _ = std::fs::remove_dir_all(&tmp_dir);
+    res.map(|()| out)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::{
+        extract::{extract_zip_split, open_zip_split},
+        filter::EntryFilter,
+        progress::NoProgress,
+        setup::{is::tests::is_setup, Setup},
+        util::unique_temp_dir,
+        SetupOpt,
+    };
+
+    use super::*;
+
+    /// PE with a single section of 0x200 bytes at 0x200
+    pub(crate) fn stub() -> Vec<u8> {
+        let mut data = vec![0u8; 0x40];
+        data[..2].copy_from_slice(b"MZ");
+        data[0x3C..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        data.extend(b"PE\0\0");
+        let mut coff = [0u8; 20];
+        coff[2..4].copy_from_slice(&1u16.to_le_bytes());
+        coff[16..18].copy_from_slice(&224u16.to_le_bytes());
+        data.extend(coff);
+        let mut opt = [0u8; 224];
+        opt[..2].copy_from_slice(&0x10bu16.to_le_bytes());
+        opt[92..96].copy_from_slice(&16u32.to_le_bytes());
+        data.extend(opt);
+        let mut section = [0u8; 40];
+        section[..5].copy_from_slice(b".text");
+        section[16..20].copy_from_slice(&0x200u32.to_le_bytes());
+        section[20..24].copy_from_slice(&0x200u32.to_le_bytes());
+        data.extend(section);
+        data.resize(0x400, 0xCC);
+        data
+    }
+
+    /// Incompressible data, so a client with it spans several volumes
+    pub(crate) fn noise(len: usize) -> Vec<u8> {
+        let mut x = 1u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    /// NFO300 setup of a client with the given files, packed into a single volume
+    pub(crate) fn nfo300_setup(dir: &Path, files: &[(&str, &[u8])]) -> PathBuf {
+        nfo300_split_setup(dir, files, 1 << 20).0
+    }
+
+    /// NFO300 setup of a client with the given files, packed into volumes of at most
+    /// `volume_size`. Returns the setup and the volumes in their order
+    pub(crate) fn nfo300_split_setup(
+        dir: &Path,
+        files: &[(&str, &[u8])],
+        volume_size: u64,
+    ) -> (PathBuf, Vec<PathBuf>) {
+        let client = dir.join("client");
+        for (name, data) in files {
+            let path = client.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+        let cancel = CancelToken::new();
+        let volumes =
+            pack_split_zip(&client, &dir.join("vol"), "Client", volume_size, &cancel).unwrap();
+        std::fs::write(dir.join("stub.exe"), stub()).unwrap();
+        let setup_path = dir.join("Setup.exe");
+        write_nfo300(&dir.join("stub.exe"), &volumes, &setup_path).unwrap();
+        (setup_path, volumes)
+    }
+
+    /// Client spanning several volumes, wrapped into a NFO300 setup and into an
+    /// InstallShield setup with the volumes as encoded entries
+    pub(crate) struct SplitSetups {
+        pub(crate) map: Vec<u8>,
+        pub(crate) volumes: Vec<PathBuf>,
+        pub(crate) nfo300: PathBuf,
+        pub(crate) is: PathBuf,
+    }
+
+    /// Writes the split setups of a client with a large `Data/Map.wz`, `Data/Skill.wz`
+    /// and `Sound.wz` into `dir`
+    pub(crate) fn split_setups(dir: &Path) -> SplitSetups {
+        let map = noise(150_000);
+        let files: [(&str, &[u8]); 3] = [
+            ("Data/Map.wz", map.as_slice()),
+            ("Data/Skill.wz", b"skill"),
+            ("Sound.wz", b"sound"),
+        ];
+        let (nfo300, volumes) = nfo300_split_setup(&dir.join("nfo300"), &files, MIN_VOLUME_SIZE);
+        let is = dir.join("Is.exe");
+        is_setup(&is, &volumes);
+        SplitSetups {
+            map,
+            volumes,
+            nfo300,
+            is,
+        }
+    }
+
+    #[test]
+    fn split_setup_entries() {
+        let dir = unique_temp_dir("mssetup_pack_split_test");
+        let setups = split_setups(&dir);
+        assert!(setups.volumes.len() > 2);
+
+        // Both setups hold the volumes in the order of the set
+        let volumes = setups
+            .volumes
+            .iter()
+            .map(|v| {
+                let name = v.file_name().unwrap().to_str().unwrap().to_string();
+                (name, v.metadata().unwrap().len())
+            })
+            .collect::<Vec<_>>();
+        for (ix, path) in [&setups.nfo300, &setups.is].into_iter().enumerate() {
+            let mut setup = SetupOpt::open(path).unwrap();
+            assert_eq!(setup.entry_list().unwrap(), volumes);
+            let entries = dir.join(format!("entries{ix}"));
+            std::fs::create_dir_all(&entries).unwrap();
+            setup
+                .extract_entries(&entries, &NoProgress, &CancelToken::new())
+                .unwrap();
+            for (volume, (name, _)) in setups.volumes.iter().zip(&volumes) {
+                let data = std::fs::read(entries.join(name)).unwrap();
+                assert!(data == std::fs::read(volume).unwrap(), "{name}");
+            }
+            let parts = volumes.iter().map(|(name, _)| entries.join(name)).collect();
+            let mut map = Vec::new();
+            open_zip_split(parts)
+                .unwrap()
+                .by_name("Data/Map.wz")
+                .unwrap()
+                .read_to_end(&mut map)
+                .unwrap();
+            assert!(map == setups.map);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn split_and_wrap() {
+        let dir = unique_temp_dir("mssetup_pack_test");
+        let client = dir.join("client");
+        std::fs::create_dir_all(client.join("Data")).unwrap();
+        // Incompressible data, so the set needs more than one volume
+        let mut x = 1u32;
+        let noise = (0..200_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect::<Vec<_>>();
+        std::fs::write(client.join("Data/Map.wz"), &noise).unwrap();
+        std::fs::write(client.join("Sound.wz"), b"sound").unwrap();
+
+        let cancel = CancelToken::new();
+        let out = dir.join("out");
+        let volumes = pack_split_zip(&client, &out, "Client", MIN_VOLUME_SIZE, &cancel).unwrap();
+        let names = volumes
+            .iter()
+            .map(|v| v.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["Client.z01", "Client.z02", "Client.z03", "Client.zip"]
+        );
+        let data = volumes
+            .iter()
+            .map(|v| std::fs::read(v).unwrap())
+            .collect::<Vec<_>>();
+        assert!(data.iter().all(|d| d.len() as u64 <= MIN_VOLUME_SIZE));
+        assert!(data[0].starts_with(SPAN_SIG));
+
+        // Each local header is found in its volume
+        let last = data.last().unwrap();
+        let eocd = &last[last.len() - EOCD_LEN..];
+        assert_eq!(u16_at(eocd, 4).unwrap(), 3);
+        let cd_start = u32_at(eocd, 16).unwrap() as usize;
+        let cd = &last[cd_start..last.len() - EOCD_LEN];
+        let entries = cd_entries(cd).unwrap();
+        assert_eq!(entries.len(), 2);
+        for entry in entries {
+            let volume = &data[entry.disk as usize];
+            assert_eq!(u32_at(volume, entry.offset as usize).unwrap(), LOCAL_SIG);
+        }
+
+        // The set reads back as one archive
+        let mut archive = open_zip_split(volumes.clone()).unwrap();
+        let mut map = Vec::new();
+        archive
+            .by_name("Data/Map.wz")
+            .unwrap()
+            .read_to_end(&mut map)
+            .unwrap();
+        assert!(map == noise);
+        let all = EntryFilter::default();
+        extract_zip_split(volumes.clone(), dir.join("x"), &all, &NoProgress, &cancel).unwrap();
+        assert_eq!(std::fs::read(dir.join("x/Sound.wz")).unwrap(), b"sound");
+        assert!(std::fs::read(dir.join("x/Data/Map.wz")).unwrap() == noise);
+
+        let setup_path = dir.join("Setup.exe");
+        std::fs::write(dir.join("stub.exe"), stub()).unwrap();
+        write_nfo300(&dir.join("stub.exe"), &volumes, &setup_path).unwrap();
+        let mut setup = SetupOpt::open(&setup_path).unwrap();
+        let SetupOpt::Nfo300(nfo, _) = &mut setup else {
+            panic!("Not a NFO300 setup");
+        };
+        let entries = nfo.entries().unwrap();
+        let names = entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["Client.z01", "Client.z02", "Client.z03", "Client.zip"]
+        );
+        let mut zip = Vec::new();
+        nfo.entry_reader(&entries[3])
+            .unwrap()
+            .read_to_end(&mut zip)
+            .unwrap();
+        assert_eq!(&zip, last);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nfo300_round_trip() {
+        let dir = unique_temp_dir("mssetup_pack_nfo300_test");
+        let client = dir.join("client");
+        std::fs::create_dir_all(client.join("Data")).unwrap();
+        let map = noise(150_000);
+        std::fs::write(client.join("Data/Map.wz"), &map).unwrap();
+        std::fs::write(client.join("Sound.wz"), b"sound").unwrap();
+        std::fs::write(dir.join("stub.exe"), stub()).unwrap();
+
+        let cancel = CancelToken::new();
+        let out_dir = dir.join("out");
+        let stub_path = dir.join("stub.exe");
+        let setup_path = pack_nfo300(
+            &client,
+            &out_dir,
+            "Client",
+            MIN_VOLUME_SIZE,
+            &stub_path,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(setup_path, out_dir.join("Client.exe"));
+        // Only the setup is kept
+        assert_eq!(std::fs::read_dir(&out_dir).unwrap().count(), 1);
+
+        let mut setup = SetupOpt::open(&setup_path).unwrap();
+        assert_eq!(setup.format_name(), "NFO300");
+        let names = setup
+            .entry_list()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert!(names.len() > 2);
+        assert_eq!(names.last().unwrap(), "Client.zip");
+
+        let (tmp_dir, extracted) = (dir.join("tmp"), dir.join("extracted"));
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+        let all = EntryFilter::default();
+        setup
+            .extract_setup(&tmp_dir, &extracted, &all, &NoProgress, &cancel)
+            .unwrap();
+        assert!(std::fs::read(extracted.join("Data/Map.wz")).unwrap() == map);
+        assert_eq!(std::fs::read(extracted.join("Sound.wz")).unwrap(), b"sound");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,15 @@
-use std::{fs::File, io::{self, BufRead, BufReader, Read, Seek}, path::Path};
+use std::{fs::File, io::{self, BufRead, BufReader, Read, Seek}, path::Path, sync::Arc};
 
+use anyhow::Context;
 use binrw::{io::NoSeek, BinRead};
 use flate2::bufread::ZlibDecoder;
 use serde::Serialize;
 
+use crate::{
+    cancel::{CancelReader, CancelToken},
+    progress::{NoProgress, Progress, ProgressEvent, ProgressReader, Stage},
+};
+
 pub const CRC_32_PATCHER: crc::Algorithm<u32> = crc::Algorithm {
     width: 32,
     poly: 0x04c11db7,
@@ -40,6 +46,13 @@ pub fn wz_patch_verify_crc(mut r: impl Read, expected: u32) -> io::Result<()> {
     Ok(())
 }
 
+/// Parses the versions of a patch named like `00083to00084.patch`
+pub fn patch_versions_from_name(name: &str) -> Option<(u32, u32)> {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    let (from, to) = stem.split_once("to")?;
+    Some((from.parse().ok()?, to.parse().ok()?))
+}
+
 #[derive(BinRead, Serialize, Debug)]
 #[br(little, magic = b"WzPatch\x1A")]
 pub struct WzPatchHdr {
@@ -66,6 +79,11 @@ impl WzPatchFilePath {
 
         Ok((Self(name), b[0]))
     }
+
+    /// Path relative to the client, patches use `\\` as separator
+    pub fn rel_path(&self) -> String {
+        self.0.replace('\\', "/")
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -204,6 +222,11 @@ impl<R: BufRead + Read + Seek> WzPatch<R> {
         self.hdr.version
     }
 
+    /// Checksum of the compressed data
+    pub fn checksum(&self) -> u32 {
+        self.hdr.checksum
+    }
+
     pub fn verify_checksum(&mut self) -> anyhow::Result<()> {
         self.rdr.seek(std::io::SeekFrom::Start(self.data_offset))?;
         wz_patch_verify_crc(&mut self.rdr, self.hdr.checksum)?;
@@ -211,16 +234,40 @@ impl<R: BufRead + Read + Seek> WzPatch<R> {
     }
 
     pub fn patch_stream(&mut self) -> anyhow::Result<WzPatchStream<NoSeek<ZlibDecoder<&mut R>>>> {
+        self.stream_with(|rdr| rdr)
+    }
+
+    /// Decodes the operations from the compressed data, which is read through `wrap`
+    fn stream_with<'a, W: BufRead>(
+        &'a mut self,
+        wrap: impl FnOnce(&'a mut R) -> W,
+    ) -> anyhow::Result<WzPatchStream<NoSeek<ZlibDecoder<W>>>> {
         self.rdr.seek(std::io::SeekFrom::Start(self.data_offset))?;
-        let deflate = flate2::bufread::ZlibDecoder::new(&mut self.rdr);
+        let deflate = ZlibDecoder::new(wrap(&mut self.rdr));
         Ok(WzPatchStream {
             rdr: NoSeek::new(deflate),
         })
     }
 
-    pub fn process(&mut self, handler: &mut impl WzPatchHandler) -> anyhow::Result<()> {
-        let stream = self.patch_stream()?;
-        stream.process(handler)?;
+    pub fn process(
+        &mut self,
+        handler: &mut impl WzPatchHandler,
+        cancel: &CancelToken,
+    ) -> anyhow::Result<()> {
+        let progress = handler.progress();
+        let end = self.rdr.seek(std::io::SeekFrom::End(0))?;
+        progress.on_event(ProgressEvent::Start {
+            stage: Stage::Patch,
+            files: None,
+            bytes: end - self.data_offset,
+        });
+
+        // The progress follows the compressed data, the size of the output is unknown
+        let stream = self.stream_with(|rdr| {
+            ProgressReader::new(CancelReader::new(rdr, cancel), &*progress)
+        })?;
+        stream.process(handler, cancel)?;
+        progress.on_event(ProgressEvent::Finish);
         Ok(())
     }
 }
@@ -235,25 +282,37 @@ pub struct WzPatchDataStream<R> {
     rdr: R,
     len: u32,
     checksum: u32,
+    read: u64,
 }
 
 impl<R: Read> Read for WzPatchDataStream<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.rdr.read(buf)
+        let n = self.rdr.read(buf)?;
+        self.read += n as u64;
+        Ok(n)
     }
 }
 
 impl<R: Read> WzPatchDataStream<R> {
     pub fn new(rdr: R, len: u32, checksum: u32) -> Self {
-        Self { rdr, len, checksum }
+        Self {
+            rdr,
+            len,
+            checksum,
+            read: 0,
+        }
     }
 
     pub fn len(&self) -> u32 {
         self.len
     }
 
+    /// Skips the rest of the data, which fails if the stream ends before `len` bytes
     pub fn clear(&mut self) -> io::Result<()> {
-        io::copy(&mut self.rdr, &mut io::empty())?;
+        io::copy(self, &mut io::empty())?;
+        if self.read < self.len as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
         Ok(())
     }
 }
@@ -279,6 +338,22 @@ pub trait WzPatchHandler {
     ) -> anyhow::Result<()>;
     fn handle_mod_old_block(&mut self, offset: u32, len: u32) -> anyhow::Result<()>;
     fn handle_mod_end(&mut self, checksum: u32) -> anyhow::Result<()>;
+
+    /// Receives the processed files and bytes of the patch
+    fn progress(&self) -> Arc<dyn Progress> {
+        Arc::new(NoProgress)
+    }
+
+    /// Called once processing failed or was cancelled, to remove partial outputs
+    fn abort(&mut self) {}
+}
+
+/// Io errors are kept as they are, so a cancelled read is still detected
+fn read_error(err: binrw::Error) -> anyhow::Error {
+    match err {
+        binrw::Error::Io(err) => err.into(),
+        err => err.into(),
+    }
 }
 
 impl<R: Read> Read for WzPatchStream<R> {
@@ -288,14 +363,42 @@ impl<R: Read> Read for WzPatchStream<R> {
 }
 
 impl<R: Read + Seek> WzPatchStream<R> {
-    pub fn process(mut self, handler: &mut impl WzPatchHandler) -> anyhow::Result<()> {
+    /// Applies the operations to the handler, the token is checked between the files
+    /// and blocks. The handler is aborted if it fails
+    pub fn process(
+        mut self,
+        handler: &mut impl WzPatchHandler,
+        cancel: &CancelToken,
+    ) -> anyhow::Result<()> {
+        let res = self.process_files(handler, cancel);
+        if res.is_err() {
+            handler.abort();
+        }
+        res
+    }
+
+    fn process_files(
+        &mut self,
+        handler: &mut impl WzPatchHandler,
+        cancel: &CancelToken,
+    ) -> anyhow::Result<()> {
+        let progress = handler.progress();
         loop {
+            cancel.check()?;
+            let start = self.rdr.stream_position()?;
             let file = match WzPatchFile::read_le(&mut self.rdr) {
                 Ok(file) => file,
-                //TODO handle only eof
-                Err(_e) => {
+                // The stream only ends cleanly in front of a record
+                Err(binrw::Error::Io(err))
+                    if err.kind() == io::ErrorKind::UnexpectedEof
+                        && self.rdr.stream_position()? == start =>
+                {
                     break;
                 }
+                Err(err) => {
+                    return Err(read_error(err))
+                        .with_context(|| format!("Read patch record at {start}"))
+                }
             };
             match file.op {
                 WzPatchOp::AddFile { len, checksum } => {
@@ -303,17 +406,29 @@ impl<R: Read + Seek> WzPatchStream<R> {
                         WzPatchDataStream::new(self.rdr.by_ref().take(len as u64), len, checksum);
                     handler.handle_add(&file.file, &mut data)?;
                     data.clear()?;
+                    progress.on_event(ProgressEvent::File {
+                        name: file.file.0.clone(),
+                        size: len as u64,
+                    });
                 }
                 WzPatchOp::RemoveFile => {
                     handler.handle_remove(&file.file)?;
+                    progress.on_event(ProgressEvent::File {
+                        name: file.file.0.clone(),
+                        size: 0,
+                    });
                 }
                 WzPatchOp::ModifyFile {
                     old_checksum,
                     new_checksum,
                 } => {
                     handler.handle_modify(&file.file, old_checksum, new_checksum)?;
-                    self.process_blocks(handler)?;
+                    self.process_blocks(handler, cancel)?;
                     handler.handle_mod_end(new_checksum)?;
+                    progress.on_event(ProgressEvent::File {
+                        name: file.file.0.clone(),
+                        size: 0,
+                    });
                 }
             }
         }
@@ -321,9 +436,14 @@ impl<R: Read + Seek> WzPatchStream<R> {
         Ok(())
     }
 
-    fn process_blocks(&mut self, handler: &mut impl WzPatchHandler) -> anyhow::Result<()> {
+    fn process_blocks(
+        &mut self,
+        handler: &mut impl WzPatchHandler,
+        cancel: &CancelToken,
+    ) -> anyhow::Result<()> {
         loop {
-            let block = WzPatchBlock::read_le(&mut self.rdr)?;
+            cancel.check()?;
+            let block = WzPatchBlock::read_le(&mut self.rdr).map_err(read_error)?;
             match block {
                 WzPatchBlock::End => break Ok(()),
                 WzPatchBlock::NewBlock { len } => {
@@ -420,51 +540,153 @@ impl WzPatchHandler for TextHandler {
 }
 
 #[cfg(test)]
-mod tests {
-    use std::io::BufReader;
-
-    use crate::patcher::{WzPatcher, WzPatcherInfo};
+pub(crate) mod tests {
+    use crate::{
+        cancel::is_cancelled,
+        patcher::{
+            tests::{build_patch, sound_patch},
+            WzPatcher, WzPatcherInfo,
+        },
+        util::unique_temp_dir,
+    };
 
     use super::*;
 
-    #[test]
-    fn patch() {
-        let patch_file = "/home/jonas/Downloads/00083to00084.patch";
-        let file = BufReader::new(std::fs::File::open(patch_file).unwrap());
+    /// Yields a byte per read and cancels the token once `at` is reached
+    struct CancelAt<'a> {
+        data: &'a [u8],
+        pos: usize,
+        at: usize,
+        cancel: &'a CancelToken,
+    }
 
-        let mut patch = WzPatch::new(file).unwrap();
-        patch.verify_checksum().unwrap();
-        //dbg!(&patch);
-        todo!();
+    impl Read for CancelAt<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.at {
+                self.cancel.cancel();
+            }
+            let len = buf.len().min(1);
+            let n = (&self.data[self.pos..]).read(&mut buf[..len])?;
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    /// Decompressed operations of the patch
+    pub(crate) fn raw_patch(patch: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        ZlibDecoder::new(&patch[16..]).read_to_end(&mut raw).unwrap();
+        raw
+    }
+
+    /// Stream of the operations, which cancels the token when reading byte `at`
+    pub(crate) fn cancel_stream<'a>(
+        raw: &'a [u8],
+        at: usize,
+        cancel: &'a CancelToken,
+    ) -> WzPatchStream<impl Read + Seek + 'a> {
+        let rdr = CancelAt {
+            data: raw,
+            pos: 0,
+            at,
+            cancel,
+        };
+        WzPatchStream {
+            rdr: NoSeek::new(CancelReader::new(rdr, cancel)),
+        }
+    }
 
+    fn process_raw(raw: impl Read) -> anyhow::Result<TextHandler> {
         let mut handler = TextHandler::default();
-        let stream = patch.patch_stream().unwrap();
-        stream.process(&mut handler).unwrap();
+        let stream = WzPatchStream {
+            rdr: NoSeek::new(raw),
+        };
+        stream.process(&mut handler, &CancelToken::new())?;
+        Ok(handler)
+    }
 
-        std::fs::write("patch.txt", handler.w).unwrap();
-        todo!();
+    #[test]
+    fn stream_end() {
+        let patch = build_patch(b"sound");
+        let raw = raw_patch(&patch);
+        let second = raw.windows(4).position(|w| w == b"Data").unwrap();
+
+        // The stream ends after a complete record
+        let handler = process_raw(&raw[..second]).unwrap();
+        assert!(handler.w.contains("Sound.wz"));
+        process_raw(&raw[..]).unwrap();
+
+        // A record cut off is an error
+        for end in [second + 2, raw.len() - 2] {
+            let err = process_raw(&raw[..end]).unwrap_err();
+            assert!(!is_cancelled(&err), "{err:#}");
+        }
 
-        //patch.mods().unwrap();
+        // Also when the compressed data is cut off
+        let mut patch = WzPatch::new(io::Cursor::new(&patch[..patch.len() - 6])).unwrap();
+        assert!(patch.process(&mut TextHandler::default(), &CancelToken::new()).is_err());
+
+        // A cancel inside the header of a record is reported as such
+        let cancel = CancelToken::new();
+        let err = cancel_stream(&raw, second + 2, &cancel)
+            .process(&mut TextHandler::default(), &CancelToken::new())
+            .unwrap_err();
+        assert!(is_cancelled(&err), "{err:#}");
     }
 
     #[test]
-    fn patcher() {
-        let patch_file = "/home/jonas/Downloads/00083to00084.patch";
-        let mut patch = WzPatch::open(patch_file).unwrap();
-        let mut patcher = WzPatcher::new("/home/jonas/Games/gms83_1/");
+    fn patch_name() {
+        assert_eq!(patch_versions_from_name("00083to00084.patch"), Some((83, 84)));
+        assert_eq!(patch_versions_from_name("00083to00084"), Some((83, 84)));
+        assert_eq!(patch_versions_from_name("Patcher.exe"), None);
+    }
 
-        patch.process(&mut patcher).unwrap();
+    #[test]
+    fn patch() {
+        let data = build_patch(b"sound");
+        let mut patch = WzPatch::new(io::Cursor::new(data)).unwrap();
+        patch.verify_checksum().unwrap();
+        assert_eq!(patch.version(), 84);
 
+        let mut handler = TextHandler::default();
+        let stream = patch.patch_stream().unwrap();
+        stream.process(&mut handler, &CancelToken::new()).unwrap();
+        assert!(handler.w.contains("Sound.wz - Old Block offset: 0 len: 5"));
+        assert!(handler.w.contains("Sound.wz - Repeat: 43 len: 3"));
+        assert!(handler.w.contains("Add: Data\\Skill.wz len: 5"));
+        assert!(handler.w.contains("Remove: Old.wz"));
+    }
+
+    #[test]
+    fn patcher() {
+        let dir = unique_temp_dir("mssetup_patch_test");
+        let client = dir.join("client");
+        let patch_file = dir.join("00083to00084.patch");
+        std::fs::write(&patch_file, sound_patch(&client)).unwrap();
+        let mut patch = WzPatch::open(&patch_file).unwrap();
+        let mut patcher = WzPatcher::new(&client);
+
+        patch.process(&mut patcher, &CancelToken::new()).unwrap();
+        assert_eq!(
+            std::fs::read(client.join("out/Sound.wz")).unwrap(),
+            b"sound+++"
+        );
+        assert_eq!(
+            std::fs::read(client.join("out/Data/Skill.wz")).unwrap(),
+            b"skill"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
     fn patcher_info() {
-        let patch_file = "/home/jonas/Downloads/00072to00073.patch";
-        let mut patch = WzPatch::open(patch_file).unwrap();
+        let data = build_patch(b"sound");
+        let mut patch = WzPatch::new(io::Cursor::new(data)).unwrap();
         let mut info = WzPatcherInfo::default();
 
-        patch.process(&mut info).unwrap();
-        dbg!(&info);
-
+        patch.process(&mut info, &CancelToken::new()).unwrap();
+        assert_eq!(info.added_files, [("Data\\Skill.wz".to_string(), 5)]);
+        assert_eq!(info.removed_files, ["Old.wz"]);
+        assert_eq!(info.modified_files, [("Sound.wz".to_string(), 8, 3)]);
     }
 }
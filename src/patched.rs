@@ -0,0 +1,406 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cancel::CancelToken,
+    extract::remove_existing,
+    patch::{wz_patch_verify_crc, WzPatch, WzPatchDataStream, WzPatchFilePath, WzPatchHandler},
+};
+
+const INDEX_FILE: &str = "index.json";
+const DATA_FILE: &str = "data.bin";
+
+/// Range of a new file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ViewOp {
+    /// Bytes of the old file
+    Old {
+        offset: u64,
+        len: u64,
+    },
+    /// Bytes of the decompressed patch, the offset is in the data file of the view
+    New {
+        offset: u64,
+        len: u64,
+    },
+    Repeat {
+        byte: u8,
+        len: u64,
+    },
+}
+
+impl ViewOp {
+    fn len(&self) -> u64 {
+        match *self {
+            Self::Old { len, .. } | Self::New { len, .. } | Self::Repeat { len, .. } => len,
+        }
+    }
+}
+
+/// New or modified file of the patch
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ViewFile {
+    pub size: u64,
+    /// Checksum of the old file, none for added files
+    pub old_checksum: Option<u32>,
+    /// Start of each op in the new file
+    starts: Vec<u64>,
+    ops: Vec<ViewOp>,
+}
+
+impl ViewFile {
+    fn push(&mut self, op: ViewOp) {
+        self.starts.push(self.size);
+        self.size += op.len();
+        self.ops.push(op);
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ViewIndex {
+    /// Checksum of the patch the index was built from
+    checksum: u32,
+    files: BTreeMap<String, ViewFile>,
+    removed: Vec<String>,
+}
+
+/// Client with a patch applied at read time. The patch is inflated once to index the
+/// ops of each file, the new blocks are kept in the cache dir together with the index.
+/// Reads of the new files pull the ranges from the old files and those blocks
+pub struct PatchedView {
+    old_dir: PathBuf,
+    index: ViewIndex,
+    data: File,
+    /// Old files, which passed their checksum
+    old_files: HashMap<String, File>,
+}
+
+impl PatchedView {
+    /// Opens the view of the patch on the client in `old_dir`, an index in `cache_dir`
+    /// is reused if it belongs to the same patch
+    pub fn open(patch: &Path, old_dir: &Path, cache_dir: &Path) -> anyhow::Result<Self> {
+        let mut patch = WzPatch::open(patch)?;
+        std::fs::create_dir_all(cache_dir).context("Create cache dir")?;
+        let index_path = cache_dir.join(INDEX_FILE);
+        let data_path = cache_dir.join(DATA_FILE);
+
+        let cached = File::open(&index_path)
+            .ok()
+            .and_then(|file| serde_json::from_reader::<_, ViewIndex>(BufReader::new(file)).ok())
+            .filter(|index| index.checksum == patch.checksum() && data_path.is_file());
+        let index = match cached {
+            Some(index) => index,
+            None => {
+                // A stale index must not outlive the data it points into
+                remove_existing(&index_path).context("Remove stale index")?;
+                let mut builder = IndexBuilder {
+                    index: ViewIndex {
+                        checksum: patch.checksum(),
+                        ..Default::default()
+                    },
+                    data: BufWriter::new(File::create(&data_path)?),
+                    data_len: 0,
+                    current: None,
+                };
+                patch.process(&mut builder, &CancelToken::new())?;
+                builder.data.into_inner()?.sync_all()?;
+
+                // Replace the index at once, so it's either complete or missing
+                let tmp = index_path.with_extension("json.tmp");
+                let mut file = BufWriter::new(File::create(&tmp)?);
+                serde_json::to_writer(&mut file, &builder.index)?;
+                file.into_inner()?.sync_all()?;
+                std::fs::rename(&tmp, &index_path)?;
+                builder.index
+            }
+        };
+
+        Ok(Self {
+            old_dir: old_dir.to_path_buf(),
+            index,
+            data: File::open(&data_path)?,
+            old_files: HashMap::new(),
+        })
+    }
+
+    /// New and modified files by their path in the patch
+    pub fn files(&self) -> &BTreeMap<String, ViewFile> {
+        &self.index.files
+    }
+
+    pub fn removed(&self) -> &[String] {
+        &self.index.removed
+    }
+
+    /// Reads the new file at `offset`, returns the bytes read which are only fewer than
+    /// the buffer at the end of the file
+    pub fn read_at(&mut self, name: &str, offset: u64, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let file = self
+            .index
+            .files
+            .get(name)
+            .with_context(|| format!("Not in the patch: {name}"))?;
+        let end = file.size.min(offset + buf.len() as u64);
+        let mut pos = offset;
+        let mut ix = file
+            .starts
+            .partition_point(|&start| start <= pos)
+            .saturating_sub(1);
+        while pos < end {
+            let (start, op) = (file.starts[ix], file.ops[ix]);
+            let skip = pos - start;
+            let n = (op.len() - skip).min(end - pos) as usize;
+            let out = &mut buf[(pos - offset) as usize..][..n];
+            match op {
+                ViewOp::Old { offset, .. } => {
+                    let checksum = file.old_checksum.context("Old block of an added file")?;
+                    let old = open_old(&mut self.old_files, &self.old_dir, name, checksum)?;
+                    old.seek(SeekFrom::Start(offset + skip))?;
+                    old.read_exact(out)?;
+                }
+                ViewOp::New { offset, .. } => {
+                    self.data.seek(SeekFrom::Start(offset + skip))?;
+                    self.data.read_exact(out)?;
+                }
+                ViewOp::Repeat { byte, .. } => out.fill(byte),
+            }
+            pos += n as u64;
+            ix += 1;
+        }
+        Ok(end.saturating_sub(offset) as usize)
+    }
+}
+
+/// Opens an old file once, its checksum is verified on the first access
+fn open_old<'a>(
+    files: &'a mut HashMap<String, File>,
+    old_dir: &Path,
+    name: &str,
+    checksum: u32,
+) -> anyhow::Result<&'a mut File> {
+    if !files.contains_key(name) {
+        let path = old_dir.join(name.replace('\\', "/"));
+        let mut file = File::open(&path).with_context(|| format!("Open {name}"))?;
+        wz_patch_verify_crc(BufReader::new(&mut file), checksum)
+            .with_context(|| format!("Old file {name}"))?;
+        files.insert(name.to_string(), file);
+    }
+    Ok(files.get_mut(name).unwrap())
+}
+
+/// Records the ops of each file and copies the new blocks into the data file
+struct IndexBuilder {
+    index: ViewIndex,
+    data: BufWriter<File>,
+    data_len: u64,
+    current: Option<(String, ViewFile)>,
+}
+
+impl IndexBuilder {
+    fn copy_data<R: Read>(&mut self, data: &mut WzPatchDataStream<R>) -> io::Result<ViewOp> {
+        let len = io::copy(data, &mut self.data)?;
+        let op = ViewOp::New {
+            offset: self.data_len,
+            len,
+        };
+        self.data_len += len;
+        Ok(op)
+    }
+
+    fn current_mut(&mut self) -> anyhow::Result<&mut ViewFile> {
+        let (_, file) = self.current.as_mut().context("No patch file open")?;
+        Ok(file)
+    }
+}
+
+impl WzPatchHandler for IndexBuilder {
+    fn handle_add<R: Read>(
+        &mut self,
+        p: &WzPatchFilePath,
+        data: &mut WzPatchDataStream<R>,
+    ) -> anyhow::Result<()> {
+        let mut file = ViewFile::default();
+        file.push(self.copy_data(data)?);
+        self.index.files.insert(p.0.clone(), file);
+        Ok(())
+    }
+
+    fn handle_remove(&mut self, p: &WzPatchFilePath) -> anyhow::Result<()> {
+        self.index.removed.push(p.0.clone());
+        Ok(())
+    }
+
+    fn handle_modify(
+        &mut self,
+        p: &WzPatchFilePath,
+        old_checksum: u32,
+        _new_checksum: u32,
+    ) -> anyhow::Result<()> {
+        let file = ViewFile {
+            old_checksum: Some(old_checksum),
+            ..Default::default()
+        };
+        self.current = Some((p.0.clone(), file));
+        Ok(())
+    }
+
+    fn handle_mod_repeat(&mut self, byte: u8, len: u32) -> anyhow::Result<()> {
+        self.current_mut()?.push(ViewOp::Repeat {
+            byte,
+            len: len as u64,
+        });
+        Ok(())
+    }
+
+    fn handle_mod_new_block<R: Read>(
+        &mut self,
+        data: &mut WzPatchDataStream<R>,
+    ) -> anyhow::Result<()> {
+        let op = self.copy_data(data)?;
+        self.current_mut()?.push(op);
+        Ok(())
+    }
+
+    fn handle_mod_old_block(&mut self, offset: u32, len: u32) -> anyhow::Result<()> {
+        self.current_mut()?.push(ViewOp::Old {
+            offset: offset as u64,
+            len: len as u64,
+        });
+        Ok(())
+    }
+
+    fn handle_mod_end(&mut self, _checksum: u32) -> anyhow::Result<()> {
+        let (name, file) = self.current.take().context("No patch file open")?;
+        self.index.files.insert(name, file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        patch::wz_patch_calc_crc,
+        patcher::tests::{build_patch, sound_patch, wrap_patch},
+        util::unique_temp_dir,
+    };
+
+    use super::*;
+
+    fn push_modify(stream: &mut Vec<u8>, name: &str, old: &[u8], new: &[u8]) {
+        stream.extend(name.as_bytes());
+        stream.push(1);
+        stream.extend(wz_patch_calc_crc(old).unwrap().to_le_bytes());
+        stream.extend(wz_patch_calc_crc(new).unwrap().to_le_bytes());
+    }
+
+    /// Writes a client to `client` and returns a patch modifying all of its files
+    fn multi_patch(client: &Path) -> Vec<u8> {
+        std::fs::create_dir_all(client.join("Data")).unwrap();
+        std::fs::write(client.join("Base.wz"), b"0123456789").unwrap();
+        std::fs::write(client.join("Data/Etc.wz"), b"etc").unwrap();
+        std::fs::write(client.join("Map.wz"), b"abcdefgh").unwrap();
+
+        let mut stream = Vec::new();
+        // Old blocks out of order around a new block
+        push_modify(&mut stream, "Base.wz", b"0123456789", b"56789xy01234");
+        stream.extend(5u32.to_le_bytes());
+        stream.extend(5u32.to_le_bytes());
+        stream.extend((0x8000_0000u32 | 2).to_le_bytes());
+        stream.extend(b"xy");
+        stream.extend(5u32.to_le_bytes());
+        stream.extend(0u32.to_le_bytes());
+        stream.extend(0u32.to_le_bytes());
+        // Only new data, the old file isn't needed
+        push_modify(&mut stream, "Data\\Etc.wz", b"etc", b"ETC!");
+        stream.extend((0x8000_0000u32 | 4).to_le_bytes());
+        stream.extend(b"ETC!");
+        stream.extend(0u32.to_le_bytes());
+        push_modify(&mut stream, "Map.wz", b"abcdefgh", b"zzzefgh");
+        stream.extend((0xC000_0000u32 | (3 << 8) | b'z' as u32).to_le_bytes());
+        stream.extend(4u32.to_le_bytes());
+        stream.extend(4u32.to_le_bytes());
+        stream.extend(0u32.to_le_bytes());
+        wrap_patch(&stream)
+    }
+
+    #[test]
+    fn multiple_modified() {
+        let dir = unique_temp_dir("mssetup_view_test");
+        let (client, cache) = (dir.join("client"), dir.join("cache"));
+        let patch = dir.join("84.patch");
+        std::fs::write(&patch, multi_patch(&client)).unwrap();
+        std::fs::remove_file(client.join("Data/Etc.wz")).unwrap();
+
+        let mut view = PatchedView::open(&patch, &client, &cache).unwrap();
+        let sizes: Vec<_> = view
+            .files()
+            .iter()
+            .map(|(k, f)| (k.as_str(), f.size))
+            .collect();
+        assert_eq!(sizes, [("Base.wz", 12), ("Data\\Etc.wz", 4), ("Map.wz", 7)]);
+        assert!(view.removed().is_empty());
+
+        // Reads alternate between the files and span several blocks
+        let mut buf = [0u8; 16];
+        assert_eq!(view.read_at("Base.wz", 3, &mut buf[..6]).unwrap(), 6);
+        assert_eq!(&buf[..6], b"89xy01");
+        assert_eq!(view.read_at("Map.wz", 2, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"zefgh");
+        assert_eq!(view.read_at("Data\\Etc.wz", 0, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ETC!");
+        assert_eq!(view.read_at("Base.wz", 0, &mut buf).unwrap(), 12);
+        assert_eq!(&buf[..12], b"56789xy01234");
+        assert_eq!(view.read_at("Base.wz", 12, &mut buf).unwrap(), 0);
+        assert!(view.read_at("Sound.wz", 0, &mut buf).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lazy_reads() {
+        let dir = unique_temp_dir("mssetup_view_test");
+        let (client, cache) = (dir.join("client"), dir.join("cache"));
+        let patch = dir.join("84.patch");
+        std::fs::write(&patch, sound_patch(&client)).unwrap();
+
+        let mut view = PatchedView::open(&patch, &client, &cache).unwrap();
+        assert_eq!(view.files()["Sound.wz"].size, 8);
+        let mut buf = [0u8; 4];
+        assert_eq!(view.read_at("Sound.wz", 3, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"nd++");
+        assert_eq!(view.read_at("Sound.wz", 6, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"++");
+
+        // The cached index is reused
+        let mtime = std::fs::metadata(cache.join(DATA_FILE))
+            .unwrap()
+            .modified()
+            .unwrap();
+        let mut view = PatchedView::open(&patch, &client, &cache).unwrap();
+        assert_eq!(view.read_at("Data\\Skill.wz", 0, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"skil");
+        let data = std::fs::metadata(cache.join(DATA_FILE)).unwrap();
+        assert_eq!(data.modified().unwrap(), mtime);
+
+        // A drifted old file is detected on the first read of its blocks
+        std::fs::write(client.join("Sound.wz"), b"sounD").unwrap();
+        let mut view = PatchedView::open(&patch, &client, &cache).unwrap();
+        assert_eq!(view.read_at("Sound.wz", 5, &mut buf[..3]).unwrap(), 3);
+        assert!(view.read_at("Sound.wz", 0, &mut buf).is_err());
+
+        // An index of another patch is removed before its data is overwritten
+        let data = build_patch(b"other");
+        std::fs::write(&patch, &data[..data.len() - 8]).unwrap();
+        assert!(PatchedView::open(&patch, &client, &cache).is_err());
+        assert!(!cache.join(INDEX_FILE).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,11 +2,17 @@ use std::{
     fs::File,
     io::{self, Read, Seek, SeekFrom, Take, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use anyhow::Context;
 use serde::Serialize;
 
-use crate::patch::{wz_patch_verify_crc, WzPatchFilePath, WzPatchHandler, WZ_PATCHER_CRC};
+use crate::{
+    extract::{remove_existing, remove_partial},
+    patch::{wz_patch_verify_crc, WzPatchFilePath, WzPatchHandler, WZ_PATCHER_CRC},
+    progress::{NoProgress, Progress},
+};
 
 pub const PATCH_BUFFER_SIZE: usize = 4096;
 
@@ -75,17 +81,41 @@ struct CurrentPatchFile {
 
 pub struct WzPatcher {
     dir: PathBuf,
+    out_dir: PathBuf,
     current: Option<CurrentPatchFile>,
+    created: Vec<PathBuf>,
+    removed: Vec<String>,
+    progress: Arc<dyn Progress>,
 }
 
 impl WzPatcher {
+    /// Patches the client in `dir`, new and modified files are written to `dir/out`.
+    /// The client itself is left as it is, removed files are only recorded
     pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self::with_out_dir(&dir, dir.as_ref().join("out"))
+    }
+
+    pub fn with_out_dir(dir: impl AsRef<Path>, out_dir: impl AsRef<Path>) -> Self {
         WzPatcher {
             dir: dir.as_ref().to_path_buf(),
+            out_dir: out_dir.as_ref().to_path_buf(),
             current: None,
+            created: Vec::new(),
+            removed: Vec::new(),
+            progress: Arc::new(NoProgress),
         }
     }
 
+    pub fn with_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Files the patch removes, relative to the client
+    pub fn removed(&self) -> &[String] {
+        &self.removed
+    }
+
     fn get_current_mut(&mut self) -> anyhow::Result<&mut CurrentPatchFile> {
         self.current
             .as_mut()
@@ -97,26 +127,34 @@ impl WzPatcher {
     }
 
     fn resolve_old(&self, p: &WzPatchFilePath) -> PathBuf {
-        self.dir.join(&p.0)
+        self.dir.join(p.rel_path())
     }
 
     fn resolve_new(&self, p: &WzPatchFilePath) -> PathBuf {
-        let _ = std::fs::create_dir(self.dir.join("out"));
-        self.dir.join("out").join(&p.0)
+        let path = self.out_dir.join(p.rel_path());
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        path
     }
 
-    fn new_file(&self, p: &WzPatchFilePath) -> anyhow::Result<NewFile<File>> {
-        Ok(NewFile::new(File::create(self.resolve_new(p))?))
+    /// Creates the output file, which is tracked so a failed patch can remove it
+    fn create_new(&mut self, p: &WzPatchFilePath) -> anyhow::Result<NewFile<File>> {
+        let path = self.resolve_new(p);
+        let file = File::create(&path).with_context(|| format!("Create {}", path.display()))?;
+        self.created.push(path);
+        Ok(NewFile::new(file))
     }
 
     fn set_current(&mut self, path: &WzPatchFilePath, checksum: u32) -> anyhow::Result<()> {
         let old = self.resolve_old(path);
-        let new = self.resolve_new(path);
-
-        let mut old_file = OldFile::new(File::open(&old)?);
-        old_file.verify_checksum(checksum)?;
+        let old = File::open(&old).with_context(|| format!("Open {}", path.0))?;
+        let mut old_file = OldFile::new(old);
+        old_file
+            .verify_checksum(checksum)
+            .with_context(|| format!("Old file {}", path.0))?;
 
-        let new_file = NewFile::new(File::create(&new)?);
+        let new_file = self.create_new(path)?;
         self.current = Some(CurrentPatchFile {
             old_file,
             new_file,
@@ -132,14 +170,17 @@ impl WzPatchHandler for WzPatcher {
         p: &WzPatchFilePath,
         data: &mut crate::patch::WzPatchDataStream<R>,
     ) -> anyhow::Result<()> {
-        self.new_file(p)?.write_from(data)?;
+        self.create_new(p)?.write_from(data)?;
 
         Ok(())
     }
 
     fn handle_remove(&mut self, p: &WzPatchFilePath) -> anyhow::Result<()> {
-        let p = self.resolve_old(p);
-        std::fs::remove_file(p)?;
+        // An output of an earlier record is dropped, the client is not touched
+        let new = self.out_dir.join(p.rel_path());
+        remove_existing(&new)?;
+        self.created.retain(|path| *path != new);
+        self.removed.push(p.rel_path());
 
         Ok(())
     }
@@ -180,7 +221,8 @@ impl WzPatchHandler for WzPatcher {
         let actual = cur.new_file.checksum();
         if actual != checksum {
             anyhow::bail!(
-                "Checksum mismatch: expected 0x{:08x}, got 0x{:08x}",
+                "Checksum mismatch for {}: expected 0x{:08x}, got 0x{:08x}",
+                cur.path.0,
                 checksum,
                 actual
             );
@@ -188,6 +230,16 @@ impl WzPatchHandler for WzPatcher {
         self.clear_current();
         Ok(())
     }
+
+    fn progress(&self) -> Arc<dyn Progress> {
+        self.progress.clone()
+    }
+
+    fn abort(&mut self) {
+        self.clear_current();
+        remove_partial(&self.created);
+        self.created.clear();
+    }
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -246,3 +298,110 @@ impl WzPatchHandler for WzPatcherInfo {
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use crate::{
+        cancel::{is_cancelled, CancelToken},
+        patch::{
+            tests::{cancel_stream, raw_patch},
+            wz_patch_calc_crc, WzPatch,
+        },
+        util::{get_all_nested_files, unique_temp_dir},
+    };
+
+    use super::*;
+
+    pub(crate) fn build_patch(old: &[u8]) -> Vec<u8> {
+        let new_checksum = wz_patch_calc_crc(&b"sound+++"[..]).unwrap();
+        let mut stream = b"Sound.wz\x01".to_vec();
+        stream.extend(wz_patch_calc_crc(old).unwrap().to_le_bytes());
+        stream.extend(new_checksum.to_le_bytes());
+        // Old block of 5 bytes at 0, repeat '+' 3 times
+        stream.extend(5u32.to_le_bytes());
+        stream.extend(0u32.to_le_bytes());
+        stream.extend((0xC000_0000u32 | (3 << 8) | b'+' as u32).to_le_bytes());
+        stream.extend(0u32.to_le_bytes());
+        stream.extend(b"Data\\Skill.wz\x00");
+        stream.extend(5u32.to_le_bytes());
+        stream.extend(wz_patch_calc_crc(&b"skill"[..]).unwrap().to_le_bytes());
+        stream.extend(b"skill");
+        stream.extend(b"Old.wz\x02");
+        wrap_patch(&stream)
+    }
+
+    /// Compresses a record stream into a patch file of version 84
+    pub(crate) fn wrap_patch(stream: &[u8]) -> Vec<u8> {
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(stream).unwrap();
+        let data = enc.finish().unwrap();
+
+        let mut patch = b"WzPatch\x1A".to_vec();
+        patch.extend(84i32.to_le_bytes());
+        patch.extend(wz_patch_calc_crc(&data[..]).unwrap().to_le_bytes());
+        patch.extend(data);
+        patch
+    }
+
+    /// Writes a client to `client` with the Sound.wz the returned patch applies to and
+    /// the Old.wz it removes
+    pub(crate) fn sound_patch(client: &Path) -> Vec<u8> {
+        std::fs::create_dir_all(client).unwrap();
+        std::fs::write(client.join("Sound.wz"), b"sound").unwrap();
+        std::fs::write(client.join("Old.wz"), b"old").unwrap();
+        build_patch(b"sound")
+    }
+
+    #[test]
+    fn patch_out_dir() {
+        let dir = unique_temp_dir("mssetup_patcher_test");
+        let (client, out) = (dir.join("client"), dir.join("patched"));
+        let data = sound_patch(&client);
+
+        let mut patch = WzPatch::new(Cursor::new(data.clone())).unwrap();
+        patch.verify_checksum().unwrap();
+        assert_eq!(patch.version(), 84);
+        let cancel = CancelToken::new();
+        let mut patcher = WzPatcher::with_out_dir(&client, &out);
+        patch.process(&mut patcher, &cancel).unwrap();
+        assert_eq!(std::fs::read(out.join("Sound.wz")).unwrap(), b"sound+++");
+        assert_eq!(std::fs::read(out.join("Data/Skill.wz")).unwrap(), b"skill");
+        // Removed files are only recorded, the client is left as it is
+        assert_eq!(patcher.removed(), ["Old.wz"]);
+        assert!(client.join("Old.wz").exists());
+
+        // A cancel inside a modify block or after an added file removes the outputs
+        let raw = raw_patch(&data);
+        let last = raw.windows(6).position(|w| w == b"Old.wz").unwrap();
+        // Inside the repeat block, which follows the old block of Sound.wz
+        for at in [27, last + 2] {
+            let out = dir.join(format!("cancelled{at}"));
+            let cancel = CancelToken::new();
+            let err = cancel_stream(&raw, at, &cancel)
+                .process(&mut WzPatcher::with_out_dir(&client, &out), &cancel)
+                .unwrap_err();
+            assert!(is_cancelled(&err), "{err:#}");
+            assert!(get_all_nested_files(&out).unwrap().is_empty());
+        }
+
+        // A drifted old file is reported with its name
+        std::fs::write(client.join("Sound.wz"), b"sounD").unwrap();
+        let mut patch = WzPatch::new(Cursor::new(data)).unwrap();
+        let err = patch
+            .process(&mut WzPatcher::with_out_dir(&client, &out), &cancel)
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Sound.wz"));
+
+        cancel.cancel();
+        let err = patch
+            .process(&mut WzPatcher::with_out_dir(&client, &out), &cancel)
+            .unwrap_err();
+        assert!(is_cancelled(&err));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
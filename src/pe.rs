@@ -0,0 +1,509 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Seek, SeekFrom},
+};
+
+use anyhow::Context;
+use bytemuck::{Pod, Zeroable};
+use serde::Serialize;
+
+pub const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+/// The address of the security directory is a file offset rather than a RVA
+pub const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+const RT_VERSION: u32 = 16;
+const VS_FIXEDFILEINFO_SIGNATURE: u32 = 0xFEEF04BD;
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+pub struct DosHeader {
+    pub magic: [u8; 2],
+    pub x1: [u16; 29],
+    pub lfanew: u32,
+}
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+pub struct CoffHeader {
+    pub machine: u16,
+    pub num_sections: u16,
+    pub time_date_stamp: u32,
+    pub symbol_table: u32,
+    pub num_symbols: u32,
+    pub optional_header_size: u16,
+    pub characteristics: u16,
+}
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable, Default)]
+#[repr(C, packed)]
+pub struct DataDirectory {
+    pub rva: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+pub struct SectionHeader {
+    pub name: [u8; 8],
+    pub virtual_size: u32,
+    pub virtual_address: u32,
+    pub raw_size: u32,
+    pub raw_offset: u32,
+    pub relocations: u32,
+    pub line_numbers: u32,
+    pub num_relocations: u16,
+    pub num_line_numbers: u16,
+    pub characteristics: u32,
+}
+
+impl SectionHeader {
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(8);
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+}
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+struct ResourceDirectory {
+    characteristics: u32,
+    time_date_stamp: u32,
+    major_version: u16,
+    minor_version: u16,
+    num_named: u16,
+    num_ids: u16,
+}
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+struct ResourceDirectoryEntry {
+    name_or_id: u32,
+    offset: u32,
+}
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+struct ResourceDataEntry {
+    rva: u32,
+    size: u32,
+    codepage: u32,
+    reserved: u32,
+}
+
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+#[repr(C, packed)]
+pub struct FixedFileInfo {
+    pub signature: u32,
+    pub struct_version: u32,
+    pub file_version_ms: u32,
+    pub file_version_ls: u32,
+    pub product_version_ms: u32,
+    pub product_version_ls: u32,
+    pub file_flags_mask: u32,
+    pub file_flags: u32,
+    pub file_os: u32,
+    pub file_type: u32,
+    pub file_subtype: u32,
+    pub file_date_ms: u32,
+    pub file_date_ls: u32,
+}
+
+fn split_version(ms: u32, ls: u32) -> [u16; 4] {
+    [(ms >> 16) as u16, ms as u16, (ls >> 16) as u16, ls as u16]
+}
+
+/// Parsed `VS_VERSIONINFO` resource
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub file_version: [u16; 4],
+    pub product_version: [u16; 4],
+    pub language: u16,
+    pub strings: BTreeMap<String, String>,
+}
+
+/// Headers of a PE image
+#[derive(Debug)]
+pub struct PeFile {
+    pub coff: CoffHeader,
+    pub is_64: bool,
+    /// File offset of the optional header
+    pub optional_header_offset: u64,
+    /// File offset of the first data directory
+    pub data_dirs_offset: u64,
+    pub data_dirs: Vec<DataDirectory>,
+    pub sections: Vec<SectionHeader>,
+}
+
+impl PeFile {
+    pub fn parse<R: Read + Seek>(mut rdr: R) -> anyhow::Result<Self> {
+        rdr.seek(SeekFrom::Start(0))?;
+        let mut dos = DosHeader::zeroed();
+        rdr.read_exact(bytemuck::bytes_of_mut(&mut dos))?;
+        if &dos.magic != b"MZ" {
+            anyhow::bail!("Invalid DOS header magic: {:?}", dos.magic);
+        }
+
+        rdr.seek(SeekFrom::Start(dos.lfanew as u64))?;
+        let mut sig = [0u8; 4];
+        rdr.read_exact(&mut sig)?;
+        if &sig != b"PE\0\0" {
+            anyhow::bail!("Invalid PE signature: {:?}", sig);
+        }
+
+        let mut coff = CoffHeader::zeroed();
+        rdr.read_exact(bytemuck::bytes_of_mut(&mut coff))?;
+
+        let optional_header_offset = rdr.stream_position()?;
+        let mut opt = vec![0u8; coff.optional_header_size as usize];
+        rdr.read_exact(&mut opt)?;
+        let magic = u16::from_le_bytes(opt.get(..2).context("No optional header")?.try_into()?);
+        let (is_64, dirs_offset) = match magic {
+            0x10b => (false, 96),
+            0x20b => (true, 112),
+            _ => anyhow::bail!("Invalid optional header magic: {magic:#x}"),
+        };
+
+        let num_dirs = opt
+            .get(dirs_offset - 4..dirs_offset)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .context("Truncated optional header")?;
+        let data_dirs = opt
+            .get(dirs_offset..)
+            .unwrap_or_default()
+            .chunks_exact(std::mem::size_of::<DataDirectory>())
+            .take(num_dirs as usize)
+            .map(bytemuck::pod_read_unaligned)
+            .collect();
+
+        let mut sections = Vec::with_capacity(coff.num_sections as usize);
+        for _ in 0..coff.num_sections {
+            let mut section = SectionHeader::zeroed();
+            rdr.read_exact(bytemuck::bytes_of_mut(&mut section))?;
+            sections.push(section);
+        }
+
+        Ok(Self {
+            coff,
+            is_64,
+            optional_header_offset,
+            data_dirs_offset: optional_header_offset + dirs_offset as u64,
+            data_dirs,
+            sections,
+        })
+    }
+
+    pub fn data_dir(&self, ix: usize) -> Option<DataDirectory> {
+        self.data_dirs.get(ix).copied().filter(|d| d.rva != 0)
+    }
+
+    /// End of the raw data of the last section, anything after is overlay data
+    pub fn sections_end(&self) -> u64 {
+        self.sections
+            .iter()
+            .filter(|s| s.raw_size > 0)
+            .map(|s| s.raw_offset as u64 + s.raw_size as u64)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// File range of the authenticode certificate table
+    pub fn certificate_table(&self) -> Option<std::ops::Range<u64>> {
+        self.data_dir(IMAGE_DIRECTORY_ENTRY_SECURITY)
+            .filter(|d| d.size > 0)
+            .map(|d| d.rva as u64..d.rva as u64 + d.size as u64)
+    }
+
+    pub fn rva_to_offset(&self, rva: u32) -> Option<u64> {
+        self.sections.iter().find_map(|s| {
+            let size = s.virtual_size.max(s.raw_size);
+            let start = s.virtual_address;
+            let end = start.checked_add(size)?;
+            (rva >= start && rva < end).then(|| (rva - start) as u64 + s.raw_offset as u64)
+        })
+    }
+
+    fn read_rva<R: Read + Seek>(&self, mut rdr: R, rva: u32, size: u32) -> anyhow::Result<Vec<u8>> {
+        let offset = self.rva_to_offset(rva).context("RVA outside of sections")?;
+        // The size comes from the header, so it's checked before allocating
+        let len = rdr.seek(SeekFrom::End(0))?;
+        anyhow::ensure!(
+            offset.saturating_add(size as u64) <= len,
+            "RVA {rva:#x} with size {size:#x} outside of the file"
+        );
+        rdr.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; size as usize];
+        rdr.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Reads the version resource, `None` if the image has no version resource
+    pub fn version_info<R: Read + Seek>(&self, mut rdr: R) -> anyhow::Result<Option<VersionInfo>> {
+        let Some(dir) = self.data_dir(IMAGE_DIRECTORY_ENTRY_RESOURCE) else {
+            return Ok(None);
+        };
+        let rsrc = self.read_rva(&mut rdr, dir.rva, dir.size)?;
+
+        // Type -> Name -> Language, always take the first entry below the version type
+        let Some(names) = find_resource_entry(&rsrc, 0, Some(RT_VERSION)) else {
+            return Ok(None);
+        };
+        let names = names.offset & 0x7FFF_FFFF;
+        let langs = find_resource_entry(&rsrc, names as usize, None)
+            .context("Empty version resource")?
+            .offset
+            & 0x7FFF_FFFF;
+        let lang =
+            find_resource_entry(&rsrc, langs as usize, None).context("Empty version resource")?;
+
+        let data_entry: ResourceDataEntry = rsrc
+            .get(
+                lang.offset as usize
+                    ..lang.offset as usize + std::mem::size_of::<ResourceDataEntry>(),
+            )
+            .map(bytemuck::pod_read_unaligned)
+            .context("Invalid resource data entry")?;
+        let data = self.read_rva(&mut rdr, data_entry.rva, data_entry.size)?;
+
+        parse_version_info(&data, lang.name_or_id as u16).map(Some)
+    }
+}
+
+/// The PE stub of a setup and the location of the overlay appended to it
+#[derive(Debug, Clone, Serialize)]
+pub struct StubInfo {
+    pub overlay_offset: u64,
+    /// Either the start of the certificate table or the end of the file
+    pub overlay_end: u64,
+    pub file_size: u64,
+    pub certificate_table: Option<std::ops::Range<u64>>,
+    pub version: Option<VersionInfo>,
+}
+
+impl StubInfo {
+    pub fn read<R: Read + Seek>(mut rdr: R) -> anyhow::Result<Self> {
+        let file_size = rdr.seek(SeekFrom::End(0))?;
+        let pe = PeFile::parse(&mut rdr)?;
+        let overlay_offset = pe.sections_end().min(file_size);
+        let certificate_table = pe.certificate_table();
+        let overlay_end = certificate_table
+            .as_ref()
+            .map(|c| c.start)
+            .filter(|&start| start >= overlay_offset)
+            .unwrap_or(file_size);
+
+        // A broken version resource should not prevent reading the setup
+        let version = pe.version_info(&mut rdr).unwrap_or_else(|err| {
+            log::warn!("Invalid stub version info: {err}");
+            None
+        });
+
+        Ok(Self {
+            overlay_offset,
+            overlay_end,
+            file_size,
+            certificate_table,
+            version,
+        })
+    }
+
+    pub fn overlay_size(&self) -> u64 {
+        self.overlay_end - self.overlay_offset
+    }
+}
+
+fn find_resource_entry(rsrc: &[u8], dir: usize, id: Option<u32>) -> Option<ResourceDirectoryEntry> {
+    let hdr_size = std::mem::size_of::<ResourceDirectory>();
+    let hdr: ResourceDirectory = bytemuck::pod_read_unaligned(rsrc.get(dir..dir + hdr_size)?);
+    let num = hdr.num_named as usize + hdr.num_ids as usize;
+    let entries = rsrc.get(dir + hdr_size..)?;
+    entries
+        .chunks_exact(std::mem::size_of::<ResourceDirectoryEntry>())
+        .take(num)
+        .map(bytemuck::pod_read_unaligned::<ResourceDirectoryEntry>)
+        .find(|e| id.is_none_or(|id| e.name_or_id == id))
+}
+
+fn align4(v: usize) -> usize {
+    (v + 3) & !3
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+/// Reads a null-terminated UTF-16 string, returns the string and the offset after the terminator
+fn read_utf16z(data: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut chars = Vec::new();
+    let mut pos = offset;
+    loop {
+        let c = read_u16(data, pos)?;
+        pos += 2;
+        if c == 0 {
+            break;
+        }
+        chars.push(c);
+    }
+    Some((String::from_utf16_lossy(&chars), pos))
+}
+
+/// Block of the version resource tree, positions are absolute within the resource data
+struct VerBlock<'a> {
+    key: String,
+    value: &'a [u8],
+    is_text: bool,
+    children: std::ops::Range<usize>,
+}
+
+fn ver_block(data: &[u8], start: usize) -> Option<VerBlock<'_>> {
+    let len = read_u16(data, start)? as usize;
+    let value_len = read_u16(data, start + 2)? as usize;
+    let is_text = read_u16(data, start + 4)? == 1;
+    let end = (start + len).min(data.len());
+    if len < 6 {
+        return None;
+    }
+
+    let (key, key_end) = read_utf16z(&data[..end], start + 6)?;
+    let value_start = align4(key_end).min(end);
+    let value_size = if is_text { value_len * 2 } else { value_len };
+    let value_end = (value_start + value_size).min(end);
+    Some(VerBlock {
+        key,
+        value: &data[value_start..value_end],
+        is_text,
+        children: align4(value_end).min(end)..end,
+    })
+}
+
+fn ver_children<'a>(data: &'a [u8], block: &VerBlock<'_>) -> Vec<VerBlock<'a>> {
+    let mut children = Vec::new();
+    let mut pos = block.children.start;
+    while pos + 6 <= block.children.end {
+        let Some(len) = read_u16(data, pos).filter(|&len| len > 0) else {
+            break;
+        };
+        let Some(child) = ver_block(data, pos) else {
+            break;
+        };
+        children.push(child);
+        pos = align4(pos + len as usize);
+    }
+    children
+}
+
+fn utf16_value(value: &[u8]) -> String {
+    let chars: Vec<u16> = value
+        .chunks_exact(2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .collect();
+    String::from_utf16_lossy(&chars)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+pub fn parse_version_info(data: &[u8], language: u16) -> anyhow::Result<VersionInfo> {
+    let root = ver_block(data, 0).context("Invalid version info")?;
+    if root.key != "VS_VERSION_INFO" {
+        anyhow::bail!("Invalid version info key: {}", root.key);
+    }
+
+    let fixed: FixedFileInfo = root
+        .value
+        .get(..std::mem::size_of::<FixedFileInfo>())
+        .map(bytemuck::pod_read_unaligned)
+        .context("Missing fixed file info")?;
+    if fixed.signature != VS_FIXEDFILEINFO_SIGNATURE {
+        anyhow::bail!("Invalid fixed file info signature: {:#x}", {
+            fixed.signature
+        });
+    }
+
+    let mut strings = BTreeMap::new();
+    for child in ver_children(data, &root) {
+        if child.key != "StringFileInfo" {
+            continue;
+        }
+        for table in ver_children(data, &child) {
+            for s in ver_children(data, &table) {
+                if s.is_text {
+                    strings.insert(s.key, utf16_value(s.value));
+                }
+            }
+        }
+    }
+
+    Ok(VersionInfo {
+        file_version: split_version(fixed.file_version_ms, fixed.file_version_ls),
+        product_version: split_version(fixed.product_version_ms, fixed.product_version_ls),
+        language,
+        strings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16z(s: &str) -> Vec<u8> {
+        s.encode_utf16()
+            .chain(std::iter::once(0))
+            .flat_map(|c| c.to_le_bytes())
+            .collect()
+    }
+
+    fn block(
+        key: &str,
+        value: &[u8],
+        value_len: u16,
+        is_text: bool,
+        children: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut data = vec![0u8; 6];
+        data.extend(utf16z(key));
+        data.resize(align4(data.len()), 0);
+        data.extend_from_slice(value);
+        for child in children {
+            data.resize(align4(data.len()), 0);
+            data.extend_from_slice(child);
+        }
+        let len = data.len() as u16;
+        data[0..2].copy_from_slice(&len.to_le_bytes());
+        data[2..4].copy_from_slice(&value_len.to_le_bytes());
+        data[4..6].copy_from_slice(&(is_text as u16).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn version_info() {
+        let fixed = FixedFileInfo {
+            signature: VS_FIXEDFILEINFO_SIGNATURE,
+            struct_version: 0x10000,
+            file_version_ms: 0x0001_0053,
+            file_version_ls: 0x0001_0000,
+            product_version_ms: 0x0001_0053,
+            product_version_ls: 0,
+            file_flags_mask: 0,
+            file_flags: 0,
+            file_os: 0,
+            file_type: 0,
+            file_subtype: 0,
+            file_date_ms: 0,
+            file_date_ls: 0,
+        };
+        let value = utf16z("Wizet");
+        let s = block("CompanyName", &value, (value.len() / 2) as u16, true, &[]);
+        let table = block("040904b0", &[], 0, true, &[s]);
+        let sfi = block("StringFileInfo", &[], 0, true, &[table]);
+        let root = block(
+            "VS_VERSION_INFO",
+            bytemuck::bytes_of(&fixed),
+            std::mem::size_of::<FixedFileInfo>() as u16,
+            false,
+            &[sfi],
+        );
+
+        let info = parse_version_info(&root, 0x409).unwrap();
+        assert_eq!(info.file_version, [1, 83, 1, 0]);
+        assert_eq!(info.strings.get("CompanyName").unwrap(), "Wizet");
+    }
+}
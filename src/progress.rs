@@ -0,0 +1,178 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Read},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// Copying the embedded entries out of the setup
+    Entries,
+    /// Decompressing the client archives
+    Archives,
+    /// Applying a patch, the bytes are those of the compressed patch
+    Patch,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Entries => "entries",
+            Self::Archives => "archives",
+            Self::Patch => "patch",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum ProgressEvent {
+    /// A stage begins, `files` is unknown for patches
+    Start {
+        stage: Stage,
+        files: Option<u64>,
+        bytes: u64,
+    },
+    /// A file was written or removed, the size is 0 if unknown
+    File {
+        name: String,
+        size: u64,
+    },
+    /// Bytes of the stage were processed
+    Bytes(u64),
+    Finish,
+}
+
+/// Receives the progress of an extraction or a patch, events may come from several
+/// worker threads at once
+pub trait Progress: Send + Sync {
+    fn on_event(&self, event: ProgressEvent);
+}
+
+impl<F: Fn(ProgressEvent) + Send + Sync> Progress for F {
+    fn on_event(&self, event: ProgressEvent) {
+        self(event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn on_event(&self, _event: ProgressEvent) {}
+}
+
+/// Reports the bytes read from the inner reader
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a dyn Progress,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a dyn Progress) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.progress.on_event(ProgressEvent::Bytes(n as u64));
+        }
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for ProgressReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        if amt > 0 {
+            self.progress.on_event(ProgressEvent::Bytes(amt as u64));
+        }
+    }
+}
+
+/// Progress bar of the CLI, the message shows the files done
+pub struct BarProgress {
+    bar: ProgressBar,
+    files: AtomicU64,
+    total_files: AtomicU64,
+}
+
+impl BarProgress {
+    pub fn new(bar: ProgressBar, name: &str) -> Self {
+        let style = ProgressStyle::with_template(
+            "{prefix:>24} {msg:<16} [{bar:32}] {bytes}/{total_bytes} ({bytes_per_sec})",
+        )
+        .unwrap()
+        .progress_chars("=> ");
+        bar.set_style(style);
+        bar.set_prefix(name.to_string());
+        Self {
+            bar,
+            files: AtomicU64::new(0),
+            total_files: AtomicU64::new(0),
+        }
+    }
+
+    pub fn finish(&self, msg: &'static str) {
+        self.bar.finish_with_message(msg);
+    }
+}
+
+impl Progress for BarProgress {
+    fn on_event(&self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Start {
+                stage,
+                files,
+                bytes,
+            } => {
+                self.files.store(0, Ordering::Relaxed);
+                self.total_files
+                    .store(files.unwrap_or(0), Ordering::Relaxed);
+                self.bar.set_length(bytes);
+                self.bar.set_position(0);
+                self.bar.set_message(stage.to_string());
+            }
+            ProgressEvent::File { .. } => {
+                let done = self.files.fetch_add(1, Ordering::Relaxed) + 1;
+                match self.total_files.load(Ordering::Relaxed) {
+                    0 => self.bar.set_message(format!("{done} files")),
+                    total => self.bar.set_message(format!("{done}/{total} files")),
+                }
+            }
+            ProgressEvent::Bytes(n) => self.bar.inc(n),
+            ProgressEvent::Finish => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn reader_events() {
+        let events = Mutex::new(Vec::new());
+        let progress = |ev: ProgressEvent| events.lock().unwrap().push(ev);
+        let mut rdr = ProgressReader::new(&b"patch data"[..], &progress);
+        let mut buf = [0u8; 4];
+        rdr.read_exact(&mut buf).unwrap();
+        rdr.fill_buf().unwrap();
+        rdr.consume(6);
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(events, [ProgressEvent::Bytes(4), ProgressEvent::Bytes(6)]);
+    }
+}
@@ -0,0 +1,371 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+};
+
+use anyhow::Context;
+use humansize::{SizeFormatter, DECIMAL};
+use serde::Serialize;
+
+use crate::{
+    pe::StubInfo,
+    setup::{
+        check_entry_file_names, entry_file_name, is,
+        nfo300::{self, Nfo300Entry},
+        Entry, Setup,
+    },
+    util::read_up_to,
+    SetupOpt,
+};
+
+/// Result of re-serialising a setup and comparing it with the original
+#[derive(Debug, Clone, Serialize)]
+pub struct RebuildReport {
+    pub format: &'static str,
+    pub original_size: u64,
+    pub rebuilt_size: u64,
+    /// Everything in front of the container is copied from the original
+    pub container: Range<u64>,
+    pub mismatches: u64,
+    pub first_mismatch: Option<u64>,
+    /// Entries, whose header or data differ
+    pub differing: Vec<String>,
+    /// Data after the container, which isn't the certificate table
+    pub trailing: Option<Range<u64>>,
+}
+
+impl RebuildReport {
+    pub fn is_identical(&self) -> bool {
+        self.mismatches == 0 && self.original_size == self.rebuilt_size
+    }
+
+    pub fn log(&self) {
+        log::info!(
+            "{} container: {:#x}..{:#x}, rebuilt {} of {}",
+            self.format,
+            self.container.start,
+            self.container.end,
+            SizeFormatter::new(self.rebuilt_size, DECIMAL),
+            SizeFormatter::new(self.original_size, DECIMAL)
+        );
+        if let Some(pos) = self.first_mismatch {
+            log::warn!("{} bytes differ, the first at {pos:#x}", self.mismatches);
+        }
+        for name in self.differing.iter() {
+            log::warn!("\tDiffers: {name}");
+        }
+        if let Some(trailing) = &self.trailing {
+            log::warn!(
+                "Trailing data: {:#x}..{:#x} ({})",
+                trailing.start,
+                trailing.end,
+                SizeFormatter::new(trailing.end - trailing.start, DECIMAL)
+            );
+        }
+    }
+}
+
+/// Compares the written data with the original, a copy is kept if requested
+struct Comparer<R> {
+    original: R,
+    pos: u64,
+    mismatches: u64,
+    first_mismatch: Option<u64>,
+    copy: Option<BufWriter<File>>,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> Write for Comparer<R> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.resize(data.len(), 0);
+        let n = read_up_to(&mut self.original, &mut self.buf)?;
+        let differ = data
+            .iter()
+            .zip(&self.buf[..n])
+            .position(|(a, b)| a != b)
+            .unwrap_or(n);
+        if differ < data.len() {
+            self.first_mismatch.get_or_insert(self.pos + differ as u64);
+            let same = data[..n].iter().zip(&self.buf[..n]).filter(|(a, b)| a == b);
+            // Bytes past the end of the original never match
+            self.mismatches += (data.len() - same.count()) as u64;
+        }
+        if let Some(copy) = &mut self.copy {
+            copy.write_all(data)?;
+        }
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.copy {
+            Some(copy) => copy.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Data of an extracted entry, the header is rebuilt with its actual size
+fn entry_file(entries_dir: &Path, name: &str) -> anyhow::Result<(File, u64)> {
+    let path = entries_dir.join(entry_file_name(name));
+    let file = File::open(&path).with_context(|| format!("Open entry: {}", path.display()))?;
+    let len = file.metadata()?.len();
+    Ok((file, len))
+}
+
+fn write_nfo300<R: Read>(
+    cmp: &mut Comparer<R>,
+    entries: Vec<Nfo300Entry>,
+    entries_dir: &Path,
+) -> anyhow::Result<Vec<String>> {
+    let mut files = Vec::with_capacity(entries.len());
+    let mut header_entries = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let (file, len) = entry_file(entries_dir, &entry.name)?;
+        let size = i32::try_from(len).context("Entry too large")?;
+        header_entries.push(Nfo300Entry { size, ..entry });
+        files.push(file);
+    }
+
+    let mut differing = Vec::new();
+    let mismatches = cmp.mismatches;
+    cmp.write_all(&nfo300::header_bytes(&header_entries))?;
+    if cmp.mismatches > mismatches {
+        differing.push("<header>".to_string());
+    }
+    for (entry, mut file) in header_entries.into_iter().zip(files) {
+        let mismatches = cmp.mismatches;
+        io::copy(&mut file, cmp)?;
+        if cmp.mismatches > mismatches {
+            differing.push(entry.name);
+        }
+    }
+    Ok(differing)
+}
+
+/// Writes the header and the encoded entries, each entry record is the attributes
+/// followed by the data
+fn write_is<R: Read>(
+    cmp: &mut Comparer<R>,
+    header: &is::IsHeader,
+    entries: Vec<is::IsEntry>,
+    entries_dir: &Path,
+) -> anyhow::Result<Vec<String>> {
+    let mut differing = Vec::new();
+    let mismatches = cmp.mismatches;
+    cmp.write_all(bytemuck::bytes_of(header))?;
+    if cmp.mismatches > mismatches {
+        differing.push("<header>".to_string());
+    }
+
+    let mut buf = vec![0u8; 64 * 1024];
+    for entry in entries {
+        let mismatches = cmp.mismatches;
+        let (mut file, len) = entry_file(entries_dir, entry.name())?;
+        let mut attr = *entry.attributes();
+        attr.file_len = u32::try_from(len).context("Entry too large")?;
+        cmp.write_all(bytemuck::bytes_of(&attr))?;
+
+        let key = is::entry_key(&entry);
+        let mut pos = 0;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            is::encode_at(&mut buf[..n], &key, pos);
+            cmp.write_all(&buf[..n])?;
+            pos += n as u64;
+        }
+        if cmp.mismatches > mismatches {
+            differing.push(entry.name().to_string());
+        }
+    }
+    Ok(differing)
+}
+
+/// Re-serialises the container of the setup from the entries extracted to
+/// `entries_dir` and compares it byte-for-byte with the original. The stub in front of
+/// the container and a certificate table after it are taken from the original
+pub fn rebuild_check(
+    setup: &mut SetupOpt,
+    entries_dir: &Path,
+    out: Option<&Path>,
+) -> anyhow::Result<RebuildReport> {
+    let path = setup.path().to_path_buf();
+    let mut original = BufReader::new(File::open(&path)?);
+    let original_size = original.seek(SeekFrom::End(0))?;
+    original.rewind()?;
+    let mut cmp = Comparer {
+        original,
+        pos: 0,
+        mismatches: 0,
+        first_mismatch: None,
+        copy: out.map(File::create).transpose()?.map(BufWriter::new),
+        buf: Vec::new(),
+    };
+
+    let start = setup.container_offset();
+    let mut head = BufReader::new(File::open(&path)?).take(start);
+    io::copy(&mut head, &mut cmp)?;
+
+    let differing = match setup {
+        SetupOpt::Nfo300(setup, _) => {
+            let entries = setup.entries()?;
+            check_entry_file_names(entries.iter().map(|e| e.name.as_str()))?;
+            write_nfo300(&mut cmp, entries, entries_dir)?
+        }
+        SetupOpt::Is(setup, _) => {
+            let header = *setup.header();
+            let entries = setup.entries()?;
+            check_entry_file_names(entries.iter().map(|e| e.name()))?;
+            write_is(&mut cmp, &header, entries, entries_dir)?
+        }
+    };
+    let container = start..cmp.pos;
+
+    let stub = StubInfo::read(BufReader::new(File::open(&path)?)).ok();
+    let cert = stub.and_then(|s| s.certificate_table);
+    let mut trailing = None;
+    match cert {
+        Some(cert) if cert.start == container.end => {
+            let mut rdr = BufReader::new(File::open(&path)?);
+            rdr.seek(SeekFrom::Start(cert.start))?;
+            io::copy(&mut rdr.take(cert.end - cert.start), &mut cmp)?;
+            if cert.end < original_size {
+                trailing = Some(cert.end..original_size);
+            }
+        }
+        Some(cert) if cert.start > container.end => trailing = Some(container.end..cert.start),
+        _ if original_size > container.end => trailing = Some(container.end..original_size),
+        _ => {}
+    }
+    cmp.flush()?;
+
+    Ok(RebuildReport {
+        format: setup.format_name(),
+        original_size,
+        rebuilt_size: cmp.pos,
+        container,
+        mismatches: cmp.mismatches,
+        first_mismatch: cmp.first_mismatch,
+        differing,
+        trailing,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use crate::{
+        cancel::CancelToken,
+        pack::tests::{nfo300_setup, split_setups, stub},
+        progress::NoProgress,
+        util::unique_temp_dir,
+    };
+
+    use super::*;
+
+    fn check(setup_path: &Path, entries: &Path) -> RebuildReport {
+        let mut setup = SetupOpt::open(setup_path).unwrap();
+        let _ = std::fs::remove_dir_all(entries);
+        std::fs::create_dir_all(entries).unwrap();
+        setup
+            .extract_entries(entries, &NoProgress, &CancelToken::new())
+            .unwrap();
+        rebuild_check(&mut setup, entries, None).unwrap()
+    }
+
+    #[test]
+    fn rebuild_setups() {
+        let dir = unique_temp_dir("mssetup_rebuild_test");
+        let nfo = nfo300_setup(&dir, &[("Sound.wz", b"sound")]);
+
+        let entries = dir.join("entries");
+        let report = check(&nfo, &entries);
+        assert!(report.is_identical(), "{report:?}");
+        assert_eq!(report.container.start, 0x400);
+
+        // Unknown data after the container is flagged
+        let mut data = std::fs::read(&nfo).unwrap();
+        let len = data.len() as u64;
+        data.extend(b"trailing");
+        std::fs::write(&nfo, &data).unwrap();
+        let report = check(&nfo, &entries);
+        assert!(!report.is_identical());
+        assert_eq!(report.trailing, Some(len..len + 8));
+
+        // InstallShield container with one encoded entry
+        let zip = std::fs::read(dir.join("vol/Client.zip")).unwrap();
+        let mut hdr = is::IsHeader::zeroed();
+        hdr.signature = *b"InstallShield\0";
+        hdr.num_files = 1;
+        let mut attr = is::IsFileAttributes::zeroed();
+        attr.file_name.0[..10].copy_from_slice(b"Client.zip");
+        attr.file_len = zip.len() as u32;
+        let mut key = b"Client.zip".to_vec();
+        for (k, m) in key.iter_mut().zip([0x13, 0x35, 0x86, 0x07].iter().cycle()) {
+            *k ^= m;
+        }
+        let mut encoded = zip.clone();
+        is::encode_at(&mut encoded, &key, 0);
+        let mut data = stub();
+        data.extend(bytemuck::bytes_of(&hdr));
+        data.extend(bytemuck::bytes_of(&attr));
+        data.extend(&encoded);
+        let is_path = dir.join("Is.exe");
+        std::fs::write(&is_path, &data).unwrap();
+
+        let report = check(&is_path, &entries);
+        assert!(report.is_identical(), "{report:?}");
+        assert_eq!(report.format, "InstallShield");
+        assert_eq!(std::fs::read(entries.join("Client.zip")).unwrap(), zip);
+
+        // A changed entry is attributed by name
+        let mut setup = SetupOpt::open(&is_path).unwrap();
+        let mut changed = zip.clone();
+        changed[0] ^= 0xFF;
+        std::fs::write(entries.join("Client.zip"), &changed).unwrap();
+        let rebuilt = dir.join("rebuilt.exe");
+        let report = rebuild_check(&mut setup, &entries, Some(&rebuilt)).unwrap();
+        assert_eq!(report.differing, ["Client.zip"]);
+        assert_eq!(report.mismatches, 1);
+        assert_eq!(std::fs::read(&rebuilt).unwrap().len(), data.len());
+
+        // Entries sharing an extracted file are rejected
+        assert!(check_entry_file_names(["Client.zip", "Data/Skill.wz"]).is_ok());
+        assert!(check_entry_file_names(["a b.zip", "a_b.zip"]).is_err());
+        assert!(check_entry_file_names(["Client.zip", "client.zip"]).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rebuild_encoded_split_setup() {
+        let dir = unique_temp_dir("mssetup_rebuild_split_test");
+        let setups = split_setups(&dir);
+
+        // Each volume is encoded again from the start of its key
+        let entries = dir.join("entries");
+        check(&setups.is, &entries);
+        let mut setup = SetupOpt::open(&setups.is).unwrap();
+        let rebuilt = dir.join("rebuilt.exe");
+        let report = rebuild_check(&mut setup, &entries, Some(&rebuilt)).unwrap();
+        assert!(report.is_identical(), "{report:?}");
+        assert!(std::fs::read(&rebuilt).unwrap() == std::fs::read(&setups.is).unwrap());
+
+        // A change past the first 1 KiB of a volume is attributed to it
+        let name = setups.volumes[1].file_name().unwrap().to_str().unwrap();
+        let mut data = std::fs::read(entries.join(name)).unwrap();
+        data[1500] ^= 0xFF;
+        std::fs::write(entries.join(name), &data).unwrap();
+        let report = rebuild_check(&mut setup, &entries, None).unwrap();
+        assert_eq!(report.differing, [name]);
+        assert_eq!(report.mismatches, 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,680 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Context;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, ResponseBox};
+
+use crate::{
+    cancel::{is_cancelled, CancelToken},
+    filter::EntryFilter,
+    patch::WzPatch,
+    patcher::{WzPatcher, WzPatcherInfo},
+    progress::{Progress, ProgressEvent, Stage},
+    ExtractOptions, SetupOpt,
+};
+
+/// Error with the HTTP status of the response
+#[derive(Debug)]
+struct HttpError(u16, String);
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.1)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+fn not_found(what: &str) -> anyhow::Error {
+    HttpError(404, format!("Not found: {what}")).into()
+}
+
+fn bad_request(msg: impl Into<String>) -> anyhow::Error {
+    HttpError(400, msg.into()).into()
+}
+
+fn conflict(msg: impl Into<String>) -> anyhow::Error {
+    HttpError(409, msg.into()).into()
+}
+
+/// Decodes the pairs of a query string, keys may repeat
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    let decode = |s: &str| {
+        percent_decode_str(&s.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+/// Relative path of a request, which can't leave the directory it is joined to
+fn safe_rel_path(path: &str) -> anyhow::Result<PathBuf> {
+    let path = percent_decode_str(path)
+        .decode_utf8_lossy()
+        .replace('\\', "/");
+    let rel = PathBuf::from(path.trim_start_matches('/'));
+    let normal = rel.components().all(|c| matches!(c, Component::Normal(_)));
+    if rel.as_os_str().is_empty() || !normal {
+        return Err(bad_request(format!("Invalid path: {path}")));
+    }
+    Ok(rel)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobState {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+/// Progress of a job, polled by the clients
+#[derive(Default)]
+struct JobProgress {
+    stage: Mutex<Option<Stage>>,
+    bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    files: AtomicU64,
+}
+
+impl Progress for JobProgress {
+    fn on_event(&self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Start { stage, bytes, .. } => {
+                *self.stage.lock().unwrap() = Some(stage);
+                self.bytes.store(0, Ordering::Relaxed);
+                self.total_bytes.store(bytes, Ordering::Relaxed);
+                self.files.store(0, Ordering::Relaxed);
+            }
+            ProgressEvent::File { .. } => {
+                self.files.fetch_add(1, Ordering::Relaxed);
+            }
+            ProgressEvent::Bytes(n) => {
+                self.bytes.fetch_add(n, Ordering::Relaxed);
+            }
+            ProgressEvent::Finish => {}
+        }
+    }
+}
+
+struct Job {
+    kind: &'static str,
+    out_dir: PathBuf,
+    progress: Arc<JobProgress>,
+    cancel: CancelToken,
+    result: Mutex<Option<(JobState, Option<String>)>>,
+}
+
+impl Job {
+    fn status(&self, id: u64) -> serde_json::Value {
+        let (state, error) = self
+            .result
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or((JobState::Running, None));
+        json!({
+            "id": id,
+            "kind": self.kind,
+            "state": state,
+            "error": error,
+            "out_dir": self.out_dir,
+            "stage": *self.progress.stage.lock().unwrap(),
+            "bytes": self.progress.bytes.load(Ordering::Relaxed),
+            "total_bytes": self.progress.total_bytes.load(Ordering::Relaxed),
+            "files": self.progress.files.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Setups and patches known to the service, registered by path or uploaded into its
+/// directory
+#[derive(Default)]
+struct Registry(Mutex<BTreeMap<u64, PathBuf>>);
+
+impl Registry {
+    fn get(&self, id: u64) -> anyhow::Result<PathBuf> {
+        let items = self.0.lock().unwrap();
+        items
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| not_found(&id.to_string()))
+    }
+
+    fn list(&self) -> serde_json::Value {
+        let items = self.0.lock().unwrap();
+        items
+            .iter()
+            .map(|(id, path)| json!({ "id": id, "path": path }))
+            .collect()
+    }
+}
+
+/// Local HTTP API on top of the extraction and patching, long operations run as jobs,
+/// which are polled through `/jobs/{id}`. Paths are relative to the service directory
+/// and can't leave it:
+///
+/// ```text
+/// GET    /setups                     registered setups
+/// POST   /setups?path=<file>         register a setup file, or upload it as body with ?name=
+/// GET    /setups/{id}/entries        embedded entries
+/// POST   /setups/{id}/extract        extract, takes ?include=, ?exclude= and ?recursive=true
+/// GET    /setups/{id}/files/{path}   file of the extracted client
+/// GET    /patches                    registered patches
+/// POST   /patches?path=<file>        register a patch file, or upload it as body with ?name=
+/// GET    /patches/{id}               added, modified and removed files
+/// POST   /patches/{id}/apply         apply to ?client=<dir> or ?setup=<id>, output in ?out=
+/// GET    /jobs                       all jobs
+/// GET    /jobs/{id}                  state and progress of a job
+/// DELETE /jobs/{id}                  cancel a job
+/// ```
+///
+/// An apply leaves the client as it is, the removed files are listed by the patch info.
+/// Jobs writing into the output of a running job are rejected with 409
+pub struct Service {
+    dir: PathBuf,
+    next_id: AtomicU64,
+    setups: Registry,
+    patches: Registry,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+}
+
+impl Service {
+    pub fn new(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir).context("Create service dir")?;
+        Ok(Self {
+            dir,
+            next_id: AtomicU64::new(1),
+            setups: Registry::default(),
+            patches: Registry::default(),
+            jobs: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Handles the requests of the server, each on its own thread
+    pub fn run(self: Arc<Self>, server: tiny_http::Server) {
+        for request in server.incoming_requests() {
+            let service = self.clone();
+            std::thread::spawn(move || service.handle(request));
+        }
+    }
+
+    fn handle(&self, mut request: Request) {
+        let method = request.method().clone();
+        let url = request.url().to_string();
+        let response = self.route(&mut request).unwrap_or_else(|err| {
+            let status = err.downcast_ref::<HttpError>().map_or(500, |err| err.0);
+            if status == 500 {
+                log::error!("Error: {err:#} for: {method} {url}");
+            }
+            json_response(status, &json!({ "error": format!("{err:#}") }))
+        });
+        if let Err(err) = request.respond(response) {
+            log::warn!("Response failed for {method} {url}: {err}");
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Directory of the client extracted from a setup
+    fn client_dir(&self, id: u64) -> anyhow::Result<PathBuf> {
+        let path = self.setups.get(id)?;
+        let name = path.file_stem().context("Invalid setup path")?;
+        Ok(self.dir.join("clients").join(id.to_string()).join(name))
+    }
+
+    fn route(&self, request: &mut Request) -> anyhow::Result<ResponseBox> {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let query = parse_query(query);
+        let param = |key: &str| {
+            query
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        let params = |key: &str| {
+            query
+                .iter()
+                .filter(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+                .collect::<Vec<_>>()
+        };
+        let segments = path.trim_matches('/').splitn(4, '/').collect::<Vec<_>>();
+        let id = |ix: usize| {
+            segments[ix]
+                .parse::<u64>()
+                .map_err(|_| bad_request(format!("Invalid id: {}", segments[ix])))
+        };
+
+        let method = request.method().clone();
+        match (&method, segments.as_slice()) {
+            (Method::Get, ["setups"]) => Ok(json_response(200, &self.setups.list())),
+            (Method::Post, ["setups"]) => {
+                // Fails early for files, which are no setups
+                let path =
+                    self.register(request, param("path"), param("name"), "setup.exe", |p| {
+                        SetupOpt::open(p).map(|_| ())
+                    })?;
+                let id = self.next_id();
+                self.setups.0.lock().unwrap().insert(id, path);
+                Ok(json_response(201, &json!({ "id": id })))
+            }
+            (Method::Get, ["setups", _, "entries"]) => {
+                let mut setup = SetupOpt::open(self.setups.get(id(1)?)?)?;
+                let entries = setup
+                    .entry_list()?
+                    .into_iter()
+                    .map(|(name, size)| json!({ "name": name, "size": size }))
+                    .collect::<Vec<_>>();
+                Ok(json_response(200, &json!(entries)))
+            }
+            (Method::Post, ["setups", _, "extract"]) => {
+                let setup_id = id(1)?;
+                let path = self.setups.get(setup_id)?;
+                let client_dir = self.client_dir(setup_id)?;
+                let out_dir = client_dir.parent().unwrap().to_path_buf();
+                let filter = EntryFilter::new(&params("include"), &params("exclude"))?;
+                let recursive = param("recursive") == Some("true");
+                let job = self.start_job("extract", client_dir, move |id, job| {
+                    let opts = ExtractOptions {
+                        keep_tmp: false,
+                        recursive,
+                        manifest: true,
+                        filter,
+                        cancel: job.cancel.clone(),
+                    };
+                    let mut setup = SetupOpt::open(&path)?;
+                    setup.extract_and_report(id as usize, &out_dir, &opts, &*job.progress)
+                })?;
+                Ok(json_response(202, &json!({ "job": job })))
+            }
+            (Method::Get, ["setups", _, "files", file]) => {
+                let path = self.client_dir(id(1)?)?.join(safe_rel_path(file)?);
+                let file = File::open(&path).map_err(|_| not_found(&path.display().to_string()))?;
+                Ok(Response::from_file(file).boxed())
+            }
+            (Method::Get, ["patches"]) => Ok(json_response(200, &self.patches.list())),
+            (Method::Post, ["patches"]) => {
+                // Reads the header to reject other files
+                let path =
+                    self.register(request, param("path"), param("name"), "client.patch", |p| {
+                        WzPatch::open(p).map(|_| ())
+                    })?;
+                let id = self.next_id();
+                self.patches.0.lock().unwrap().insert(id, path);
+                Ok(json_response(201, &json!({ "id": id })))
+            }
+            (Method::Get, ["patches", _]) => {
+                let mut patch = WzPatch::open(self.patches.get(id(1)?)?)?;
+                let mut info = WzPatcherInfo::default();
+                patch.process(&mut info, &CancelToken::new())?;
+                Ok(json_response(
+                    200,
+                    &json!({ "version": patch.version(), "files": info }),
+                ))
+            }
+            (Method::Post, ["patches", _, "apply"]) => {
+                let patch_path = self.patches.get(id(1)?)?;
+                let client = match (param("client"), param("setup")) {
+                    (Some(client), _) => self.service_path(client)?,
+                    (None, Some(setup)) => {
+                        let setup = setup
+                            .parse()
+                            .map_err(|_| bad_request(format!("Invalid setup: {setup}")))?;
+                        self.client_dir(setup)?
+                    }
+                    (None, None) => return Err(bad_request("Missing client or setup")),
+                };
+                let out_dir = match param("out") {
+                    Some(out) => self.service_path(out)?,
+                    None => self.dir.join("patched").join(self.next_id().to_string()),
+                };
+                let job = self.start_job("patch", out_dir.clone(), move |_, job| {
+                    let mut patch = WzPatch::open(&patch_path)?;
+                    patch.verify_checksum()?;
+                    let mut patcher = WzPatcher::with_out_dir(&client, &out_dir)
+                        .with_progress(job.progress.clone());
+                    patch.process(&mut patcher, &job.cancel)
+                })?;
+                Ok(json_response(202, &json!({ "job": job })))
+            }
+            (Method::Get, ["jobs"]) => {
+                let jobs = self.jobs.lock().unwrap();
+                let jobs = jobs
+                    .iter()
+                    .map(|(id, job)| job.status(*id))
+                    .collect::<Vec<_>>();
+                Ok(json_response(200, &json!(jobs)))
+            }
+            (Method::Get, ["jobs", _]) => {
+                let id = id(1)?;
+                let job = self.job(id)?;
+                Ok(json_response(200, &job.status(id)))
+            }
+            (Method::Delete, ["jobs", _]) => {
+                let id = id(1)?;
+                let job = self.job(id)?;
+                job.cancel.cancel();
+                Ok(json_response(200, &job.status(id)))
+            }
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn job(&self, id: u64) -> anyhow::Result<Arc<Job>> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(&id)
+            .cloned()
+            .ok_or_else(|| not_found(&id.to_string()))
+    }
+
+    /// Path of a request inside the service dir, symlinks leaving it are rejected as well.
+    /// Paths, which don't exist yet, are checked by their nearest existing ancestor
+    fn service_path(&self, path: &str) -> anyhow::Result<PathBuf> {
+        if path.starts_with(['/', '\\']) || Path::new(path).is_absolute() {
+            return Err(bad_request(format!("Not relative to the service dir: {path}")));
+        }
+        let path = self.dir.join(safe_rel_path(path)?);
+        let dir = self.dir.canonicalize()?;
+        let real = path.ancestors().find_map(|p| p.canonicalize().ok());
+        if !real.is_some_and(|real| real.starts_with(&dir)) {
+            return Err(bad_request(format!(
+                "Outside of the service dir: {}",
+                path.display()
+            )));
+        }
+        Ok(path)
+    }
+
+    /// Path of a registered file, either the given path or the uploaded body. Files
+    /// rejected by `check` are a bad request, their uploads are removed
+    fn register(
+        &self,
+        request: &mut Request,
+        path: Option<&str>,
+        name: Option<&str>,
+        default_name: &str,
+        check: impl FnOnce(&Path) -> anyhow::Result<()>,
+    ) -> anyhow::Result<PathBuf> {
+        if let Some(path) = path {
+            let path = self.service_path(path)?;
+            if !path.is_file() {
+                return Err(not_found(&path.display().to_string()));
+            }
+            check(&path).map_err(|err| bad_request(format!("{err:#}")))?;
+            return Ok(path);
+        }
+
+        let name = safe_rel_path(name.unwrap_or(default_name))?;
+        let dir = self.dir.join("uploads").join(self.next_id().to_string());
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(name.file_name().context("Invalid name")?);
+        let received = File::create(&path)
+            .context("Create upload")
+            .and_then(|mut file| {
+                std::io::copy(&mut request.as_reader(), &mut file).context("Receive upload")
+            });
+        let checked =
+            received.and_then(|_| check(&path).map_err(|err| bad_request(format!("{err:#}"))));
+        if let Err(err) = checked {
+            let _ = std::fs::remove_dir_all(&dir);
+            return Err(err);
+        }
+        Ok(path)
+    }
+
+    /// Runs the job on a new thread and returns its id. A job, whose output overlaps the
+    /// one of a running job, is a conflict as their outputs and cleanups would mix
+    fn start_job(
+        &self,
+        kind: &'static str,
+        out_dir: PathBuf,
+        f: impl FnOnce(u64, &Job) -> anyhow::Result<()> + Send + 'static,
+    ) -> anyhow::Result<u64> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let busy = jobs.iter().find(|(_, job)| {
+            job.result.lock().unwrap().is_none()
+                && (job.out_dir.starts_with(&out_dir) || out_dir.starts_with(&job.out_dir))
+        });
+        if let Some((busy, _)) = busy {
+            return Err(conflict(format!(
+                "Job {busy} is writing to {}",
+                out_dir.display()
+            )));
+        }
+        let id = self.next_id();
+        let job = Arc::new(Job {
+            kind,
+            out_dir,
+            progress: Arc::default(),
+            cancel: CancelToken::new(),
+            result: Mutex::new(None),
+        });
+        jobs.insert(id, job.clone());
+        drop(jobs);
+        log::info!("Job {id}: {kind}");
+
+        std::thread::spawn(move || {
+            let result = match f(id, &job) {
+                Ok(()) => (JobState::Done, None),
+                Err(err) if is_cancelled(&err) => (JobState::Cancelled, None),
+                Err(err) => {
+                    log::error!("Job {id} failed: {err:#}");
+                    (JobState::Failed, Some(format!("{err:#}")))
+                }
+            };
+            *job.result.lock().unwrap() = Some(result);
+        });
+        Ok(id)
+    }
+}
+
+fn json_response(status: u16, value: &serde_json::Value) -> ResponseBox {
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    Response::from_data(value.to_string())
+        .with_status_code(status)
+        .with_header(header)
+        .boxed()
+}
+
+/// Serves the API on `addr` until the process is stopped
+pub fn serve(addr: &str, dir: &Path) -> anyhow::Result<()> {
+    let service = Arc::new(Service::new(dir)?);
+    let server =
+        tiny_http::Server::http(addr).map_err(|err| anyhow::anyhow!("Bind {addr}: {err}"))?;
+    log::info!("Listening on http://{addr}");
+    service.run(server);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    use crate::{pack::tests::nfo300_setup, patcher::tests::sound_patch, util::unique_temp_dir};
+
+    use super::*;
+
+    fn send(addr: &str, method: &str, path: &str, body: &[u8]) -> (u16, serde_json::Value) {
+        let (status, body) = send_raw(addr, method, path, body);
+        (status, serde_json::from_str(&body).unwrap_or_default())
+    }
+
+    fn send_raw(addr: &str, method: &str, path: &str, body: &[u8]) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: {addr}\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, body.to_string())
+    }
+
+    /// Polls the job until it's no longer running
+    fn wait_job(addr: &str, job: &serde_json::Value) -> serde_json::Value {
+        let job = format!("/jobs/{job}");
+        loop {
+            let (_, res) = send(addr, "GET", &job, b"");
+            if res["state"] != "running" {
+                break res;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    fn start_service(dir: &Path) -> (Arc<Service>, String) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap().to_string();
+        let service = Arc::new(Service::new(dir).unwrap());
+        std::thread::spawn({
+            let service = service.clone();
+            move || service.run(server)
+        });
+        (service, addr)
+    }
+
+    #[test]
+    fn patch_api() {
+        assert_eq!(
+            parse_query("include=*.wz&include=Data%2F*&x"),
+            [
+                ("include".to_string(), "*.wz".to_string()),
+                ("include".to_string(), "Data/*".to_string()),
+                ("x".to_string(), String::new()),
+            ]
+        );
+        assert!(safe_rel_path("Data/../../secret").is_err());
+
+        let dir = unique_temp_dir("mssetup_serve_test");
+        let client = dir.join("service/client");
+        let patch = sound_patch(&client);
+
+        let (service, addr) = start_service(&dir.join("service"));
+
+        let (status, res) = send(&addr, "POST", "/patches?name=84.patch", &patch);
+        assert_eq!(status, 201);
+        let id = res["id"].as_u64().unwrap();
+        let (_, res) = send(&addr, "GET", &format!("/patches/{id}"), b"");
+        assert_eq!(res["version"], 84);
+        assert_eq!(res["files"]["modified_files"][0][0], "Sound.wz");
+
+        // Paths outside of the service dir are rejected
+        let apply = format!("/patches/{id}/apply?client={}", client.display());
+        assert_eq!(send(&addr, "POST", &apply, b"").0, 400);
+        let apply = format!("/patches/{id}/apply?client=client&out=../out");
+        assert_eq!(send(&addr, "POST", &apply, b"").0, 400);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&dir, dir.join("service/link")).unwrap();
+            let apply = format!("/patches/{id}/apply?client=client&out=link/out");
+            assert_eq!(send(&addr, "POST", &apply, b"").0, 400);
+        }
+
+        // The output of a running job can't be shared
+        let busy = service
+            .start_job("busy", dir.join("service/busy"), |_, job| {
+                while !job.cancel.is_cancelled() {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Ok(())
+            })
+            .unwrap();
+        for out in ["busy", "busy/sub"] {
+            let apply = format!("/patches/{id}/apply?client=client&out={out}");
+            assert_eq!(send(&addr, "POST", &apply, b"").0, 409);
+        }
+        send(&addr, "DELETE", &format!("/jobs/{busy}"), b"");
+        let (status, res) = send(
+            &addr,
+            "POST",
+            &format!("/patches/{id}/apply?client=client"),
+            b"",
+        );
+        assert_eq!(status, 202);
+        let res = wait_job(&addr, &res["job"]);
+        assert_eq!(res["state"], "done");
+        let out = PathBuf::from(res["out_dir"].as_str().unwrap());
+        assert_eq!(std::fs::read(out.join("Sound.wz")).unwrap(), b"sound+++");
+        // The removal of the patch leaves the client as it is
+        assert_eq!(std::fs::read(client.join("Old.wz")).unwrap(), b"old");
+
+        assert_eq!(send(&addr, "GET", "/setups/7/entries", b"").0, 404);
+
+        // A rejected upload is removed
+        assert_eq!(send(&addr, "POST", "/setups?name=a.exe", b"MZ").0, 400);
+        let uploads = dir.join("service/uploads");
+        assert_eq!(std::fs::read_dir(&uploads).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn setup_api() {
+        let dir = unique_temp_dir("mssetup_serve_setup_test");
+        let files: [(&str, &[u8]); 2] = [("Data/Skill.wz", b"skill"), ("Sound.wz", b"sound")];
+        let setup_path = nfo300_setup(&dir.join("service/build"), &files);
+        let (_service, addr) = start_service(&dir.join("service"));
+
+        let (status, res) = send(&addr, "POST", "/setups?path=build/Setup.exe", b"");
+        assert_eq!(status, 201);
+        let id = res["id"].as_u64().unwrap();
+        let (_, res) = send(&addr, "GET", &format!("/setups/{id}/entries"), b"");
+        assert_eq!(res[0]["name"], "Client.zip");
+
+        // Only the included files are extracted and served
+        let extract = format!("/setups/{id}/extract?include=dir%3AData");
+        let (status, res) = send(&addr, "POST", &extract, b"");
+        assert_eq!(status, 202);
+        assert_eq!(wait_job(&addr, &res["job"])["state"], "done");
+        let file = format!("/setups/{id}/files/Data/Skill.wz");
+        assert_eq!(
+            send_raw(&addr, "GET", &file, b""),
+            (200, "skill".to_string())
+        );
+        let file = format!("/setups/{id}/files/Sound.wz");
+        assert_eq!(send(&addr, "GET", &file, b"").0, 404);
+        let file = format!("/setups/{id}/files/%2E%2E/%2E%2E/build/Setup.exe");
+        assert_eq!(send(&addr, "GET", &file, b"").0, 400);
+
+        // A job, which fails, reports its error
+        std::fs::remove_file(&setup_path).unwrap();
+        let (_, res) = send(&addr, "POST", &extract, b"");
+        let res = wait_job(&addr, &res["job"]);
+        assert_eq!(res["state"], "failed");
+        assert!(res["error"].as_str().is_some_and(|err| !err.is_empty()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,11 +4,12 @@ use anyhow::Context;
 use bytemuck::{Pod, Zeroable};
 
 use crate::{
+    pe::StubInfo,
     setup::Setup,
     util::find_needle,
 };
 
-use super::Entry;
+use super::{Entry, EntrySpan};
 
 #[derive(Debug, Pod, Clone, Copy, Zeroable)]
 #[repr(C, packed)]
@@ -84,6 +85,12 @@ pub struct IsEntry {
     offset: u64,
 }
 
+impl IsEntry {
+    pub fn attributes(&self) -> &IsFileAttributes {
+        &self.attr
+    }
+}
+
 impl Entry for IsEntry {
     fn name(&self) -> &str {
         let cname = CStr::from_bytes_until_nul(&self.attr.file_name.0[..]).expect("Invalid filename");
@@ -107,10 +114,10 @@ fn decode_byte(b: u8, k: u8) -> u8 {
     !(k ^ b.rotate_right(4))
 }
 
-/*fn encode_byte(b: u8, k: u8) -> u8 {
+fn encode_byte(b: u8, k: u8) -> u8 {
     let b = !b ^ k;
     b.rotate_left(4)
-}*/
+}
 
 fn decode_data(data: &mut [u8], key: &[u8], offset: u32) {
     for (i, b) in data.iter_mut().enumerate() {
@@ -118,6 +125,30 @@ fn decode_data(data: &mut [u8], key: &[u8], offset: u32) {
     }
 }
 
+/// Decodes data at `pos` of an entry, the key restarts every 1024 bytes
+pub fn decode_at(data: &mut [u8], key: &[u8], pos: u64) {
+    for (i, b) in data.iter_mut().enumerate() {
+        let block_pos = (pos + i as u64) % 1024;
+        *b = decode_byte(*b, key[block_pos as usize % key.len()]);
+    }
+}
+
+/// Encodes data at `pos` of an entry, the inverse of [`decode_at`]
+pub fn encode_at(data: &mut [u8], key: &[u8], pos: u64) {
+    for (i, b) in data.iter_mut().enumerate() {
+        let block_pos = (pos + i as u64) % 1024;
+        *b = encode_byte(*b, key[block_pos as usize % key.len()]);
+    }
+}
+
+/// The key of an entry is derived from its file name
+pub fn entry_key(entry: &IsEntry) -> Vec<u8> {
+    let len = entry.attr.file_name.0.iter().position(|&b| b == 0).unwrap();
+    let mut key = entry.attr.file_name.0[..len].to_vec();
+    gen_key(&mut key);
+    key
+}
+
 #[derive(Debug)]
 pub struct EntryReader<'a, R> {
     reader: std::io::Take<&'a mut R>,
@@ -169,12 +200,23 @@ impl<R: Read + Seek> IsSetup<R> {
         })
     }
 
+    pub fn header(&self) -> &IsHeader {
+        &self.hdr
+    }
+
+    /// Offset of the container in the setup file
+    pub fn offset(&self) -> u64 {
+        self.hdr_offset
+    }
+
     pub fn new_detect(mut rdr: R) -> anyhow::Result<Self> {
-        rdr.seek(std::io::SeekFrom::Start(0))?;
+        // Search from the start of the overlay, if the stub can be parsed
+        let start = StubInfo::read(rdr.by_ref()).map_or(0, |stub| stub.overlay_offset);
+        rdr.seek(std::io::SeekFrom::Start(start))?;
         let offset = find_needle(rdr.by_ref(), Self::tag())?.context("No InstallShield tag found")?;
         Self::new(
             rdr,
-            offset
+            start + offset
         )
     }
 }
@@ -207,19 +249,54 @@ impl<R: Read + Seek> Setup for IsSetup<R> {
         let offset = entry.offset + std::mem::size_of::<IsFileAttributes>() as u64;
         self.rdr.seek(SeekFrom::Start(offset))?;
 
-        // Filename is the key
-        let len = entry.attr.file_name.0.iter().position(|&b| b == 0).unwrap();
-        let mut key = entry.attr.file_name.0[..len].to_vec();
-        gen_key(&mut key);
-
         Ok(EntryReader {
             reader: self.rdr.by_ref().take(entry.attr.file_len as u64),
-            key,
+            key: entry_key(entry),
             offset: 0,
         })
     }
+
+    fn entry_span(&self, entry: &Self::Entry) -> EntrySpan {
+        EntrySpan {
+            offset: entry.offset + std::mem::size_of::<IsFileAttributes>() as u64,
+            len: entry.attr.file_len as u64,
+            key: Some(entry_key(entry)),
+        }
+    }
     
     fn size(&self) -> u64 {
         self.size - self.hdr_offset
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::pack::tests::stub;
+
+    use super::*;
+
+    /// InstallShield setup of the files, each is an entry named after it and encoded
+    /// with the key of its name
+    pub(crate) fn is_setup(path: &Path, files: &[PathBuf]) {
+        let mut hdr = IsHeader::zeroed();
+        hdr.signature = *b"InstallShield\0";
+        hdr.num_files = files.len() as u16;
+        let mut data = stub();
+        data.extend(bytemuck::bytes_of(&hdr));
+        for file in files {
+            let name = file.file_name().unwrap().to_str().unwrap();
+            let mut entry = std::fs::read(file).unwrap();
+            let mut attr = IsFileAttributes::zeroed();
+            attr.file_name.0[..name.len()].copy_from_slice(name.as_bytes());
+            attr.file_len = entry.len() as u32;
+            let mut key = name.as_bytes().to_vec();
+            gen_key(&mut key);
+            encode_at(&mut entry, &key, 0);
+            data.extend(bytemuck::bytes_of(&attr));
+            data.extend(entry);
+        }
+        std::fs::write(path, data).unwrap();
+    }
+}
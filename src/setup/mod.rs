@@ -1,8 +1,17 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 
-use crate::util::find_needle;
+use crate::{
+    cancel::{is_cancelled, CancelReader, CancelToken},
+    extract::remove_partial,
+    progress::{Progress, ProgressEvent, ProgressReader, Stage},
+    util::find_needle,
+};
 
 pub mod nfo300;
 pub mod is;
@@ -14,6 +23,99 @@ pub trait Entry {
     fn size(&self) -> u64;
 }
 
+/// Location of the data of an entry in the setup file
+#[derive(Debug, Clone)]
+pub struct EntrySpan {
+    pub offset: u64,
+    pub len: u64,
+    /// Key of encoded InstallShield entries
+    pub key: Option<Vec<u8>>,
+}
+
+/// Seekable reader of a single entry, so it can be read lazily without extracting it
+pub struct SpanReader<R> {
+    rdr: R,
+    span: EntrySpan,
+    pos: u64,
+    /// Whether the inner reader is at `pos`
+    synced: bool,
+}
+
+impl<R: Read + Seek> SpanReader<R> {
+    /// The reader must be a separate handle to the setup file
+    pub fn new(rdr: R, span: EntrySpan) -> Self {
+        Self {
+            rdr,
+            span,
+            pos: 0,
+            synced: false,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.span.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.span.len == 0
+    }
+}
+
+impl<R: Read + Seek> Read for SpanReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.span.len.saturating_sub(self.pos);
+        let n = buf.len().min(remaining as usize);
+        if n == 0 {
+            return Ok(0);
+        }
+        if !self.synced {
+            self.rdr.seek(SeekFrom::Start(self.span.offset + self.pos))?;
+            self.synced = true;
+        }
+
+        let n = self.rdr.read(&mut buf[..n])?;
+        if let Some(key) = self.span.key.as_deref() {
+            is::decode_at(&mut buf[..n], key, self.pos);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SpanReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(off) => self.span.len.checked_add_signed(off),
+            SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+        };
+        let pos = pos.ok_or_else(|| io::Error::other("Seek before the entry start"))?;
+        if pos != self.pos {
+            self.pos = pos;
+            self.synced = false;
+        }
+        Ok(pos)
+    }
+}
+
+/// Name of the file an entry is extracted to
+pub fn entry_file_name(name: &str) -> String {
+    name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_")
+}
+
+/// Fails if two entries would be extracted to the same file, names differing only in
+/// case included as they collide on case-insensitive file systems
+pub fn check_entry_file_names<'a>(names: impl IntoIterator<Item = &'a str>) -> anyhow::Result<()> {
+    let mut seen = HashMap::new();
+    for name in names {
+        let file_name = entry_file_name(name).to_ascii_lowercase();
+        if let Some(other) = seen.insert(file_name, name) {
+            anyhow::bail!("Entries {other:?} and {name:?} map to the same file");
+        }
+    }
+    Ok(())
+}
+
 pub trait Setup {
     type Entry: Entry;
     type EntryReader<'a>: std::io::Read where Self: 'a;
@@ -21,6 +123,7 @@ pub trait Setup {
     fn tag() -> &'static [u8];
     fn entries(&mut self) -> anyhow::Result<Vec<Self::Entry>>;
     fn entry_reader(&mut self, entry: &Self::Entry) -> anyhow::Result<Self::EntryReader<'_>>;
+    fn entry_span(&self, entry: &Self::Entry) -> EntrySpan;
 
     fn size(&self) -> u64;
 
@@ -31,20 +134,49 @@ pub trait Setup {
     }
 
 
-    fn extract_to(&mut self, out_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    fn extract_to(
+        &mut self,
+        out_dir: &Path,
+        progress: &dyn Progress,
+        cancel: &CancelToken,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let entries = self.entries()?;
+        check_entry_file_names(entries.iter().map(|e| e.name()))?;
+        progress.on_event(ProgressEvent::Start {
+            stage: Stage::Entries,
+            files: Some(entries.len() as u64),
+            bytes: entries.iter().map(|e| e.size()).sum(),
+        });
         let mut files = Vec::new();
-        for entry in self.entries()? {
-            let mut reader = self.entry_reader(&entry)?;
-            let name = entry
-                .name()
-                .replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_");
-            let out_path = out_dir.join(name);
+        let res = entries.into_iter().try_for_each(|entry| {
+            cancel.check()?;
+            let reader = CancelReader::new(self.entry_reader(&entry)?, cancel);
+            let mut reader = ProgressReader::new(reader, progress);
+            let out_path = out_dir.join(entry_file_name(entry.name()));
             let mut writer = std::fs::File::create(&out_path)
                 .with_context(|| format!("Failed to create file: {:?}", out_path))?;
-            std::io::copy(&mut reader, &mut writer)
-                .with_context(|| format!("Failed to write to file: {:?}", out_path))?;
+            if let Err(err) = std::io::copy(&mut reader, &mut writer) {
+                // No partial entry is left behind
+                drop(writer);
+                let _ = std::fs::remove_file(&out_path);
+                return Err(err)
+                    .with_context(|| format!("Failed to write to file: {:?}", out_path));
+            }
+            progress.on_event(ProgressEvent::File {
+                name: entry.name().to_string(),
+                size: entry.size(),
+            });
             files.push(out_path);
+            anyhow::Ok(())
+        });
+        if let Err(err) = res {
+            // The entries of a cancelled extraction are removed
+            if is_cancelled(&err) {
+                remove_partial(&files);
+            }
+            return Err(err);
         }
+        progress.on_event(ProgressEvent::Finish);
         Ok(files)
     }
 }
@@ -66,6 +198,10 @@ impl<'a, T: Setup> Setup for &'a mut T {
         (**self).entry_reader(entry)
     }
 
+    fn entry_span(&self, entry: &Self::Entry) -> EntrySpan {
+        (**self).entry_span(entry)
+    }
+
     fn size(&self) -> u64 {
         (**self).size()
     }
@@ -2,9 +2,9 @@ use std::io::{BufRead, Read, Seek, Take};
 
 use anyhow::Context;
 
-use crate::util::{find_needle, MAX_PE_SIZE};
+use crate::{pe::StubInfo, util::{find_needle, MAX_PE_SIZE}};
 
-use super::{Entry, Setup};
+use super::{Entry, EntrySpan, Setup};
 
 #[derive(Debug)]
 pub struct Nfo300Entry {
@@ -24,6 +24,16 @@ impl Entry for Nfo300Entry {
     }
 }
 
+/// Serialises the container header of the entries, the data follows right after it
+pub fn header_bytes(entries: &[Nfo300Entry]) -> Vec<u8> {
+    let mut header = b"NFO300\r\n".to_vec();
+    for entry in entries {
+        let line = format!("\"{}\",\"{}\",\"{}\"\r\n", entry.name, entry.checksum, entry.size);
+        header.extend(line.as_bytes());
+    }
+    header
+}
+
 #[derive(Debug)]
 pub struct EntryReader<'a, R> {
     reader: Take<&'a mut R>,
@@ -53,13 +63,22 @@ impl<R: Read + Seek> Nfo300Setup<R> {
         })
     }
 
+    /// Offset of the container in the setup file
+    pub fn offset(&self) -> u64 {
+        self.nfo_offset
+    }
+
     pub fn new_detect(mut rdr: R) -> anyhow::Result<Self> where R: BufRead {
-        //let offset = find_padding_data(rdr.by_ref(), MAX_PE_SIZE)?.context("No padding data found")?;
-        rdr.seek(std::io::SeekFrom::Start(0))?;
-        let offset = find_needle(rdr.by_ref().take(MAX_PE_SIZE), Self::tag())?.context("No NFO300 tag found")?;
+        // Search from the start of the overlay, if the stub can be parsed
+        let (start, limit) = match StubInfo::read(rdr.by_ref()) {
+            Ok(stub) => (stub.overlay_offset, stub.overlay_size()),
+            Err(_) => (0, MAX_PE_SIZE),
+        };
+        rdr.seek(std::io::SeekFrom::Start(start))?;
+        let offset = find_needle(rdr.by_ref().take(limit), Self::tag())?.context("No NFO300 tag found")?;
         Self::new(
             rdr,
-            offset
+            start + offset
         )
     }
 }
@@ -112,6 +131,14 @@ impl<R: BufRead + Read + Seek> Setup for Nfo300Setup<R> {
         })
     }
 
+    fn entry_span(&self, entry: &Self::Entry) -> EntrySpan {
+        EntrySpan {
+            offset: entry.offset,
+            len: u32::from_le_bytes(entry.size.to_le_bytes()) as u64,
+            key: None,
+        }
+    }
+
     fn size(&self) -> u64 {
         self.size - self.nfo_offset
     }
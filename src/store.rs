@@ -0,0 +1,290 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use humansize::{SizeFormatter, DECIMAL};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    extract::{remove_existing, restore_entry_meta, EntryMeta},
+    manifest::is_output_file,
+    util::{get_all_nested_files, move_file, sha256_file, unique_name, DosAttributes},
+};
+
+const OBJECTS_DIR: &str = "objects";
+const CLIENTS_DIR: &str = "clients";
+
+/// How a client directory is rebuilt from the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LinkMode {
+    /// Shares the object, the files are read-only and carry the time of the first client
+    Hardlink,
+    /// Copy-on-write clone, falls back to a copy if the file system has no support
+    Reflink,
+    Copy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreFile {
+    /// Path relative to the client directory with `/` as separator
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+    pub modified: Option<String>,
+    pub read_only: bool,
+}
+
+/// Client in the store, each file refers to an object by its hash
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoreManifest {
+    pub name: String,
+    pub files: Vec<StoreFile>,
+}
+
+/// Content addressed store, every unique file is kept once under `objects/ab/abcd..`
+#[derive(Debug, Clone)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(dir.join(OBJECTS_DIR))?;
+        std::fs::create_dir_all(dir.join(CLIENTS_DIR))?;
+        Ok(Self { dir })
+    }
+
+    pub fn object_path(&self, sha256: &str) -> PathBuf {
+        self.dir.join(OBJECTS_DIR).join(&sha256[..2]).join(sha256)
+    }
+
+    pub fn manifest_path(&self, name: &str) -> PathBuf {
+        self.dir.join(CLIENTS_DIR).join(format!("{name}.json"))
+    }
+
+    /// Report and manifest of the extraction of the client, kept next to its store manifest
+    pub fn outputs_dir(&self, name: &str) -> PathBuf {
+        self.dir.join(CLIENTS_DIR).join(name)
+    }
+
+    pub fn clients(&self) -> anyhow::Result<Vec<String>> {
+        let mut clients = Vec::new();
+        for entry in std::fs::read_dir(self.dir.join(CLIENTS_DIR))? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) == Some("json") {
+                clients.push(path.file_stem().unwrap().to_string_lossy().into_owned());
+            }
+        }
+        clients.sort();
+        Ok(clients)
+    }
+
+    pub fn manifest(&self, name: &str) -> anyhow::Result<StoreManifest> {
+        let path = self.manifest_path(name);
+        let file = File::open(&path).with_context(|| format!("Unknown client: {name}"))?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    /// Moves all files of an extracted client into the store and removes the directory,
+    /// files which are already stored are dropped. The report and manifest of the
+    /// extraction are moved to the outputs dir of the client
+    pub fn ingest(&self, name: &str, client_dir: &Path) -> anyhow::Result<StoreManifest> {
+        let mut files = Vec::new();
+        let mut new_bytes = 0;
+        for path in get_all_nested_files(client_dir)? {
+            let rel = path
+                .strip_prefix(client_dir)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            // The report and manifest of the extraction aren't client files
+            if is_output_file(&rel) {
+                let outputs_dir = self.outputs_dir(name);
+                std::fs::create_dir_all(&outputs_dir)?;
+                move_file(&path, &outputs_dir.join(&rel))
+                    .with_context(|| format!("Keep {rel} of {name}"))?;
+                continue;
+            }
+            let meta = path.metadata()?;
+            let sha256 = sha256_file(&path).with_context(|| format!("Hash {:?}", path))?;
+            let object = self.object_path(&sha256);
+            if !object.exists() {
+                new_bytes += meta.len();
+            }
+            self.insert(&path, &sha256)
+                .with_context(|| format!("Store {:?}", path))?;
+
+            files.push(StoreFile {
+                path: rel,
+                sha256,
+                size: meta.len(),
+                modified: meta
+                    .modified()
+                    .ok()
+                    .map(|t| DateTime::<Utc>::from(t).to_rfc3339()),
+                read_only: meta.permissions().readonly(),
+            });
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let manifest = StoreManifest {
+            name: name.to_string(),
+            files,
+        };
+        // The manifest is written last, it marks the client as complete
+        let tmp = self.manifest_path(name).with_extension("json.tmp");
+        serde_json::to_writer_pretty(BufWriter::new(File::create(&tmp)?), &manifest)?;
+        std::fs::rename(&tmp, self.manifest_path(name))?;
+        std::fs::remove_dir_all(client_dir)?;
+
+        let total: u64 = manifest.files.iter().map(|f| f.size).sum();
+        log::info!(
+            "Stored {name}: {} files, {}/{} new",
+            manifest.files.len(),
+            SizeFormatter::new(new_bytes, DECIMAL),
+            SizeFormatter::new(total, DECIMAL)
+        );
+        Ok(manifest)
+    }
+
+    /// Moves the file to the object path, concurrent ingests may store the same object
+    fn insert(&self, path: &Path, sha256: &str) -> anyhow::Result<()> {
+        let object = self.object_path(sha256);
+        if object.exists() {
+            remove_existing(path)?;
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(object.parent().unwrap())?;
+        // Stage next to the object, a rename across file systems is not possible
+        let tmp = object.with_file_name(unique_name(sha256));
+        if std::fs::rename(path, &tmp).is_err() {
+            std::fs::copy(path, &tmp)?;
+            remove_existing(path)?;
+        }
+        // Objects are shared by all clients, so they must never be modified
+        let mut perms = tmp.metadata()?.permissions();
+        perms.set_readonly(true);
+        std::fs::set_permissions(&tmp, perms)?;
+
+        if let Err(err) = std::fs::rename(&tmp, &object) {
+            remove_existing(&tmp)?;
+            if !object.exists() {
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the client directory in `out_dir`
+    pub fn materialize(&self, name: &str, out_dir: &Path, mode: LinkMode) -> anyhow::Result<()> {
+        let manifest = self.manifest(name)?;
+        for file in manifest.files.iter() {
+            let object = self.object_path(&file.sha256);
+            let out_path = out_dir.join(&file.path);
+            if let Some(parent) = out_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            remove_existing(&out_path)?;
+
+            match mode {
+                LinkMode::Hardlink => std::fs::hard_link(&object, &out_path).map(|_| ()),
+                LinkMode::Reflink => reflink_copy::reflink_or_copy(&object, &out_path).map(|_| ()),
+                LinkMode::Copy => std::fs::copy(&object, &out_path).map(|_| ()),
+            }
+            .with_context(|| format!("Materialize {}", file.path))?;
+
+            if mode != LinkMode::Hardlink {
+                // Copies keep the read-only flag of the object
+                let mut perms = out_path.metadata()?.permissions();
+                #[allow(clippy::permissions_set_readonly_false)]
+                perms.set_readonly(false);
+                std::fs::set_permissions(&out_path, perms)?;
+
+                let meta = EntryMeta {
+                    modified: file
+                        .modified
+                        .as_deref()
+                        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                        .map(SystemTime::from),
+                    attributes: if file.read_only {
+                        DosAttributes::READ_ONLY
+                    } else {
+                        DosAttributes::empty()
+                    },
+                };
+                restore_entry_meta(&out_path, &meta)?;
+            }
+        }
+
+        let outputs_dir = self.outputs_dir(name);
+        if outputs_dir.is_dir() {
+            for entry in std::fs::read_dir(&outputs_dir)? {
+                let path = entry?.path();
+                std::fs::copy(&path, out_dir.join(path.file_name().unwrap()))?;
+            }
+        }
+
+        log::info!("Materialized {name}: {} files", manifest.files.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        manifest::{MANIFEST_FILE, REPORT_FILE},
+        util::unique_temp_dir,
+    };
+
+    use super::*;
+
+    #[test]
+    fn dedup_and_materialize() {
+        let dir = unique_temp_dir("mssetup_store_test");
+        let store = Store::open(dir.join("store")).unwrap();
+        for (name, data) in [("v83", b"v83"), ("v84", b"v84")] {
+            let client = dir.join(name);
+            std::fs::create_dir_all(client.join("HShield")).unwrap();
+            std::fs::write(client.join("Sound.wz"), b"sound").unwrap();
+            std::fs::write(client.join("HShield/v3.dll"), data).unwrap();
+            std::fs::write(client.join(MANIFEST_FILE), name).unwrap();
+            std::fs::write(client.join(REPORT_FILE), name).unwrap();
+            store.ingest(name, &client).unwrap();
+            assert!(!client.exists());
+        }
+
+        let v83 = store.manifest("v83").unwrap();
+        let v84 = store.manifest("v84").unwrap();
+        assert_eq!(v83.files.len(), 2);
+        assert_eq!(v83.files[1].path, "Sound.wz");
+        assert_eq!(v83.files[1].sha256, v84.files[1].sha256);
+        assert_ne!(v83.files[0].sha256, v84.files[0].sha256);
+        assert_eq!(store.clients().unwrap(), ["v83", "v84"]);
+
+        for mode in [LinkMode::Hardlink, LinkMode::Copy] {
+            let out = dir.join(format!("{mode:?}"));
+            store.materialize("v84", &out, mode).unwrap();
+            store.materialize("v84", &out, mode).unwrap();
+            assert_eq!(std::fs::read(out.join("HShield/v3.dll")).unwrap(), b"v84");
+            assert_eq!(std::fs::read(out.join(MANIFEST_FILE)).unwrap(), b"v84");
+        }
+        // The outputs of the extraction are kept
+        let outputs = store.outputs_dir("v83");
+        assert_eq!(std::fs::read(outputs.join(REPORT_FILE)).unwrap(), b"v83");
+
+        // Objects are read-only, which windows doesn't remove
+        for path in get_all_nested_files(&dir).unwrap() {
+            let mut perms = path.metadata().unwrap().permissions();
+            #[allow(clippy::permissions_set_readonly_false)]
+            perms.set_readonly(false);
+            std::fs::set_permissions(&path, perms).unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
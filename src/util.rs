@@ -1,5 +1,17 @@
-use std::{collections::VecDeque, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}};
+use std::{
+    collections::VecDeque,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use chrono::NaiveDate;
+use sha2::{Digest, Sha256};
+
+use crate::pe::StubInfo;
+
+/// Scan limit for setups without a valid PE stub
 pub const MAX_PE_SIZE: u64 = 40 * 1024 * 1024;
 
 pub fn find_needle<R: Read>(mut reader: R, needle: &[u8]) -> anyhow::Result<Option<u64>> {
@@ -37,6 +49,20 @@ pub fn find_needle<R: Read>(mut reader: R, needle: &[u8]) -> anyhow::Result<Opti
     Ok(None)
 }
 
+/// Reads until the buffer is full or the reader ends, returns the number of bytes read
+pub fn read_up_to<R: Read>(mut reader: R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(n)
+}
+
 pub fn find_padding_data<R: Read + Seek>(
     mut reader: R,
     offset: u64,
@@ -50,10 +76,13 @@ pub fn find_padding_data<R: Read + Seek>(
     let pad_ix = offset + pad_ix;
     reader.seek(SeekFrom::Start(pad_ix))?;
     let mut buf = [0u8; 4096];
-    reader.read_exact(&mut buf)?;
-
+    let n = read_up_to(reader, &mut buf)?;
 
-    for (i, chunk) in buf.chunks(16).enumerate() {
+    for (i, chunk) in buf[..n].chunks(16).enumerate() {
+        // Padding, which runs until the end of the file, has no data after it
+        if chunk.len() < 16 && PAT.starts_with(chunk) {
+            return Ok(None);
+        }
         if chunk != PAT {
             let strip = chunk
                 .iter()
@@ -73,30 +102,107 @@ pub enum SetupFormat {
 }
 
 impl SetupFormat {
+    fn from_magic(magic: &[u8], offset: u64) -> Option<Self> {
+        if magic.starts_with(b"NFO300") {
+            Some(Self::NFO300(offset))
+        } else if magic.starts_with(b"InstallShield") {
+            Some(Self::InstallShield(offset))
+        } else {
+            None
+        }
+    }
+
     pub fn from_reader<R: Read + Seek>(mut reader: R) -> anyhow::Result<Self> {
-        let mut offset = 0;
+        match StubInfo::read(reader.by_ref()) {
+            Ok(stub) => Self::from_overlay(reader, stub.overlay_offset, stub.overlay_end),
+            Err(err) => {
+                log::warn!("Unable to parse PE stub({err}), scanning for padding data");
+                Self::from_padding_scan(reader, 0, MAX_PE_SIZE)
+            }
+        }
+    }
+
+    /// Detects the container at the start of the overlay, the container may be preceded by
+    /// padding. If it's not found there the overlay is scanned for padding data
+    pub fn from_overlay<R: Read + Seek>(
+        mut reader: R,
+        overlay: u64,
+        overlay_end: u64,
+    ) -> anyhow::Result<Self> {
+        const PAT: &[u8] = b"PADDINGXXPADDING";
+        let mut offset = overlay;
+        let mut chunk = [0u8; 16];
+        reader.seek(SeekFrom::Start(offset))?;
+        // A short overlay or padding until the end of the file falls back to the scan
+        let mut n = read_up_to(reader.by_ref(), &mut chunk)?;
+        while n == 16 && chunk == PAT {
+            offset += 16;
+            n = read_up_to(reader.by_ref(), &mut chunk)?;
+        }
+        if n == 16 {
+            let strip = chunk
+                .iter()
+                .zip(PAT.iter())
+                .position(|(a, b)| a != b)
+                .unwrap_or(0) as u64;
+            offset += strip;
+
+            reader.seek(SeekFrom::Start(offset))?;
+            let n = read_up_to(reader.by_ref(), &mut chunk)?;
+            if let Some(format) = Self::from_magic(&chunk[..n], offset) {
+                return Ok(format);
+            }
+        }
+
+        let end = overlay_end.min(overlay.saturating_add(MAX_PE_SIZE));
+        Self::from_padding_scan(reader, overlay, end)
+    }
+
+    /// Searches padding data in the range, the container directly follows the padding
+    pub fn from_padding_scan<R: Read + Seek>(
+        mut reader: R,
+        start: u64,
+        end: u64,
+    ) -> anyhow::Result<Self> {
+        let mut offset = start;
         loop {
             reader.seek(SeekFrom::Start(offset))?;
-            let ix = find_padding_data(reader.by_ref(), offset, MAX_PE_SIZE - offset)?;
+            let ix = find_padding_data(reader.by_ref(), offset, end.saturating_sub(offset))?;
             let Some(ix) = ix else {
                 anyhow::bail!("Could not find padding data");
             };
 
             reader.seek(SeekFrom::Start(ix))?;
             let mut magic = [0u8; 16];
-            reader.read_exact(&mut magic)?;
+            let n = read_up_to(reader.by_ref(), &mut magic)?;
 
-            if magic.starts_with(b"NFO300") {
-                break Ok(Self::NFO300(ix));
-            } else if magic.starts_with(b"InstallShield") {
-                break Ok(Self::InstallShield(ix));
+            if let Some(format) = Self::from_magic(&magic[..n], ix) {
+                break Ok(format);
             } else {
                 offset = ix + 16;
             }
         }
     }
+
+    pub fn offset(&self) -> u64 {
+        match self {
+            Self::InstallShield(offset) | Self::NFO300(offset) => *offset,
+        }
+    }
 }
 
+/// Temporary directory, which is unique to this call, so concurrent runs and threads
+/// never share or remove each others files
+pub fn unique_temp_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(unique_name(name))
+}
+
+/// File name, which is unique to this call
+pub fn unique_name(name: &str) -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    format!("{name}_{}_{n}", std::process::id())
+}
 
 pub fn get_all_nested_files(dir: impl AsRef<Path>) -> anyhow::Result<Vec<PathBuf>> {
     let mut entries = vec![];
@@ -115,4 +221,140 @@ pub fn get_all_nested_files(dir: impl AsRef<Path>) -> anyhow::Result<Vec<PathBuf
     }
 
     Ok(entries)
-}
\ No newline at end of file
+}
+
+/// Copies all files of `src` into `dst`, keeping the directory structure
+pub fn copy_dir(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    for path in get_all_nested_files(src)? {
+        let out_path = dst.join(path.strip_prefix(src)?);
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&path, &out_path)?;
+    }
+    Ok(())
+}
+
+/// Moves the file, falls back to a copy if it's on another file system
+pub fn move_file(src: &Path, dst: &Path) -> std::io::Result<()> {
+    if std::fs::rename(src, dst).is_err() {
+        std::fs::copy(src, dst)?;
+        std::fs::remove_file(src)?;
+    }
+    Ok(())
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hex encoded SHA-256 of the reader's remaining data
+pub fn sha256_reader<R: Read>(mut rdr: R) -> std::io::Result<String> {
+    let mut digest = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = rdr.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+    }
+    Ok(to_hex(&digest.finalize()))
+}
+
+pub fn sha256_file(path: impl AsRef<Path>) -> std::io::Result<String> {
+    sha256_reader(std::fs::File::open(path)?)
+}
+
+bitflags::bitflags! {
+    /// File attributes as stored in zip/cab headers
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct DosAttributes: u16 {
+        const READ_ONLY = 0x01;
+        const HIDDEN = 0x02;
+        const SYSTEM = 0x04;
+        const DIRECTORY = 0x10;
+        const ARCHIVE = 0x20;
+    }
+}
+
+/// Converts a MS-DOS date/time pair into a `SystemTime`, the local time is treated as UTC
+pub fn dos_datetime_to_system_time(date: u16, time: u16) -> Option<SystemTime> {
+    let year = 1980 + (date >> 9) as i32;
+    let month = ((date >> 5) & 0xF) as u32;
+    let day = (date & 0x1F) as u32;
+    let hour = (time >> 11) as u32;
+    let min = ((time >> 5) & 0x3F) as u32;
+    let sec = (time & 0x1F) as u32 * 2;
+    let dt = NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(hour, min, sec)?;
+    Some(dt.and_utc().into())
+}
+
+/// Converts a windows FILETIME(100ns intervals since 1601-01-01) into a `SystemTime`
+pub fn filetime_to_system_time(ft: u64) -> Option<SystemTime> {
+    const EPOCH_DIFF_SECS: u64 = 11_644_473_600;
+    let secs = (ft / 10_000_000).checked_sub(EPOCH_DIFF_SECS)?;
+    let nanos = (ft % 10_000_000) as u32 * 100;
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dos_datetime() {
+        // 2008-07-14 13:37:42
+        let date = ((2008 - 1980) << 9) | (7 << 5) | 14;
+        let time = (13 << 11) | (37 << 5) | (42 / 2);
+        let t = dos_datetime_to_system_time(date, time).unwrap();
+        assert_eq!(t.duration_since(UNIX_EPOCH).unwrap().as_secs(), 1216042662);
+
+        // Month 0 is invalid
+        assert!(dos_datetime_to_system_time(0, 0).is_none());
+    }
+
+    #[test]
+    fn overlay_format() {
+        let mut data = vec![0u8; 0x200];
+        data.extend(b"PADDINGXXPADDINGPADDINGXX");
+        data.extend(b"NFO300\r\n");
+        data.resize(0x400, 0);
+
+        let rdr = std::io::Cursor::new(&data);
+        let format = SetupFormat::from_overlay(rdr, 0x200, data.len() as u64).unwrap();
+        assert!(matches!(format, SetupFormat::NFO300(0x219)));
+
+        // A container before the overlay is never taken
+        let rdr = std::io::Cursor::new(&data);
+        assert!(SetupFormat::from_overlay(rdr, 0x3F8, data.len() as u64).is_err());
+        let mut padded = data.clone();
+        padded.extend(b"PADDINGXXPADDINGPADDINGXXPAD");
+        let len = padded.len() as u64;
+        let rdr = std::io::Cursor::new(&padded);
+        assert!(SetupFormat::from_overlay(rdr, 0x400, len).is_err());
+
+        // Data in front of the padding falls back to the scan of the overlay
+        let rdr = std::io::Cursor::new(&data);
+        let format = SetupFormat::from_overlay(rdr, 0x1F0, data.len() as u64).unwrap();
+        assert!(matches!(format, SetupFormat::NFO300(0x219)));
+
+        // Padding after the overlay end, e.g. in the certificate table, is never scanned
+        let mut data = vec![0u8; 0x300];
+        data.extend(b"PADDINGXXPADDINGPADDINGXX");
+        data.extend(b"NFO300\r\n");
+        data.resize(0x400, 0);
+        let rdr = std::io::Cursor::new(&data);
+        assert!(SetupFormat::from_overlay(rdr, 0x200, 0x300).is_err());
+        let rdr = std::io::Cursor::new(&data);
+        let format = SetupFormat::from_overlay(rdr, 0x200, data.len() as u64).unwrap();
+        assert!(matches!(format, SetupFormat::NFO300(0x319)));
+    }
+
+    #[test]
+    fn filetime() {
+        let t = filetime_to_system_time(128_608_162_620_000_000).unwrap();
+        assert_eq!(t.duration_since(UNIX_EPOCH).unwrap().as_secs(), 1216342662);
+        assert!(filetime_to_system_time(0).is_none());
+    }
+}